@@ -8,6 +8,8 @@ use std::sync::Mutex;
 use pgp::armor::BlockType;
 use pgp::types::Tag;
 
+use crate::error;
+
 #[derive(Default)]
 pub(crate) struct Armor {
     label: sop::ops::ArmorLabel,
@@ -55,7 +57,7 @@ impl<'a> sop::ops::Ready for ArmorReady<'a> {
 
         if buf[0] & 0x80 == 0 {
             // the input don't seem to be binary pgp data -> just pass it through
-            std::io::copy(&mut reader, &mut sink).expect("FIXME");
+            std::io::copy(&mut reader, &mut sink)?;
         } else {
             let label = if self.armor.label == sop::ops::ArmorLabel::Auto {
                 // autodetect type
@@ -72,7 +74,7 @@ impl<'a> sop::ops::Ready for ArmorReady<'a> {
 
             // TODO: don't write out checksum for v6 artifacts?
 
-            pgp::armor::write(&input, typ, &mut sink, None, true).expect("FIXME")
+            pgp::armor::write(&input, typ, &mut sink, None, true).map_err(error::rpgp)?
         }
 
         Ok(())
@@ -97,10 +99,10 @@ impl pgp::ser::Serialize for SerializableBinary<'_> {
 
 // Produce the equivalent pgp::armor::reader::BlockType
 //
-// NOTE: Panics for sop::ops::ArmorLabel::Auto
+// NOTE: Fails for sop::ops::ArmorLabel::Auto
 fn blocktype_try_from(label: sop::ops::ArmorLabel) -> sop::Result<BlockType> {
     match label {
-        sop::ops::ArmorLabel::Auto => unimplemented!("this should never happen"),
+        sop::ops::ArmorLabel::Auto => Err(sop::errors::Error::UnspecifiedFailure),
         sop::ops::ArmorLabel::Cert => Ok(BlockType::PublicKey),
         sop::ops::ArmorLabel::Key => Ok(BlockType::PrivateKey),
         sop::ops::ArmorLabel::Message => Ok(BlockType::Message),
@@ -0,0 +1,369 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use chrono::{SubsecRound, Utc};
+use pgp::packet::SignatureType;
+use pgp::types::{PublicKeyTrait, SecretKeyTrait, Tag};
+use rpgpie::key::checked::CheckedCertificate;
+use rpgpie::key::Tsk;
+
+use crate::{Certs, Keys, RPGSOP};
+
+/// Issue third-party certifications over User IDs, as in `sop certify-userid`.
+///
+/// By default, these are generic certifications (signature type 0x10). Beyond the SOP
+/// interface, this allows choosing the certification level. This setting must be made before
+/// using the methods of [`sop::ops::CertifyUserID`].
+pub struct CertifyUserID {
+    user_ids: Vec<String>,
+    with_key_password: Vec<sop::Password>,
+    require_self_sig: bool,
+    certifiers: Vec<Tsk>,
+    level: SignatureType,
+}
+
+impl CertifyUserID {
+    pub(crate) fn new() -> Self {
+        Self {
+            user_ids: Default::default(),
+            with_key_password: Default::default(),
+            require_self_sig: true,
+            certifiers: Default::default(),
+            level: SignatureType::CertGeneric,
+        }
+    }
+
+    /// Issue certifications of type `level` (generic, persona, casual or positive), instead of
+    /// generic certifications.
+    ///
+    /// Fails with `UnsupportedOption` if `level` is not a certification signature type.
+    pub fn level(mut self: Box<Self>, level: SignatureType) -> sop::Result<Box<Self>> {
+        match level {
+            SignatureType::CertGeneric
+            | SignatureType::CertPersona
+            | SignatureType::CertCasual
+            | SignatureType::CertPositive => {
+                self.level = level;
+                Ok(self)
+            }
+            typ => {
+                log::warn!("Signature type {typ:?} is not a certification level");
+                Err(sop::errors::Error::UnsupportedOption)
+            }
+        }
+    }
+}
+
+impl<'a> sop::ops::CertifyUserID<'a, RPGSOP, Certs, Keys> for CertifyUserID {
+    fn userid(
+        mut self: Box<Self>,
+        userid: String,
+    ) -> Box<dyn sop::ops::CertifyUserID<'a, RPGSOP, Certs, Keys> + 'a> {
+        self.user_ids.push(userid);
+        self
+    }
+
+    fn with_key_password(
+        mut self: Box<Self>,
+        password: sop::Password,
+    ) -> sop::Result<Box<dyn sop::ops::CertifyUserID<'a, RPGSOP, Certs, Keys> + 'a>> {
+        self.with_key_password.push(password);
+        Ok(self)
+    }
+
+    fn no_require_self_sig(
+        mut self: Box<Self>,
+    ) -> Box<dyn sop::ops::CertifyUserID<'a, RPGSOP, Certs, Keys> + 'a> {
+        self.require_self_sig = false;
+        self
+    }
+
+    fn keys(
+        mut self: Box<Self>,
+        keys: &Keys,
+    ) -> sop::Result<Box<dyn sop::ops::CertifyUserID<'a, RPGSOP, Certs, Keys> + 'a>> {
+        self.certifiers.extend(keys.keys.iter().cloned());
+        Ok(self)
+    }
+
+    fn certify(self: Box<Self>, certs: &Certs) -> sop::Result<Certs> {
+        if self.certifiers.is_empty() || self.user_ids.is_empty() {
+            return Err(sop::errors::Error::MissingArg);
+        }
+
+        let now = Utc::now().trunc_subsecs(0);
+
+        // Passwords to try
+        let pws: Vec<&[u8]> = if self.with_key_password.is_empty() {
+            vec![&[]]
+        } else {
+            self.with_key_password
+                .iter()
+                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+                .collect()
+        };
+
+        let mut results = vec![];
+
+        for cert in &certs.certs {
+            let checked = CheckedCertificate::from(cert);
+            let mut spk = crate::util::to_signed_public_key(cert)?;
+
+            for user_id in &self.user_ids {
+                let Some(user) = spk
+                    .details
+                    .users
+                    .iter_mut()
+                    .find(|u| u.id.id() == user_id.as_str())
+                else {
+                    return Err(sop::errors::Error::CertUseridNoMatch);
+                };
+
+                if self.require_self_sig {
+                    let bound = checked
+                        .user_ids()
+                        .iter()
+                        .find(|u| u.id == user.id)
+                        .is_some_and(|u| {
+                            crate::util::user_id_bound_at(u, checked.primary_creation_time(), &now)
+                        });
+
+                    if !bound {
+                        return Err(sop::errors::Error::CertUseridNoMatch);
+                    }
+                }
+
+                for tsk in &self.certifiers {
+                    let Tsk::Tsk(ssk) = tsk else {
+                        // rpgpie can't issue certifications with card-backed keys
+                        return Err(sop::errors::Error::KeyCannotSign);
+                    };
+                    let certifier = &ssk.primary_key;
+
+                    log::info!(
+                        "Trying to certify {:?} with certifier: {:02x?}",
+                        user_id,
+                        certifier.fingerprint()
+                    );
+
+                    let config = crate::util::signature_config(
+                        certifier,
+                        self.level,
+                        certifier.hash_alg(),
+                        &now,
+                    )?;
+
+                    let sig = pws
+                        .iter()
+                        .flat_map(|pw| {
+                            let result = config.clone().sign_certification_third_party(
+                                certifier,
+                                || String::from_utf8_lossy(pw).to_string(),
+                                &spk.primary_key,
+                                Tag::UserId,
+                                &user.id,
+                            );
+
+                            if result.is_err() {
+                                log::warn!("Certification failed: {result:?}");
+                            }
+
+                            result
+                        })
+                        .next();
+
+                    let Some(sig) = sig else {
+                        // None of the passwords unlocked the certifier
+                        return Err(sop::errors::Error::KeyIsProtected);
+                    };
+
+                    user.signatures.push(sig);
+                }
+            }
+
+            results.push(spk.into());
+        }
+
+        Ok(Certs {
+            certs: results,
+            source_name: None,
+        })
+    }
+}
+
+#[cfg(test)]
+fn generate(profile: &str, user_id: &str, password: Option<&str>) -> Keys {
+    use sop::ops::GenerateKey as _;
+
+    let mut generate = Box::new(crate::GenerateKey::new())
+        .profile(profile)
+        .unwrap()
+        .userid(user_id);
+    if let Some(pw) = password {
+        generate = generate
+            .with_key_password(sop::Password::new_unchecked(pw.as_bytes().to_vec()))
+            .unwrap();
+    }
+
+    generate.generate().unwrap()
+}
+
+#[cfg(test)]
+fn certs(keys: &Keys) -> Certs {
+    Certs {
+        certs: keys
+            .keys
+            .iter()
+            .map(rpgpie::key::Certificate::from)
+            .collect(),
+        source_name: None,
+    }
+}
+
+#[test]
+fn test_certify_levels() {
+    use sop::ops::CertifyUserID as _;
+
+    for profile in ["draft-koch-eddsa-for-openpgp-00", "rfc9580"] {
+        let alice = generate(profile, "<alice@example.org>", None);
+        let bob = certs(&generate(profile, "<bob@example.org>", None));
+
+        let Tsk::Tsk(certifier) = &alice.keys[0] else {
+            unreachable!()
+        };
+
+        for level in [
+            SignatureType::CertGeneric,
+            SignatureType::CertPersona,
+            SignatureType::CertCasual,
+            SignatureType::CertPositive,
+        ] {
+            let certified = Box::new(CertifyUserID::new())
+                .level(level)
+                .unwrap()
+                .keys(&alice)
+                .unwrap()
+                .userid("<bob@example.org>".to_string())
+                .certify(&bob)
+                .unwrap();
+
+            let spk = crate::util::to_signed_public_key(&certified.certs[0]).unwrap();
+            let user = &spk.details.users[0];
+            let sig = user.signatures.last().unwrap();
+
+            assert_eq!(sig.typ(), level, "{profile}");
+            assert_eq!(sig.issuer_fingerprint(), vec![&certifier.fingerprint()]);
+            sig.verify_third_party_certification(
+                &spk.primary_key,
+                &certifier.primary_key.public_key(),
+                Tag::UserId,
+                &user.id,
+            )
+            .unwrap();
+        }
+    }
+
+    // Only certification signature types are levels
+    assert!(matches!(
+        Box::new(CertifyUserID::new()).level(SignatureType::Binary),
+        Err(sop::errors::Error::UnsupportedOption)
+    ));
+}
+
+#[test]
+fn test_certify_no_require_self_sig() {
+    use sop::ops::CertifyUserID as _;
+
+    let alice = generate(
+        "draft-koch-eddsa-for-openpgp-00",
+        "<alice@example.org>",
+        None,
+    );
+    let bob = generate("draft-koch-eddsa-for-openpgp-00", "<bob@example.org>", None);
+
+    let certify = || {
+        Box::new(CertifyUserID::new())
+            .keys(&alice)
+            .unwrap()
+            .userid("<bob@example.org>".to_string())
+    };
+
+    // Bob's certificate, with a User ID that only has Alice's certification, but no
+    // self-signature
+    let mut spk =
+        crate::util::to_signed_public_key(&certify().certify(&certs(&bob)).unwrap().certs[0])
+            .unwrap();
+    let bob_fp = spk.fingerprint();
+    spk.details.users[0]
+        .signatures
+        .retain(|s| s.issuer_fingerprint() != vec![&bob_fp]);
+    assert_eq!(spk.details.users[0].signatures.len(), 1);
+    let bob = Certs {
+        certs: vec![spk.into()],
+        source_name: None,
+    };
+
+    assert!(matches!(
+        certify().certify(&bob),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+
+    let certified = certify().no_require_self_sig().certify(&bob).unwrap();
+    let spk = crate::util::to_signed_public_key(&certified.certs[0]).unwrap();
+    assert_eq!(spk.details.users[0].signatures.len(), 2);
+
+    // A User ID that the certificate doesn't have can't be certified either way
+    assert!(matches!(
+        Box::new(CertifyUserID::new())
+            .keys(&alice)
+            .unwrap()
+            .userid("<carol@example.org>".to_string())
+            .no_require_self_sig()
+            .certify(&bob),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+}
+
+#[test]
+fn test_certify_password() {
+    use sop::ops::CertifyUserID as _;
+
+    let alice = generate(
+        "draft-koch-eddsa-for-openpgp-00",
+        "<alice@example.org>",
+        Some("password"),
+    );
+    let bob = certs(&generate(
+        "draft-koch-eddsa-for-openpgp-00",
+        "<bob@example.org>",
+        None,
+    ));
+
+    let certify = |passwords: &[&str]| {
+        let mut certify = Box::new(CertifyUserID::new())
+            .keys(&alice)
+            .unwrap()
+            .userid("<bob@example.org>".to_string());
+        for pw in passwords {
+            certify = certify
+                .with_key_password(sop::Password::new_unchecked(pw.as_bytes().to_vec()))
+                .unwrap();
+        }
+
+        certify.certify(&bob)
+    };
+
+    assert!(matches!(
+        certify(&[]),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+    assert!(matches!(
+        certify(&["wrong"]),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+
+    // The passwords are tried in turn, until one unlocks the certifier
+    let certified = certify(&["wrong", "password"]).unwrap();
+    let spk = crate::util::to_signed_public_key(&certified.certs[0]).unwrap();
+    assert_eq!(spk.details.users[0].signatures.len(), 2);
+}
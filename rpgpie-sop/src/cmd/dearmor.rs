@@ -39,10 +39,23 @@ impl<'a> sop::ops::Ready for DearmorReady<'a> {
 
         if buf[0] & 0x80 != 0 {
             // the input seems to be binary data -> just pass it through
-            std::io::copy(&mut reader, &mut sink).expect("FIXME");
+            std::io::copy(&mut reader, &mut sink)?;
         } else {
             let mut dearmor = pgp::armor::Dearmor::new(reader);
-            std::io::copy(&mut dearmor, &mut sink).expect("FIXME");
+
+            // Failures while reading from `dearmor` mean that the input is not well-formed
+            // armor, so we can't just use `std::io::copy`
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = dearmor
+                    .read(&mut buf)
+                    .map_err(|_| sop::errors::Error::BadData)?;
+                if n == 0 {
+                    break;
+                }
+
+                sink.write_all(&buf[..n])?;
+            }
         }
 
         Ok(())
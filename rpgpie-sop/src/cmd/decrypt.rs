@@ -1,19 +1,129 @@
 // SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use std::io;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
 use std::time::SystemTime;
 
-use pgp::composed::{Deserializable, Message};
+use pgp::composed::{Deserializable, Edata, Esk, Message, PlainSessionKey};
+use pgp::packet::{
+    Data, LiteralData, Packet, PublicKeyEncryptedSessionKey, SymKeyEncryptedSessionKey,
+};
+use pgp::types::{PublicKeyTrait, SecretKeyTrait, SkeskVersion, Tag, Version};
+use rpgpie::key::component::{SignedComponentKey, SignedComponentKeySec};
 use rpgpie::key::Tsk;
 
 use crate::cmd::verify::Verify;
-use crate::{util, Certs, Keys, RPGSOP};
+use crate::stream::packet::{self, BodyReader};
+use crate::stream::seipd::SeipdReader;
+use crate::stream::spill::Spill;
+use crate::stream::{armor, message};
+use crate::{error, util, Certs, Keys, RPGSOP};
+
+/// How much of the encrypted data we use to find the right session key: enough for the SEIPDv2
+/// parameters and the largest AEAD chunk, with its authentication tags
+const PROBE_LEN: u64 = 3 + 32 + (1 << 22) + 2 * 16;
+
+/// The session key and the verifications of a decrypted message
+pub type Decrypted = (Option<sop::SessionKey>, Vec<sop::ops::Verification>);
+
+/// Why no session key for a message was found.
+///
+/// When several reasons apply, the most specific one (the greatest) is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DecryptFailure {
+    /// None of the decryption keys or session keys is for the message
+    NoKey,
+
+    /// None of the message passwords decrypts a SKESK packet of the message (or the message
+    /// itself, for SKESK packets that don't protect the session key's integrity)
+    MessagePassword,
+
+    /// A decryption key for the message is locked, and none of the key passwords unlocks it
+    KeyPassword,
+
+    /// A session key was decrypted with integrity protection, but it doesn't decrypt the message
+    Corrupt,
+}
+
+impl fmt::Display for DecryptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptFailure::NoKey => write!(f, "No matching decryption key"),
+            DecryptFailure::MessagePassword => write!(f, "Wrong message password"),
+            DecryptFailure::KeyPassword => write!(f, "Wrong key password"),
+            DecryptFailure::Corrupt => write!(f, "Corrupt ciphertext"),
+        }
+    }
+}
+
+impl From<DecryptFailure> for sop::errors::Error {
+    fn from(failure: DecryptFailure) -> Self {
+        log::warn!("Decryption failed: {failure}");
+
+        match failure {
+            DecryptFailure::NoKey | DecryptFailure::MessagePassword => Self::CannotDecrypt,
+            DecryptFailure::KeyPassword => Self::KeyIsProtected,
+            DecryptFailure::Corrupt => Self::BadData,
+        }
+    }
+}
+
+/// An error from [`DecryptMany`].
+///
+/// Unlike the SOP error that it converts into, this tells apart why no session key for a message
+/// was found.
+#[derive(Debug)]
+pub enum DecryptError {
+    /// No session key for a message was found
+    Failure(DecryptFailure),
+
+    /// Any other error, e.g. malformed input
+    Sop(sop::errors::Error),
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::Failure(failure) => failure.fmt(f),
+            DecryptError::Sop(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<DecryptFailure> for DecryptError {
+    fn from(failure: DecryptFailure) -> Self {
+        Self::Failure(failure)
+    }
+}
+
+impl From<sop::errors::Error> for DecryptError {
+    fn from(e: sop::errors::Error) -> Self {
+        Self::Sop(e)
+    }
+}
+
+impl From<io::Error> for DecryptError {
+    fn from(e: io::Error) -> Self {
+        Self::Sop(e.into())
+    }
+}
+
+impl From<DecryptError> for sop::errors::Error {
+    fn from(e: DecryptError) -> Self {
+        match e {
+            DecryptError::Failure(failure) => failure.into(),
+            DecryptError::Sop(e) => e,
+        }
+    }
+}
 
 #[derive(Default)]
 pub(crate) struct Decrypt {
     verify: Verify,
-    _session_keys: Vec<sop::SessionKey>,
+    session_keys: Vec<sop::SessionKey>,
     decryption_keys: Vec<Tsk>,
     key_passwords: Vec<sop::Password>, // Passwords for asymmetric component key material
     skesk_passwords: Vec<sop::Password>,
@@ -23,27 +133,472 @@ impl Decrypt {
     pub(crate) fn new() -> Self {
         Default::default()
     }
+
+    /// Does any of `pkesks` need to be decrypted by a key on an OpenPGP card?
+    fn needs_card(&self, pkesks: &[PublicKeyEncryptedSessionKey]) -> bool {
+        pkesks.iter().any(|pkesk| {
+            self.decryption_keys.iter().any(|tsk| {
+                tsk.decryption_capable_component_keys()
+                    .any(|ek| matches!(ek, SignedComponentKey::Pub(_)) && pkesk.match_identity(&ek))
+            })
+        })
+    }
+
+    /// Find a session key for encrypted data of SEIPD `version`, for which `open` succeeds.
+    ///
+    /// Session keys from the caller take precedence over decryption via PKESK or SKESK.
+    /// Returns the result of `open`, or the reason why no session key was found.
+    ///
+    /// Along with the result of `open`, this returns the failure to report if the data doesn't
+    /// decrypt after all. If `open` only checks part of the data (like the quick check of a
+    /// SEIPDv1 packet), it may accept a wrong session key from a version 4 SKESK. That's
+    /// indistinguishable from corrupt data, and reported as a wrong message password.
+    fn find_session_key<T>(
+        &self,
+        version: u8,
+        pkesks: &[PublicKeyEncryptedSessionKey],
+        skesks: &[SymKeyEncryptedSessionKey],
+        mut open: impl FnMut(&PlainSessionKey) -> Option<T>,
+    ) -> Result<(T, DecryptFailure), DecryptFailure> {
+        let mut failure = DecryptFailure::NoKey;
+
+        for sk in &self.session_keys {
+            // SEIPDv2 packets specify the symmetric algorithm themselves
+            let plain = match version {
+                2 => PlainSessionKey::V6 {
+                    key: sk.key().to_vec(),
+                },
+                _ => PlainSessionKey::V3_4 {
+                    sym_alg: sk.algorithm().into(),
+                    key: sk.key().to_vec(),
+                },
+            };
+
+            if let Some(t) = open(&plain) {
+                return Ok((t, DecryptFailure::Corrupt));
+            }
+        }
+
+        let mut key_passwords: Vec<&[u8]> = self
+            .key_passwords
+            .iter()
+            .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+            .collect();
+        if key_passwords.is_empty() {
+            key_passwords = vec![&[]];
+        }
+
+        for pkesk in pkesks {
+            // PKESKs without recipient match any key, failing to decrypt them proves nothing
+            let anonymous = match pkesk {
+                PublicKeyEncryptedSessionKey::V3 { id, .. } => id.is_wildcard(),
+                PublicKeyEncryptedSessionKey::V6 { fingerprint, .. } => fingerprint.is_none(),
+                _ => false,
+            };
+
+            for tsk in &self.decryption_keys {
+                for ek in tsk.decryption_capable_component_keys() {
+                    let SignedComponentKey::Sec(sec) = &ek else {
+                        continue;
+                    };
+                    if !pkesk.match_identity(&ek) {
+                        continue;
+                    }
+
+                    let mut unlocked = false;
+                    for pw in &key_passwords {
+                        match sec.decrypt_session_key(pkesk, || String::from_utf8_lossy(pw).into())
+                        {
+                            Ok(sk) => {
+                                if let Some(t) = open(&sk) {
+                                    return Ok((t, DecryptFailure::Corrupt));
+                                }
+                            }
+                            Err(e) => {
+                                log::info!("Decrypting PKESK failed: {e:?}");
+
+                                // Was the password wrong?
+                                if !unlocks(sec, pw) {
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // The key is unlocked, but doesn't yield a session key for the data
+                        unlocked = true;
+                        if !anonymous {
+                            failure = failure.max(DecryptFailure::Corrupt);
+                        }
+                        break;
+                    }
+
+                    if !unlocked {
+                        log::info!("Unlocking decryption key failed: {:02x?}", ek.fingerprint());
+                        failure = failure.max(DecryptFailure::KeyPassword);
+                    }
+                }
+            }
+        }
+
+        for skesk in skesks {
+            for pw in &self.skesk_passwords {
+                let pw = sop::plumbing::PasswordsAreHumanReadable::normalized(pw);
+
+                match pgp::decrypt_session_key_with_password(skesk, || {
+                    String::from_utf8_lossy(pw).into()
+                }) {
+                    Ok(sk) => match open(&sk) {
+                        // Only version 6 SKESKs protect the integrity of the session key
+                        Some(t) if skesk.version() == SkeskVersion::V6 => {
+                            return Ok((t, DecryptFailure::Corrupt))
+                        }
+                        Some(t) => return Ok((t, DecryptFailure::MessagePassword)),
+
+                        None if skesk.version() == SkeskVersion::V6 => {
+                            failure = failure.max(DecryptFailure::Corrupt)
+                        }
+                        None => failure = failure.max(DecryptFailure::MessagePassword),
+                    },
+                    Err(e) => {
+                        log::info!("Decrypting SKESK failed: {e:?}");
+                        failure = failure.max(DecryptFailure::MessagePassword);
+                    }
+                }
+            }
+        }
+
+        Err(failure)
+    }
+
+    /// Decrypt the messages in `ciphertext` in memory, with rpgpie.
+    ///
+    /// This handles messages that we can't decrypt as a stream: decryption with OpenPGP cards,
+    /// and messages that aren't encrypted with a SEIPD packet.
+    ///
+    /// Unless `many` is set, `ciphertext` must contain exactly one message.
+    fn decrypt_buffered(
+        &self,
+        ciphertext: impl Read,
+        many: bool,
+        sink: &mut (dyn io::Write + Send + Sync),
+    ) -> Result<Vec<Decrypted>, DecryptError> {
+        let (iter, _header) = Message::from_reader_many(ciphertext).map_err(error::rpgp)?;
+        let mut iter = iter.peekable();
+
+        let mut decrypted = vec![];
+
+        while let Some(msg) = iter.next() {
+            let msg = msg.map_err(error::rpgp)?;
+
+            let (plaintext, session_key, verifications) = self.unpack(msg)?;
+
+            if !many && iter.peek().is_some() {
+                return Err(sop::errors::Error::BadData.into());
+            }
+
+            sink.write_all(plaintext.data())?;
+            decrypted.push((session_key, verifications));
+        }
+
+        if decrypted.is_empty() {
+            return Err(sop::errors::Error::BadData.into());
+        }
+
+        Ok(decrypted)
+    }
+
+    /// Decrypt `msg` in memory.
+    ///
+    /// Returns the literal data, the session key and the verifications.
+    fn unpack(
+        &self,
+        msg: Message,
+    ) -> Result<
+        (
+            LiteralData,
+            Option<sop::SessionKey>,
+            Vec<sop::ops::Verification>,
+        ),
+        DecryptError,
+    > {
+        let Message::Encrypted { esk, edata } = &msg else {
+            // Not encrypted, rpgpie only verifies the message
+            let mr = rpgpie::msg::unpack(msg, &[], vec![], vec![], &self.verify.certs)
+                .map_err(error::rpgpie)?;
+
+            let verifications = util::verifications_within(
+                &mr.validated,
+                self.verify.not_before,
+                self.verify.not_after,
+            );
+
+            return Ok((mr.cleartext, None, verifications));
+        };
+
+        let mut pkesks = vec![];
+        let mut skesks = vec![];
+        for esk in esk {
+            match esk {
+                Esk::PublicKeyEncryptedSessionKey(pkesk) => pkesks.push(pkesk.clone()),
+                Esk::SymKeyEncryptedSessionKey(skesk) => skesks.push(skesk.clone()),
+            }
+        }
+
+        if self.needs_card(&pkesks) {
+            return Ok(self.unpack_with_card(msg)?);
+        }
+
+        let seipd_v2 = match edata {
+            Edata::SymEncryptedProtectedData(seipd) => match seipd.data() {
+                Data::V2 { sym_alg, .. } => Some(*sym_alg),
+                Data::V1 { .. } => None,
+            },
+            Edata::SymEncryptedData(_) => None,
+        };
+
+        let ((session_key, inner), _) = self.find_session_key(
+            if seipd_v2.is_some() { 2 } else { 1 },
+            &pkesks,
+            &skesks,
+            |sk| {
+                let (sym_alg, key) = match sk {
+                    PlainSessionKey::V3_4 { sym_alg, key } => (*sym_alg, key),
+                    PlainSessionKey::V6 { key } => (seipd_v2?, key),
+                    PlainSessionKey::V5 { .. } => return None,
+                };
+
+                match edata.decrypt(sk.clone()) {
+                    Ok(inner) => Some((sop::SessionKey::new(u8::from(sym_alg), key).ok()?, inner)),
+                    Err(e) => {
+                        log::info!("Decryption with session key failed: {e:?}");
+                        None
+                    }
+                }
+            },
+        )?;
+
+        let mr = rpgpie::msg::unpack(inner, &[], vec![], vec![], &self.verify.certs)
+            .map_err(error::rpgpie)?;
+
+        let verifications = util::verifications_within(
+            &mr.validated,
+            self.verify.not_before,
+            self.verify.not_after,
+        );
+
+        Ok((mr.cleartext, Some(session_key), verifications))
+    }
+
+    /// Decrypt `msg` with rpgpie, which uses OpenPGP cards for decryption
+    fn unpack_with_card(
+        &self,
+        msg: Message,
+    ) -> sop::Result<(
+        LiteralData,
+        Option<sop::SessionKey>,
+        Vec<sop::ops::Verification>,
+    )> {
+        let key_passwords = self
+            .key_passwords
+            .iter()
+            .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+            .collect();
+
+        let skesk_passwords = self
+            .skesk_passwords
+            .iter()
+            .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+            .collect();
+
+        let mr = rpgpie::msg::unpack(
+            msg,
+            &self.decryption_keys,
+            key_passwords,
+            skesk_passwords,
+            &self.verify.certs,
+        )
+        .map_err(|e| match e {
+            // The card couldn't be used, e.g. because the PIN wasn't accepted
+            rpgpie::Error::Ocard(e) => {
+                log::warn!("OpenPGP card error: {e:?}");
+                sop::errors::Error::KeyIsProtected
+            }
+            // rpgpie didn't find a session key
+            rpgpie::Error::Message(e) => {
+                log::warn!("rpgpie error: {e}");
+                sop::errors::Error::CannotDecrypt
+            }
+            e => error::rpgpie(e),
+        })?;
+
+        let session_key = mr
+            .session_key
+            .as_ref()
+            .map(|sk| sop::SessionKey::new(sk.0, &(sk.1)))
+            .transpose()?;
+
+        let verifications = util::verifications_within(
+            &mr.validated,
+            self.verify.not_before,
+            self.verify.not_after,
+        );
+
+        Ok((mr.cleartext, session_key, verifications))
+    }
+
+    /// Decrypt the next message in `source` (which contains unarmored OpenPGP data) to `sink`.
+    ///
+    /// Returns the session key and verifications of the message, or nothing at the end of the
+    /// input.
+    /// A message that can't be decrypted as a stream is handed to rpgpie, together with the rest
+    /// of the input: then, the results for all remaining messages are returned.
+    ///
+    /// Unless `many` is set, the message must be the last one in `source`.
+    fn decrypt_next(
+        &self,
+        source: &mut dyn Read,
+        many: bool,
+        sink: &mut (dyn io::Write + Send + Sync),
+    ) -> Result<Vec<Decrypted>, DecryptError> {
+        // Read the encrypted session keys, up to the encrypted data.
+        // `head` collects the packets, in case we need to hand the message to rpgpie.
+        let mut head = vec![];
+        let mut pkesks = vec![];
+        let mut skesks = vec![];
+
+        let seipd = loop {
+            let Some(header) = packet::read_header(&mut *source).map_err(error::rpgp)? else {
+                if head.is_empty() {
+                    // The end of the input
+                    return Ok(vec![]);
+                }
+
+                break None;
+            };
+
+            match header.tag {
+                Tag::PublicKeyEncryptedSessionKey
+                | Tag::SymKeyEncryptedSessionKey
+                | Tag::Marker
+                | Tag::Padding => {
+                    let body = BodyReader::new(&mut *source, &header)
+                        .read_to_vec()
+                        .map_err(error::rpgp)?;
+
+                    match packet::parse(header.tag, &body).map_err(error::rpgp)? {
+                        Packet::PublicKeyEncryptedSessionKey(pkesk) => pkesks.push(pkesk),
+                        Packet::SymKeyEncryptedSessionKey(skesk) => skesks.push(skesk),
+                        _ => {}
+                    }
+
+                    Version::New
+                        .write_header(&mut head, header.tag.into(), body.len())
+                        .map_err(error::rpgp)?;
+                    head.extend_from_slice(&body);
+                }
+                Tag::SymEncryptedProtectedData if !self.needs_card(&pkesks) => break Some(header),
+                _ => {
+                    head.extend_from_slice(&header.raw);
+                    break None;
+                }
+            }
+        };
+
+        let Some(header) = seipd else {
+            return self.decrypt_buffered(Cursor::new(head).chain(source), many, sink);
+        };
+
+        let mut body = BodyReader::new(&mut *source, &header);
+
+        let mut version = [0u8];
+        body.read_exact(&mut version).map_err(error::io)?;
+
+        let mut probe = vec![];
+        body.by_ref()
+            .take(PROBE_LEN)
+            .read_to_end(&mut probe)
+            .map_err(error::io)?;
+
+        let (session_key, failure) = self.find_session_key(version[0], &pkesks, &skesks, |sk| {
+            match SeipdReader::new(&probe[..], version[0], sk) {
+                Ok(_) => Some(sk.clone()),
+                Err(e) => {
+                    log::info!("Session key doesn't fit: {e:?}");
+                    None
+                }
+            }
+        })?;
+
+        let mut reader = SeipdReader::new(Cursor::new(probe).chain(body), version[0], &session_key)
+            .map_err(error::rpgp)?;
+
+        let key = match &session_key {
+            PlainSessionKey::V3_4 { key, .. } | PlainSessionKey::V6 { key } => key,
+            PlainSessionKey::V5 { .. } => unreachable!(),
+        };
+        let session_key = sop::SessionKey::new(u8::from(reader.sym_alg()), key)?;
+
+        // Plaintext from a SEIPDv1 packet is only authenticated at the end, so we hold it back
+        // until then.
+        // SEIPDv2 chunks are authenticated individually and go to the sink right away. Data
+        // following a single message is reported as an error afterwards.
+        let mut spill = (!reader.is_authenticated()).then(Spill::new);
+        let out: &mut dyn Write = match &mut spill {
+            Some(spill) => spill,
+            None => sink,
+        };
+
+        // Only the quick check vouches for a SEIPDv1 session key, so far
+        let unverified = !reader.is_authenticated() && failure == DecryptFailure::MessagePassword;
+        let not_decrypted = |e: sop::errors::Error| -> DecryptError {
+            if unverified {
+                log::info!("Decryption with session key from SKESK failed: {e:?}");
+                failure.into()
+            } else {
+                e.into()
+            }
+        };
+
+        let validated = message::process(&mut reader, &self.verify.certs, out)
+            .map_err(|e| not_decrypted(error::rpgp(e)))?;
+        io::copy(&mut reader, &mut io::sink()).map_err(|e| not_decrypted(error::io(e)))?;
+        drop(reader);
+
+        // A single message must not be followed by anything
+        if !many
+            && packet::read_header(&mut *source)
+                .map_err(error::rpgp)?
+                .is_some()
+        {
+            return Err(sop::errors::Error::BadData.into());
+        }
+
+        if let Some(spill) = spill {
+            spill.release(sink)?;
+        }
+
+        let verifications =
+            util::verifications_within(&validated, self.verify.not_before, self.verify.not_after);
+
+        Ok(vec![(Some(session_key), verifications)])
+    }
 }
 
 impl<'a> sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> for Decrypt {
     fn verify_not_before(
-        self: Box<Self>,
-        _t: SystemTime,
+        mut self: Box<Self>,
+        t: SystemTime,
     ) -> Box<dyn sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> + 'a> {
-        todo!();
-
-        // self.verify.not_before = Some(t);
-        // self
+        self.verify.not_before = Some(t);
+        self
     }
 
     fn verify_not_after(
-        self: Box<Self>,
-        _t: SystemTime,
+        mut self: Box<Self>,
+        t: SystemTime,
     ) -> Box<dyn sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> + 'a> {
-        todo!()
-
-        // self.verify.not_after = Some(t);
-        // self
+        self.verify.not_after = Some(t);
+        self
     }
 
     fn verify_with_certs(
@@ -58,13 +613,11 @@ impl<'a> sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> for Decrypt {
     }
 
     fn with_session_key(
-        self: Box<Self>,
-        _session_key: sop::SessionKey,
+        mut self: Box<Self>,
+        session_key: sop::SessionKey,
     ) -> sop::Result<Box<dyn sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> + 'a>> {
-        todo!()
-
-        // self.session_keys.push(sk);
-        // Ok(self)
+        self.session_keys.push(session_key);
+        Ok(self)
     }
 
     fn with_password(
@@ -109,6 +662,11 @@ impl<'a> sop::ops::Decrypt<'a, RPGSOP, Certs, Keys> for Decrypt {
     }
 }
 
+/// Decrypts a message to a sink.
+///
+/// Plaintext from a SEIPDv1 packet is held back until its MDC has been checked: in memory, and
+/// beyond that in a temporary file that is encrypted with an ephemeral key.
+/// Authenticated SEIPDv2 chunks are written to the sink as they are decrypted.
 struct DecryptReady<'a> {
     decrypt: Decrypt,
     ciphertext: &'a mut (dyn io::Read + Send + Sync),
@@ -120,56 +678,450 @@ impl<'a> sop::ops::Ready<(Option<sop::SessionKey>, Vec<sop::ops::Verification>)>
     fn to_writer(
         self: Box<Self>,
         sink: &mut (dyn io::Write + Send + Sync),
-    ) -> sop::Result<(Option<sop::SessionKey>, Vec<sop::ops::Verification>)> {
-        let (mut iter, _header) = Message::from_reader_many(self.ciphertext).expect("FIXME");
+    ) -> sop::Result<Decrypted> {
+        let mut source = armor::dearmor(self.ciphertext)?;
+
+        // There must be exactly one message
+        match self.decrypt.decrypt_next(&mut source, false, sink)?.pop() {
+            Some(decrypted) => Ok(decrypted),
+            None => Err(sop::errors::Error::BadData),
+        }
+    }
+}
+
+/// Decryption of a sequence of concatenated messages.
+///
+/// This goes beyond `sop decrypt`, which only accepts input that consists of exactly one message.
+/// The messages are decrypted in order, and their plaintexts are written to the sink one after the
+/// other.
+///
+/// The input may be binary, or a single armored block.
+pub struct DecryptMany {
+    decrypt: Decrypt,
+}
+
+impl DecryptMany {
+    pub(crate) fn new() -> Self {
+        Self {
+            decrypt: Decrypt::new(),
+        }
+    }
+
+    /// Only consider signatures made at or after `t`
+    pub fn verify_not_before(mut self, t: SystemTime) -> Self {
+        self.decrypt.verify.not_before = Some(t);
+        self
+    }
+
+    /// Only consider signatures made at or before `t`
+    pub fn verify_not_after(mut self, t: SystemTime) -> Self {
+        self.decrypt.verify.not_after = Some(t);
+        self
+    }
+
+    /// Verify signatures with `certs`
+    pub fn verify_with_certs(mut self, certs: &Certs) -> Self {
+        self.decrypt
+            .verify
+            .certs
+            .extend(certs.certs.iter().cloned());
+        self
+    }
+
+    /// Try to decrypt with `session_key`
+    pub fn with_session_key(mut self, session_key: sop::SessionKey) -> Self {
+        self.decrypt.session_keys.push(session_key);
+        self
+    }
+
+    /// Try to decrypt with `password`
+    pub fn with_password(mut self, password: sop::Password) -> Self {
+        self.decrypt.skesk_passwords.push(password);
+        self
+    }
+
+    /// Try to decrypt with `keys`
+    pub fn with_keys(mut self, keys: &Keys) -> Self {
+        self.decrypt
+            .decryption_keys
+            .extend(keys.keys.iter().cloned());
+        self
+    }
+
+    /// Try to unlock the decryption keys with `password`
+    pub fn with_key_password(mut self, password: sop::Password) -> Self {
+        self.decrypt.key_passwords.push(password);
+        self
+    }
+
+    /// Decrypt the messages in `ciphertext` to `sink`.
+    ///
+    /// Returns the session key and the verifications for each message, in order.
+    /// Fails with [sop::errors::Error::BadData] if `ciphertext` contains no message, or anything
+    /// but messages, and with a [`DecryptFailure`] if no session key for a message is found.
+    pub fn to_writer(
+        self,
+        ciphertext: &mut (dyn io::Read + Send + Sync),
+        sink: &mut (dyn io::Write + Send + Sync),
+    ) -> Result<Vec<Decrypted>, DecryptError> {
+        let mut source = armor::dearmor(ciphertext)?;
+
+        let mut decrypted = vec![];
+        loop {
+            match self.decrypt.decrypt_next(&mut source, true, sink)? {
+                d if d.is_empty() => break,
+                mut d => decrypted.append(&mut d),
+            }
+        }
+
+        if decrypted.is_empty() {
+            return Err(sop::errors::Error::BadData.into());
+        }
+
+        Ok(decrypted)
+    }
+}
+
+#[test]
+fn test_decrypt_many() {
+    use pgp::KeyType;
+    use sop::ops::{Decrypt as _, Encrypt as _};
+
+    let tsk = Tsk::generate_v6(
+        KeyType::Ed25519,
+        KeyType::X25519,
+        Some("<alice@example.org>".to_string()),
+        vec![],
+        None,
+    )
+    .unwrap();
+
+    let certs = Certs {
+        certs: vec![rpgpie::key::Certificate::from(&tsk)],
+        source_name: None,
+    };
+    let keys = Keys {
+        keys: vec![tsk],
+        source_name: None,
+    };
+
+    let encrypt = |plaintext: &[u8]| {
+        Box::new(crate::cmd::encrypt::Encrypt::new())
+            .no_armor()
+            .with_certs(&certs)
+            .unwrap()
+            .sign_with_keys(&keys)
+            .unwrap()
+            .plaintext(&mut &plaintext[..])
+            .unwrap()
+            .to_vec()
+            .unwrap()
+            .1
+    };
+
+    let first = encrypt(b"first");
+    let second = encrypt(b"second");
+    let ciphertext = [first.clone(), second.clone()].concat();
+
+    // A single message may not be followed by another one
+    let decrypt = Box::new(Decrypt::new()).with_keys(&keys).unwrap();
+    assert!(matches!(
+        decrypt.ciphertext(&mut &ciphertext[..]).unwrap().to_vec(),
+        Err(sop::errors::Error::BadData)
+    ));
 
-        if let Some(Ok(msg)) = iter.next() {
-            // FIXME: use provided session keys, if any
+    let mut plaintext = vec![];
+    let decrypted = DecryptMany::new()
+        .with_keys(&keys)
+        .verify_with_certs(&certs)
+        .to_writer(&mut &ciphertext[..], &mut plaintext)
+        .unwrap();
 
-            let key_passwords = self
-                .decrypt
-                .key_passwords
+    assert_eq!(plaintext, b"firstsecond");
+    assert_eq!(decrypted.len(), 2);
+    for (session_key, verifications) in &decrypted {
+        assert!(session_key.is_some());
+        assert_eq!(verifications.len(), 1);
+    }
+
+    // Each message has its own session key
+    let key = |i: usize| decrypted[i].0.as_ref().map(|sk| sk.key().to_vec());
+    assert_ne!(key(0), key(1));
+
+    // Anything but messages is rejected
+    for input in [&[][..], &[first, b"garbage".to_vec()].concat()] {
+        assert!(matches!(
+            DecryptMany::new()
+                .with_keys(&keys)
+                .to_writer(&mut &input[..], &mut vec![]),
+            Err(DecryptError::Sop(sop::errors::Error::BadData))
+        ));
+    }
+}
+
+#[test]
+fn test_decrypt_time_window() {
+    use std::time::Duration;
+
+    use sop::ops::{Decrypt as _, Encrypt as _, GenerateKey as _};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    // Version 4 and 6 keys, which get SEIPDv1 and SEIPDv2 encrypted messages
+    for profile in ["draft-koch-eddsa-for-openpgp-00", "rfc9580"] {
+        let keys = Box::new(crate::GenerateKey::new())
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap();
+        let certs = Certs {
+            certs: keys
+                .keys
                 .iter()
-                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
-                .collect();
+                .map(rpgpie::key::Certificate::from)
+                .collect(),
+            source_name: None,
+        };
+
+        let ciphertext = Box::new(crate::cmd::encrypt::Encrypt::new())
+            .with_certs(&certs)
+            .unwrap()
+            .sign_with_keys(&keys)
+            .unwrap()
+            .plaintext(&mut &b"hello world"[..])
+            .unwrap()
+            .to_vec()
+            .unwrap()
+            .1;
+        let now = SystemTime::now();
+
+        let decrypt = |not_before: SystemTime, not_after: SystemTime| {
+            let (decrypted, plaintext) = Box::new(Decrypt::new())
+                .verify_not_before(not_before)
+                .verify_not_after(not_after)
+                .verify_with_certs(&certs)
+                .unwrap()
+                .with_keys(&keys)
+                .unwrap()
+                .ciphertext(&mut &ciphertext[..])
+                .unwrap()
+                .to_vec()
+                .unwrap();
+            assert_eq!(plaintext, b"hello world", "{profile}");
+
+            decrypted.1
+        };
+
+        assert_eq!(decrypt(now - HOUR, now + HOUR).len(), 1, "{profile}");
 
-            let skesk_passwords = self
-                .decrypt
-                .skesk_passwords
+        // The signature was made after, or before the window. The message still decrypts, but
+        // without verifications.
+        for (not_before, not_after) in [(now - 2 * HOUR, now - HOUR), (now + HOUR, now + 2 * HOUR)]
+        {
+            assert!(decrypt(not_before, not_after).is_empty(), "{profile}");
+        }
+    }
+}
+
+#[test]
+fn test_decrypt_session_key() {
+    use sop::ops::{Encrypt as _, GenerateKey as _};
+
+    // Version 4 and 6 keys, which get SEIPDv1 and SEIPDv2 encrypted messages
+    for profile in ["draft-koch-eddsa-for-openpgp-00", "rfc9580"] {
+        let keys = Box::new(crate::GenerateKey::new())
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap();
+        let certs = Certs {
+            certs: keys
+                .keys
                 .iter()
-                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
-                .collect();
-
-            let Ok(mr) = rpgpie::msg::unpack(
-                msg,
-                &self.decrypt.decryption_keys,
-                key_passwords,
-                skesk_passwords,
-                &self.decrypt.verify.certs,
-            ) else {
-                // FIXME: probably the password(s) were wrong, but this is a bit of a guess
-                //
-                // FIXME: SKESK decryption failure should give a different error?
-                return Err(sop::errors::Error::KeyIsProtected);
-            };
+                .map(rpgpie::key::Certificate::from)
+                .collect(),
+            source_name: None,
+        };
 
-            let session_key = mr
-                .session_key
-                .as_ref()
-                .map(|sk| sop::SessionKey::new(sk.0, &(sk.1)).expect("FIXME"));
+        let ciphertext = Box::new(crate::cmd::encrypt::Encrypt::new())
+            .with_certs(&certs)
+            .unwrap()
+            .plaintext(&mut &b"hello world"[..])
+            .unwrap()
+            .to_vec()
+            .unwrap()
+            .1;
 
-            let verifications = util::result_to_verifications(&mr);
+        let decrypt = |session_key: Option<&(u8, Vec<u8>)>, keys: Option<&Keys>| {
+            let mut decrypt: Box<dyn sop::ops::Decrypt<RPGSOP, Certs, Keys>> =
+                Box::new(Decrypt::new());
+            if let Some((algorithm, key)) = session_key {
+                let sk = sop::SessionKey::new(*algorithm, key).unwrap();
+                decrypt = decrypt.with_session_key(sk).unwrap();
+            }
+            if let Some(keys) = keys {
+                decrypt = decrypt.with_keys(keys).unwrap();
+            }
 
-            assert!(
-                iter.next().is_none(),
-                "message must be empty at this point!"
-            );
+            decrypt
+                .ciphertext(&mut &ciphertext[..])
+                .unwrap()
+                .to_vec()
+                .map(|((session_key, _), plaintext)| {
+                    let sk = session_key.unwrap();
+                    ((sk.algorithm(), sk.key().to_vec()), plaintext)
+                })
+        };
+
+        // The session key, as found via the PKESK
+        let (session_key, _) = decrypt(None, Some(&keys)).unwrap();
+
+        // Without any key material, the session key alone decrypts the message
+        let (used, plaintext) = decrypt(Some(&session_key), None).unwrap();
+        assert_eq!(plaintext, b"hello world", "{profile}");
+        assert_eq!(used, session_key, "{profile}");
+
+        // A wrong session key doesn't decrypt the message
+        let mut wrong = session_key.clone();
+        wrong.1[0] ^= 0xff;
+        assert!(
+            matches!(
+                decrypt(Some(&wrong), None),
+                Err(sop::errors::Error::CannotDecrypt)
+            ),
+            "{profile}"
+        );
+
+        // With a wrong session key, the PKESK is used, and the right session key is returned
+        let (used, plaintext) = decrypt(Some(&wrong), Some(&keys)).unwrap();
+        assert_eq!(plaintext, b"hello world", "{profile}");
+        assert_eq!(used, session_key, "{profile}");
+    }
+}
 
-            sink.write_all(mr.cleartext.data()).expect("FIXME");
+#[test]
+fn test_decrypt_failures() {
+    use sop::ops::{Encrypt as _, GenerateKey as _};
 
-            Ok((session_key, verifications))
-        } else {
-            panic!("no message found");
+    let password = |pw: &[u8]| sop::Password::new_unchecked(pw.to_vec());
+
+    let generate = |password: Option<sop::Password>| {
+        let mut generate = Box::new(crate::GenerateKey::new())
+            .profile("rfc9580")
+            .unwrap()
+            .userid("<alice@example.org>");
+        if let Some(password) = password {
+            generate = generate.with_key_password(password).unwrap();
         }
+        generate.generate().unwrap()
+    };
+    let cert = |keys: &Keys| Certs {
+        certs: keys
+            .keys
+            .iter()
+            .map(rpgpie::key::Certificate::from)
+            .collect(),
+        source_name: None,
+    };
+
+    let keys = generate(Some(password(b"password")));
+    let other = generate(None);
+
+    let to_key = Box::new(crate::cmd::encrypt::Encrypt::new())
+        .no_armor()
+        .with_certs(&cert(&keys))
+        .unwrap()
+        .plaintext(&mut &b"hello"[..])
+        .unwrap()
+        .to_vec()
+        .unwrap()
+        .1;
+    let to_password = Box::new(crate::cmd::encrypt::Encrypt::new())
+        .no_armor()
+        .with_password(password(b"password"))
+        .unwrap()
+        .plaintext(&mut &b"hello"[..])
+        .unwrap()
+        .to_vec()
+        .unwrap()
+        .1;
+
+    let failure = |decrypt: DecryptMany, ciphertext: &[u8]| match decrypt
+        .to_writer(&mut &ciphertext[..], &mut vec![])
+    {
+        Err(DecryptError::Failure(failure)) => failure,
+        Err(e) => panic!("unexpected error {e:?}"),
+        Ok(_) => panic!("unexpectedly decrypted"),
+    };
+
+    assert_eq!(
+        failure(DecryptMany::new().with_keys(&other), &to_key),
+        DecryptFailure::NoKey
+    );
+    assert_eq!(
+        failure(
+            DecryptMany::new().with_password(password(b"wrong")),
+            &to_password
+        ),
+        DecryptFailure::MessagePassword
+    );
+    assert_eq!(
+        failure(
+            DecryptMany::new()
+                .with_keys(&keys)
+                .with_key_password(password(b"wrong")),
+            &to_key
+        ),
+        DecryptFailure::KeyPassword
+    );
+
+    // Only the quick check vouches for a session key from a version 4 SKESK, so a SEIPDv1
+    // packet that fails to authenticate may be due to a wrong password
+    let mut unverified = to_password.clone();
+    let len = unverified.len();
+    unverified[len - 1] ^= 0x01;
+    assert_eq!(
+        failure(
+            DecryptMany::new().with_password(password(b"password")),
+            &unverified
+        ),
+        DecryptFailure::MessagePassword
+    );
+
+    // The session key is integrity protected, so a message that it doesn't decrypt is corrupt
+    let mut corrupt = to_key.clone();
+    let len = corrupt.len();
+    corrupt[len - 20] ^= 0x01;
+    assert_eq!(
+        failure(
+            DecryptMany::new()
+                .with_keys(&keys)
+                .with_key_password(password(b"password")),
+            &corrupt
+        ),
+        DecryptFailure::Corrupt
+    );
+
+    // Each reason maps to its SOP error
+    assert!(matches!(
+        sop::errors::Error::from(DecryptFailure::MessagePassword),
+        sop::errors::Error::CannotDecrypt
+    ));
+    assert!(matches!(
+        sop::errors::Error::from(DecryptFailure::KeyPassword),
+        sop::errors::Error::KeyIsProtected
+    ));
+}
+
+/// Can `sec` be unlocked with `pw`?
+fn unlocks(sec: &SignedComponentKeySec, pw: &[u8]) -> bool {
+    let pw = || String::from_utf8_lossy(pw).into();
+
+    match sec {
+        SignedComponentKeySec::Primary(sk) => sk.unlock(pw, |_| Ok(())),
+        SignedComponentKeySec::Subkey((ssk, _)) => ssk.unlock(pw, |_| Ok(())),
     }
+    .is_ok()
 }
@@ -5,7 +5,7 @@ use std::io::{BufRead, Read, Write};
 
 use pgp::{Deserializable, Message, Signature};
 
-use crate::Sigs;
+use crate::{error, Sigs};
 
 #[derive(Default)]
 pub(crate) struct InlineDetach {}
@@ -54,8 +54,8 @@ impl sop::ops::Ready<Sigs> for InlineDetachReady<'_> {
 
             match msg {
                 Message::Compressed(cd) => {
-                    let payload = cd.decompress().expect("FIXME");
-                    let msg = Message::from_bytes(payload).expect("FIXME");
+                    let payload = cd.decompress().map_err(error::rpgp)?;
+                    let msg = Message::from_bytes(payload).map_err(error::rpgp)?;
 
                     unwrap_signed_internal(msg, sigs, depth + 1)
                 }
@@ -63,7 +63,11 @@ impl sop::ops::Ready<Sigs> for InlineDetachReady<'_> {
                     message, signature, ..
                 } => {
                     sigs.push(signature);
-                    unwrap_signed_internal(*message.expect("FIXME"), sigs, depth + 1)
+                    let Some(message) = message else {
+                        return Err(sop::errors::Error::BadData);
+                    };
+
+                    unwrap_signed_internal(*message, sigs, depth + 1)
                 }
                 Message::Literal(lit) => Ok((lit.data().to_vec(), sigs)),
                 Message::Encrypted { .. } => Err(sop::errors::Error::BadData),
@@ -75,16 +79,16 @@ impl sop::ops::Ready<Sigs> for InlineDetachReady<'_> {
 
         let buf = reader.fill_buf()?;
         if buf.is_empty() {
-            panic!("empty input");
+            return Err(sop::errors::Error::BadData);
         }
 
         let (payload, sigs) = if buf[0] & 0x80 != 0 {
             // the input seems to be binary data - presumably an unarmored signed message
-            let msg = Message::from_bytes(reader).expect("FIXME");
+            let msg = Message::from_bytes(reader).map_err(error::rpgp)?;
 
             unwrap_signed(msg)?
         } else {
-            let (pgp, _) = pgp::Any::from_armor(reader).expect("FIXME");
+            let (pgp, _) = pgp::Any::from_armor(reader).map_err(error::rpgp)?;
 
             match pgp {
                 pgp::Any::Message(msg) => unwrap_signed(msg)?,
@@ -99,11 +103,11 @@ impl sop::ops::Ready<Sigs> for InlineDetachReady<'_> {
                     (payload, sigs)
                 }
 
-                _ => panic!("unexpected data type"),
+                _ => return Err(sop::errors::Error::BadData),
             }
         };
 
-        sink.write_all(&payload).expect("FIXME");
+        sink.write_all(&payload)?;
 
         Ok(Sigs {
             sigs,
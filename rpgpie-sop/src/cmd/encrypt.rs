@@ -4,16 +4,26 @@
 use std::io;
 
 use chrono::{DateTime, Utc};
+use pgp::armor::BlockType;
 use pgp::crypto::aead::AeadAlgorithm;
 use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet::{SignatureType, SymKeyEncryptedSessionKey};
+use pgp::ser::Serialize;
+use pgp::types::{SecretKeyTrait, StringToKey};
+use pgp::Esk;
+use rand::thread_rng;
 use rpgpie::key::checked::CheckedCertificate;
-use rpgpie::key::component::ComponentKeyPub;
-use rpgpie::key::Certificate;
+use rpgpie::key::component::{ComponentKeyPub, ComponentKeySec};
+use rpgpie::key::{Certificate, DataSigner};
 use rpgpie::msg;
 use rpgpie::policy::Seipd;
+use zeroize::Zeroizing;
 
 use crate::cmd::sign::Sign;
-use crate::{Certs, Keys, RPGSOP};
+use crate::stream::armor::ArmorWriter;
+use crate::stream::seipd::{SeipdV1Writer, SeipdV2Writer};
+use crate::stream::sign::{self, Signer};
+use crate::{error, Certs, Keys, RPGSOP};
 
 pub(crate) struct Encrypt {
     armor: bool,
@@ -198,7 +208,7 @@ impl<'a> sop::ops::Ready<Option<sop::SessionKey>> for EncryptReady<'a> {
             match self.encrypt.profile {
                 Encrypt::PROFILE_RFC4880 => Seipd::SEIPD1,
                 Encrypt::PROFILE_RFC9580 => Seipd::SEIPD2,
-                _ => unimplemented!(),
+                _ => return Err(sop::errors::Error::UnsupportedProfile),
             }
         };
 
@@ -221,9 +231,191 @@ impl<'a> sop::ops::Ready<Option<sop::SessionKey>> for EncryptReady<'a> {
 
                 msg::EncryptionMechanism::SeipdV2(aead_algo.1, aead_algo.0)
             }
-            Seipd::SED => unimplemented!("SED"),
+            // We never encrypt using the obsolete SED mechanism
+            Seipd::SED => return Err(sop::errors::Error::CertCannotEncrypt),
         };
 
+        // Like rpgpie, we sign with the first signing capable component key of each signer
+        let data_signers = self
+            .encrypt
+            .sign
+            .signers
+            .iter()
+            .map(|tsk| tsk.signing_capable_component_keys().next())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(sop::errors::Error::KeyCannotSign)?;
+
+        let session_key = if data_signers
+            .iter()
+            .any(|ds| matches!(ds, DataSigner::Card(_)))
+        {
+            // Signing with card-backed keys is only possible via rpgpie, which processes the
+            // message in memory
+            self.encrypt_buffered(mechanism, data_signers, sink)?
+        } else {
+            let signers = data_signers
+                .into_iter()
+                .filter_map(|ds| match ds {
+                    DataSigner::Software(key) => Some(key),
+                    DataSigner::Card(_) => None,
+                })
+                .collect();
+
+            self.encrypt_streaming(mechanism, signers, sink)?
+        };
+
+        let alg_id = u8::from(match mechanism {
+            msg::EncryptionMechanism::SeipdV1(sym) | msg::EncryptionMechanism::SeipdV2(_, sym) => {
+                sym
+            }
+        });
+        let session_key = sop::SessionKey::new(alg_id, &session_key)?;
+
+        Ok(Some(session_key))
+    }
+}
+
+impl EncryptReady<'_> {
+    /// Encrypt (and maybe sign) the plaintext as it is read, with constant memory use
+    fn encrypt_streaming(
+        self,
+        mechanism: msg::EncryptionMechanism,
+        signers: Vec<ComponentKeySec>,
+        mut sink: &mut (dyn io::Write + Send + Sync),
+    ) -> sop::Result<Zeroizing<Vec<u8>>> {
+        let mut rng = thread_rng();
+
+        // Passwords to try
+        let pws: Vec<&[u8]> = if self.encrypt.sign.with_key_password.is_empty() {
+            vec![&[]]
+        } else {
+            self.encrypt
+                .sign
+                .with_key_password
+                .iter()
+                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+                .collect()
+        };
+
+        let hash_alg = self
+            .encrypt
+            .sign
+            .hash_algos
+            .first()
+            .copied()
+            .unwrap_or_default();
+
+        // Unlock all signers before producing any output
+        let signers = signers
+            .into_iter()
+            .map(|key| Signer::new(key, &pws, SignatureType::Binary, hash_alg))
+            .collect::<sop::Result<Vec<_>>>()?;
+
+        let session_key = match mechanism {
+            msg::EncryptionMechanism::SeipdV1(sym) | msg::EncryptionMechanism::SeipdV2(_, sym) => {
+                sym.new_session_key(&mut rng)
+            }
+        };
+
+        let mut esk = vec![];
+
+        for recipient in &self.encrypt.recipients {
+            let pkesk = match mechanism {
+                msg::EncryptionMechanism::SeipdV1(sym) => {
+                    recipient.pkesk_from_session_key_v3(&mut rng, &session_key, sym)
+                }
+                msg::EncryptionMechanism::SeipdV2(_, _) => {
+                    recipient.pkesk_from_session_key_v6(&mut rng, &session_key)
+                }
+            }
+            .map_err(error::rpgpie)?;
+
+            esk.push(Esk::PublicKeyEncryptedSessionKey(pkesk));
+        }
+
+        for pw in &self.encrypt.skesk_passwords {
+            let pw = sop::plumbing::PasswordsAreHumanReadable::normalized(pw);
+            let pass = String::from_utf8_lossy(pw).to_string();
+
+            let skesk = match mechanism {
+                msg::EncryptionMechanism::SeipdV1(sym) => SymKeyEncryptedSessionKey::encrypt_v4(
+                    || pass,
+                    &session_key,
+                    StringToKey::new_default(&mut rng),
+                    sym,
+                ),
+                msg::EncryptionMechanism::SeipdV2(aead, sym) => {
+                    // The same Argon2 parameters that rpgpie uses
+                    // (the "SECOND RECOMMENDED option" of RFC 9580)
+                    let s2k = StringToKey::new_argon2(&mut rng, 3, 4, 16);
+
+                    SymKeyEncryptedSessionKey::encrypt_v6(
+                        &mut rng,
+                        || pass,
+                        &session_key,
+                        s2k,
+                        sym,
+                        aead,
+                    )
+                }
+            }
+            .map_err(error::rpgp)?;
+
+            esk.push(Esk::SymKeyEncryptedSessionKey(skesk));
+        }
+
+        match self.encrypt.armor {
+            true => {
+                let mut armor = ArmorWriter::new(sink, BlockType::Message).map_err(error::io)?;
+                write_message(
+                    mechanism,
+                    &session_key,
+                    &esk,
+                    signers,
+                    self.plaintext,
+                    &mut armor,
+                )
+                .map_err(error::rpgp)?;
+                armor.finish().map_err(error::io)?;
+            }
+            false => write_message(
+                mechanism,
+                &session_key,
+                &esk,
+                signers,
+                self.plaintext,
+                &mut sink,
+            )
+            .map_err(error::rpgp)?,
+        }
+
+        Ok(session_key)
+    }
+
+    /// Encrypt (and maybe sign) the plaintext with rpgpie
+    fn encrypt_buffered(
+        self,
+        mechanism: msg::EncryptionMechanism,
+        data_signers: Vec<DataSigner>,
+        sink: &mut (dyn io::Write + Send + Sync),
+    ) -> sop::Result<Zeroizing<Vec<u8>>> {
+        // rpgpie can't use key passwords while signing during encryption, so software keys must
+        // unlock without one
+        for ds in &data_signers {
+            let DataSigner::Software(key) = ds else {
+                continue;
+            };
+
+            let unlocked = match key {
+                ComponentKeySec::Primary(sk) => sk.unlock(String::default, |_| Ok(())),
+                ComponentKeySec::Subkey(ssk) => ssk.unlock(String::default, |_| Ok(())),
+            };
+            if let Err(e) = unlocked {
+                log::warn!("Unlocking signer failed: {e:?}");
+                return Err(sop::errors::Error::KeyIsProtected);
+            }
+        }
+
         let skesk_passwords = self
             .encrypt
             .skesk_passwords
@@ -231,7 +423,7 @@ impl<'a> sop::ops::Ready<Option<sop::SessionKey>> for EncryptReady<'a> {
             .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
             .collect();
 
-        let session_key: Vec<u8> = msg::encrypt(
+        msg::encrypt(
             mechanism,
             self.encrypt.recipients,
             skesk_passwords,
@@ -241,16 +433,50 @@ impl<'a> sop::ops::Ready<Option<sop::SessionKey>> for EncryptReady<'a> {
             sink,
             self.encrypt.armor,
         )
-        .expect("FIXME")
-        .to_vec();
-
-        let alg_id = u8::from(match mechanism {
-            msg::EncryptionMechanism::SeipdV1(sym) | msg::EncryptionMechanism::SeipdV2(_, sym) => {
-                sym
+        .map_err(|e| match e {
+            rpgpie::Error::Io(e) => sop::errors::Error::IoError(e),
+            // The card couldn't be used for signing, e.g. because the PIN wasn't accepted
+            rpgpie::Error::Ocard(e) => {
+                log::warn!("OpenPGP card error: {e:?}");
+                sop::errors::Error::KeyIsProtected
             }
-        });
-        let session_key = sop::SessionKey::new(alg_id, session_key)?;
+            e => error::rpgpie(e),
+        })
+    }
+}
 
-        Ok(Some(session_key))
+/// Write an encrypted message to `w`, producing it while the plaintext is read
+fn write_message<W: io::Write>(
+    mechanism: msg::EncryptionMechanism,
+    session_key: &[u8],
+    esk: &[Esk],
+    signers: Vec<Signer>,
+    plaintext: &mut (dyn io::Read + Send + Sync),
+    w: &mut W,
+) -> pgp::errors::Result<()> {
+    for esk in esk {
+        esk.to_writer(w)?;
     }
+
+    match mechanism {
+        msg::EncryptionMechanism::SeipdV1(sym) => {
+            let mut seipd = SeipdV1Writer::new(thread_rng(), w, sym, session_key)?;
+            sign::write_signed_literal(plaintext, signers, false, &mut seipd)?;
+            seipd.finish()?;
+        }
+        msg::EncryptionMechanism::SeipdV2(aead, sym) => {
+            let mut seipd = SeipdV2Writer::new(
+                thread_rng(),
+                w,
+                sym,
+                aead,
+                rpgpie::policy::AEAD_CHUNK_SIZE,
+                session_key,
+            )?;
+            sign::write_signed_literal(plaintext, signers, false, &mut seipd)?;
+            seipd.finish()?;
+        }
+    }
+
+    Ok(())
 }
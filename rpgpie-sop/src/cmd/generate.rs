@@ -2,11 +2,27 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
 
+use chrono::{DateTime, SubsecRound, Utc};
 use pgp::crypto::ecc_curve::ECCCurve;
+use pgp::packet::{
+    KeyFlags, PacketTrait, SignatureType, SignatureVersionSpecific, Subpacket, SubpacketData,
+    UserId,
+};
+use pgp::types::{KeyVersion, PublicKeyTrait, SecretKeyTrait};
+use pgp::{packet, KeyType, Signature, SignedKeyDetails, SignedSecretKey, SignedSecretSubKey};
+use rand::thread_rng;
+use rand_core::CryptoRngCore;
 use rpgpie::key::Tsk;
+use rpgpie::policy::{
+    PREFERRED_AEAD_ALGORITHMS, PREFERRED_COMPRESSION_ALGORITHMS, PREFERRED_HASH_ALGORITHMS,
+    PREFERRED_SYMMETRIC_KEY_ALGORITHMS,
+};
 
-use crate::{Keys, RPGSOP};
+use crate::cmd::password::S2kProfile;
+use crate::cmd::update_key::{FEATURE_SEIPD_V1, FEATURE_SEIPD_V2};
+use crate::{error, Keys, RPGSOP};
 
 const PROFILE_EDDSA: &str = "draft-koch-eddsa-for-openpgp-00";
 const PROFILE_RFC9580: &str = "rfc9580";
@@ -20,6 +36,8 @@ const PROFILE_RFC9580_NISTP: &str = "interop-testing-rfc9580-nistp";
 const PROFILE_RFC9580_RSA: &str = "interop-testing-rfc9580-rsa";
 const PROFILE_RFC9580_CV448: &str = "interop-testing-rfc9580-cv448";
 
+// There is no post-quantum (ML-KEM + X25519, ML-DSA + Ed25519) profile: rPGP 0.14 implements
+// neither ML-KEM nor ML-DSA, so such keys could be neither generated nor used.
 const PROFILES: &[(&str, &str)] = &[
     (PROFILE_EDDSA, "use EdDSA & ECDH over Cv25519"),
     (PROFILE_RFC9580, "use algorithms from RFC 9580"),
@@ -40,11 +58,24 @@ const PROFILES: &[(&str, &str)] = &[
     ),
 ];
 
-pub(crate) struct GenerateKey {
+/// Generation of a key, as in `sop generate-key`.
+///
+/// Beyond the SOP interface, this allows setting expiration times for the key and its subkeys,
+/// choosing its component keys, choosing how the key is protected with a password, and
+/// deterministic generation for test data.
+/// These settings must be made before using the methods of [`sop::ops::GenerateKey`].
+pub struct GenerateKey {
     profile: &'static str,
     signing_only: bool,
     key_password: Option<sop::Password>,
     user_ids: VecDeque<String>,
+    key_expiration: Option<Duration>,
+    subkey_expiration: Option<Duration>,
+    layout: KeyLayout,
+    s2k: S2kProfile,
+
+    /// Creation time and source of randomness, for deterministic generation
+    deterministic: Option<(DateTime<Utc>, Box<dyn CryptoRngCore>)>,
 }
 
 impl GenerateKey {
@@ -54,8 +85,59 @@ impl GenerateKey {
             signing_only: false,
             key_password: Default::default(),
             user_ids: Default::default(),
+            key_expiration: None,
+            subkey_expiration: None,
+            layout: KeyLayout::standard(),
+            s2k: S2kProfile::INTERACTIVE,
+            deterministic: None,
         }
     }
+
+    /// Let the key expire after `validity`, counted from its creation
+    pub fn key_expiration(mut self: Box<Self>, validity: Duration) -> Box<Self> {
+        self.key_expiration = Some(validity);
+        self
+    }
+
+    /// Let the subkeys expire after `validity`, counted from their creation.
+    ///
+    /// Without this setting, subkeys expire together with the key.
+    pub fn subkey_expiration(mut self: Box<Self>, validity: Duration) -> Box<Self> {
+        self.subkey_expiration = Some(validity);
+        self
+    }
+
+    /// Generate the component keys described by `layout`, instead of the standard layout of a
+    /// primary key and an encryption subkey.
+    ///
+    /// With `signing_only`, encryption-capable subkeys of the layout are left out.
+    pub fn layout(mut self: Box<Self>, layout: KeyLayout) -> Box<Self> {
+        self.layout = layout;
+        self
+    }
+
+    /// Protect the key with its password as described by `profile`, instead of
+    /// [`S2kProfile::INTERACTIVE`]
+    pub fn s2k_profile(mut self: Box<Self>, profile: S2kProfile) -> Box<Self> {
+        self.s2k = profile;
+        self
+    }
+
+    /// Generate the key deterministically: all component keys and signatures are created at
+    /// `created` (truncated to full seconds), and all randomness is taken from `rng`.
+    ///
+    /// With the same settings, `created` and an identically seeded `rng`, the output is identical
+    /// byte for byte. This is meant for reproducible test data, such as expected keys in interop
+    /// tests. The secrecy of the generated key depends entirely on the seed of `rng`.
+    pub fn deterministic(
+        mut self: Box<Self>,
+        created: SystemTime,
+        rng: impl CryptoRngCore + 'static,
+    ) -> Box<Self> {
+        let created = DateTime::<Utc>::from(created).trunc_subsecs(0);
+        self.deterministic = Some((created, Box::new(rng)));
+        self
+    }
 }
 
 impl<'a> sop::ops::GenerateKey<'a, RPGSOP, Keys> for GenerateKey {
@@ -107,124 +189,812 @@ impl<'a> sop::ops::GenerateKey<'a, RPGSOP, Keys> for GenerateKey {
     }
 
     fn generate(mut self: Box<Self>) -> sop::Result<Keys> {
-        let primary_user_id = self.user_ids.pop_front();
-        let other_user_ids = self.user_ids.into();
-
         let key_password: Option<&[u8]> = self
             .key_password
             .as_ref()
             .map(sop::plumbing::PasswordsAreHumanReadable::normalized);
 
-        let key_password: Option<String> =
-            key_password.map(String::from_utf8_lossy).map(Into::into);
+        // Passwords for newly generated keys must be human-readable
+        let key_password: Option<String> = key_password
+            .map(|pw| std::str::from_utf8(pw).map(Into::into))
+            .transpose()
+            .map_err(|_| sop::errors::Error::PasswordNotHumanReadable)?;
 
-        let (key_type_pri, key_type_enc) = match self.profile {
+        let algorithms =
+            Algorithms::for_profile(self.profile).ok_or(sop::errors::Error::UnsupportedProfile)?;
+
+        let tsk = match self.deterministic.take() {
+            Some((created, mut rng)) => {
+                self.tsk(&algorithms, key_password.as_deref(), &created, &mut *rng)?
+            }
+            None => self.tsk(
+                &algorithms,
+                key_password.as_deref(),
+                &Utc::now().trunc_subsecs(0),
+                &mut thread_rng(),
+            )?,
+        };
+
+        Ok(Keys {
+            keys: vec![tsk],
+            source_name: None,
+        })
+    }
+}
+
+/// The key version and algorithms that a profile uses
+struct Algorithms {
+    version: KeyVersion,
+
+    /// Algorithm of the primary key, and of signing and authentication subkeys
+    signing: KeyType,
+
+    /// Algorithm of encryption subkeys
+    encryption: KeyType,
+}
+
+impl Algorithms {
+    fn for_profile(profile: &str) -> Option<Self> {
+        let (version, signing, encryption) = match profile {
             // Curve 25519-based keys
             PROFILE_EDDSA => (
-                pgp::KeyType::EdDSALegacy,
-                pgp::KeyType::ECDH(ECCCurve::Curve25519),
+                KeyVersion::V4,
+                KeyType::EdDSALegacy,
+                KeyType::ECDH(ECCCurve::Curve25519),
             ),
 
             // RSA 4096 is compatible with Gnuk v1 (while RSA 3072 is not)
-            PROFILE_RFC4880 => (pgp::KeyType::Rsa(4096), pgp::KeyType::Rsa(4096)),
+            PROFILE_RFC4880 => (KeyVersion::V4, KeyType::Rsa(4096), KeyType::Rsa(4096)),
 
             // Nist-P* -based keys
             PROFILE_NISTP256 => (
-                pgp::KeyType::ECDSA(ECCCurve::P256),
-                pgp::KeyType::ECDH(ECCCurve::P256),
+                KeyVersion::V4,
+                KeyType::ECDSA(ECCCurve::P256),
+                KeyType::ECDH(ECCCurve::P256),
             ),
             PROFILE_NISTP384 => (
-                pgp::KeyType::ECDSA(ECCCurve::P384),
-                pgp::KeyType::ECDH(ECCCurve::P384),
+                KeyVersion::V4,
+                KeyType::ECDSA(ECCCurve::P384),
+                KeyType::ECDH(ECCCurve::P384),
             ),
             PROFILE_NISTP521 => (
-                pgp::KeyType::ECDSA(ECCCurve::P521),
-                pgp::KeyType::ECDH(ECCCurve::P521),
+                KeyVersion::V4,
+                KeyType::ECDSA(ECCCurve::P521),
+                KeyType::ECDH(ECCCurve::P521),
             ),
 
-            PROFILE_RFC9580 => {
-                let tsk = Tsk::generate_v6(
-                    pgp::KeyType::Ed25519,
-                    pgp::KeyType::X25519,
-                    primary_user_id,
-                    other_user_ids,
-                    key_password.as_deref(),
-                )
-                .expect("FIXME");
+            PROFILE_RFC9580 => (KeyVersion::V6, KeyType::Ed25519, KeyType::X25519),
+            PROFILE_RFC9580_NISTP => (
+                KeyVersion::V6,
+                KeyType::ECDSA(ECCCurve::P256),
+                KeyType::ECDH(ECCCurve::P256),
+            ),
+            PROFILE_RFC9580_RSA => (KeyVersion::V6, KeyType::Rsa(4096), KeyType::Rsa(4096)),
+            // rPGP 0.14 has no Ed448: it can't generate such keys, sign or verify with them, or
+            // even parse them. So this profile keeps an Ed25519 primary key until rPGP does.
+            PROFILE_RFC9580_CV448 => (KeyVersion::V6, KeyType::Ed25519, KeyType::X448),
+
+            _ => return None,
+        };
+
+        Some(Self {
+            version,
+            signing,
+            encryption,
+        })
+    }
+}
+
+/// The component keys of a generated key.
+///
+/// Unless set explicitly, component keys use the algorithms of the profile: its signing algorithm
+/// for the primary key and for signing and authentication subkeys, and its encryption algorithm
+/// for encryption subkeys.
+#[derive(Clone, Debug)]
+pub struct KeyLayout {
+    primary_signs: bool,
+    subkeys: Vec<SubkeySpec>,
+}
+
+impl KeyLayout {
+    /// A primary key that can certify, and sign if `signs` is set, without any subkeys
+    pub fn new(signs: bool) -> Self {
+        Self {
+            primary_signs: signs,
+            subkeys: vec![],
+        }
+    }
+
+    /// Add `subkey` to the layout
+    pub fn subkey(mut self, subkey: SubkeySpec) -> Self {
+        self.subkeys.push(subkey);
+        self
+    }
+
+    /// A primary key that can certify and sign, with an encryption subkey (the default layout)
+    pub fn standard() -> Self {
+        Self::new(true).subkey(SubkeySpec::encryption())
+    }
+
+    /// A primary key that can certify and sign, with an encryption and an authentication subkey.
+    ///
+    /// This fills the three key slots of an OpenPGP card, with the primary key in the signing slot.
+    pub fn card() -> Self {
+        Self::standard().subkey(SubkeySpec::authentication())
+    }
+
+    /// A primary key that can only certify, with a signing, an encryption and an authentication
+    /// subkey.
+    ///
+    /// The subkeys fill the three key slots of an OpenPGP card, while the primary key can be kept
+    /// offline.
+    pub fn card_subkeys() -> Self {
+        Self::new(false)
+            .subkey(SubkeySpec::signing())
+            .subkey(SubkeySpec::encryption())
+            .subkey(SubkeySpec::authentication())
+    }
+}
+
+impl Default for KeyLayout {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+/// A subkey of a generated key
+#[derive(Clone, Debug)]
+pub struct SubkeySpec {
+    flags: KeyFlags,
+    algorithm: Option<KeyType>,
+    expiration: Option<Duration>,
+}
+
+impl SubkeySpec {
+    /// A subkey with the capabilities in `flags`
+    pub fn new(flags: KeyFlags) -> Self {
+        Self {
+            flags,
+            algorithm: None,
+            expiration: None,
+        }
+    }
+
+    /// A subkey for signing
+    pub fn signing() -> Self {
+        let mut flags = KeyFlags::default();
+        flags.set_sign(true);
+
+        Self::new(flags)
+    }
+
+    /// A subkey for encryption (of both communications and storage)
+    pub fn encryption() -> Self {
+        let mut flags = KeyFlags::default();
+        flags.set_encrypt_comms(true);
+        flags.set_encrypt_storage(true);
+
+        Self::new(flags)
+    }
+
+    /// A subkey for authentication
+    pub fn authentication() -> Self {
+        let mut flags = KeyFlags::default();
+        flags.set_authentication(true);
+
+        Self::new(flags)
+    }
+
+    /// Use `algorithm` for this subkey, instead of the algorithm from the profile
+    pub fn algorithm(mut self, algorithm: KeyType) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    /// Let this subkey expire after `validity`, instead of the subkey validity of the key
+    pub fn expiration(mut self, validity: Duration) -> Self {
+        self.expiration = Some(validity);
+        self
+    }
+
+    fn encrypts(&self) -> bool {
+        self.flags.encrypt_comms() || self.flags.encrypt_storage()
+    }
+
+    /// Does this subkey need an algorithm that can make signatures?
+    fn signs(&self) -> bool {
+        self.flags.certify() || self.flags.sign() || self.flags.authentication()
+    }
+}
+
+impl GenerateKey {
+    /// Generate a key with the layout of `self`, using `algorithms` by default
+    fn tsk(
+        &self,
+        algorithms: &Algorithms,
+        key_password: Option<&str>,
+        created: &DateTime<Utc>,
+        rng: &mut dyn CryptoRngCore,
+    ) -> sop::Result<Tsk> {
+        let version = algorithms.version;
+
+        // Version 4 keys carry their metadata in User ID binding signatures
+        if version == KeyVersion::V4 && self.user_ids.is_empty() {
+            log::warn!("Generating UID-less version 4 keys is not supported");
+            return Err(sop::errors::Error::BadData);
+        }
+
+        let key_expiration = self.key_expiration.map(expiration_time).transpose()?;
+
+        let (public_params, secret_params) = algorithms
+            .signing
+            .generate(&mut *rng)
+            .map_err(error::rpgp)?;
+        let public = packet::PublicKey::new(
+            Default::default(),
+            version,
+            algorithms.signing.to_alg(),
+            *created,
+            None,
+            public_params,
+        )
+        .map_err(error::rpgp)?;
+        let mut primary = packet::SecretKey::new(public, secret_params);
 
-                return Ok(Keys {
-                    keys: vec![tsk],
-                    source_name: None,
-                });
+        let mut flags = KeyFlags::default();
+        flags.set_certify(true);
+        flags.set_sign(self.layout.primary_signs);
+
+        // Metadata of the key, in the direct key and User ID binding signatures
+        let mut metadata = vec![
+            Subpacket::regular(SubpacketData::KeyFlags(flags.into())),
+            Subpacket::regular(SubpacketData::PreferredSymmetricAlgorithms(
+                PREFERRED_SYMMETRIC_KEY_ALGORITHMS.into(),
+            )),
+            Subpacket::regular(SubpacketData::PreferredHashAlgorithms(
+                PREFERRED_HASH_ALGORITHMS.into(),
+            )),
+            Subpacket::regular(SubpacketData::PreferredCompressionAlgorithms(
+                PREFERRED_COMPRESSION_ALGORITHMS.into(),
+            )),
+        ];
+        if version == KeyVersion::V6 {
+            metadata.push(Subpacket::regular(SubpacketData::PreferredAeadAlgorithms(
+                PREFERRED_AEAD_ALGORITHMS.into(),
+            )));
+        }
+        if let Some(expiration) = key_expiration {
+            metadata.push(Subpacket::regular(SubpacketData::KeyExpirationTime(
+                expiration,
+            )));
+        }
+
+        // Version 6 keys carry their metadata in a direct key signature
+        let mut direct_signatures = vec![];
+        if version == KeyVersion::V6 {
+            let mut config = crate::util::signature_config_with_rng(
+                &mut *rng,
+                &primary,
+                SignatureType::Key,
+                primary.hash_alg(),
+                created,
+            )?;
+            config.hashed_subpackets.extend(metadata.iter().cloned());
+            config
+                .hashed_subpackets
+                .push(Subpacket::regular(SubpacketData::Features(
+                    [FEATURE_SEIPD_V1 | FEATURE_SEIPD_V2][..].into(),
+                )));
+
+            let dks = config
+                .sign_key(&primary, String::new, &primary)
+                .map_err(error::rpgp)?;
+            direct_signatures.push(dks);
+        }
+
+        let mut users = vec![];
+        for (i, user_id) in self.user_ids.iter().enumerate() {
+            let id = UserId::from_str(Default::default(), user_id);
+
+            let mut config = crate::util::signature_config_with_rng(
+                &mut *rng,
+                &primary,
+                SignatureType::CertGeneric,
+                primary.hash_alg(),
+                created,
+            )?;
+            if i == 0 {
+                config
+                    .hashed_subpackets
+                    .push(Subpacket::regular(SubpacketData::IsPrimary(true)));
             }
+            config.hashed_subpackets.extend(metadata.iter().cloned());
 
-            PROFILE_RFC9580_NISTP => {
-                let tsk = Tsk::generate_v6(
-                    pgp::KeyType::ECDSA(ECCCurve::P256),
-                    pgp::KeyType::ECDH(ECCCurve::P256),
-                    primary_user_id,
-                    other_user_ids,
-                    key_password.as_deref(),
-                )
-                .expect("FIXME");
+            let sig = config
+                .sign_certification(&primary, String::new, id.tag(), &id)
+                .map_err(error::rpgp)?;
+            users.push(id.into_signed(sig));
+        }
 
-                return Ok(Keys {
-                    keys: vec![tsk],
-                    source_name: None,
-                });
+        let mut secret_subkeys = vec![];
+        for spec in &self.layout.subkeys {
+            // Signing-only keys get no encryption-capable subkeys
+            if self.signing_only && spec.encrypts() {
+                continue;
             }
 
-            PROFILE_RFC9580_RSA => {
-                let tsk = Tsk::generate_v6(
-                    pgp::KeyType::Rsa(4096),
-                    pgp::KeyType::Rsa(4096),
-                    primary_user_id,
-                    other_user_ids,
-                    key_password.as_deref(),
-                )
-                .expect("FIXME");
+            let mut key = self.subkey(spec, algorithms, created, rng)?;
+            let sig = self.subkey_binding(spec, &primary, &key, created, rng)?;
 
-                return Ok(Keys {
-                    keys: vec![tsk],
-                    source_name: None,
-                });
+            if let Some(key_password) = key_password {
+                key.set_password_with_s2k(
+                    || key_password.to_string(),
+                    self.s2k.params(&mut *rng, version)?,
+                )
+                .map_err(error::rpgp)?;
             }
 
-            PROFILE_RFC9580_CV448 => {
-                let tsk = Tsk::generate_v6(
-                    pgp::KeyType::Ed25519, // FIXME: use Ed448 when rpgp supports it
-                    pgp::KeyType::X448,
-                    primary_user_id,
-                    other_user_ids,
-                    key_password.as_deref(),
+            secret_subkeys.push(SignedSecretSubKey::new(key, vec![sig]));
+        }
+
+        if let Some(key_password) = key_password {
+            primary
+                .set_password_with_s2k(
+                    || key_password.to_string(),
+                    self.s2k.params(&mut *rng, version)?,
                 )
-                .expect("FIXME");
+                .map_err(error::rpgp)?;
+        }
 
-                return Ok(Keys {
-                    keys: vec![tsk],
-                    source_name: None,
-                });
-            }
+        let details = SignedKeyDetails::new(vec![], direct_signatures, users, vec![]);
 
-            _ => return Err(sop::errors::Error::UnsupportedProfile),
+        Ok(Tsk::Tsk(SignedSecretKey::new(
+            primary,
+            details,
+            vec![],
+            secret_subkeys,
+        )))
+    }
+
+    /// Generate the key material of a subkey, as described by `spec`
+    fn subkey(
+        &self,
+        spec: &SubkeySpec,
+        algorithms: &Algorithms,
+        created: &DateTime<Utc>,
+        rng: &mut dyn CryptoRngCore,
+    ) -> sop::Result<packet::SecretSubkey> {
+        let key_type = match (&spec.algorithm, spec.encrypts()) {
+            (Some(key_type), _) => key_type,
+            (None, true) => &algorithms.encryption,
+            (None, false) => &algorithms.signing,
         };
 
-        let tsk = Tsk::generate_v4(
-            key_type_pri,
-            if self.signing_only {
-                None
-            } else {
-                Some(key_type_enc)
-            },
-            primary_user_id,
-            other_user_ids,
-            key_password.as_deref(),
+        // Encryption needs an encryption algorithm, all other capabilities a signing algorithm
+        let usable = match key_type {
+            KeyType::Rsa(_) => true,
+            KeyType::ECDH(_) | KeyType::X25519 | KeyType::X448 => !spec.signs(),
+            _ => !spec.encrypts(),
+        };
+        if !usable {
+            log::warn!(
+                "Algorithm {key_type:?} can't be used for key flags {:?}",
+                spec.flags
+            );
+            return Err(sop::errors::Error::UnsupportedAsymmetricAlgo);
+        }
+
+        let (public_params, secret_params) = key_type.generate(rng).map_err(error::rpgp)?;
+        let public = packet::PublicSubkey::new(
+            Default::default(),
+            algorithms.version,
+            key_type.to_alg(),
+            *created,
+            None,
+            public_params,
         )
-        .map_err(std::io::Error::other)?;
+        .map_err(error::rpgp)?;
 
-        Ok(Keys {
-            keys: vec![tsk],
-            source_name: None,
-        })
+        Ok(packet::SecretSubkey::new(public, secret_params))
+    }
+
+    /// Make the binding signature for a subkey `key`, as described by `spec`.
+    ///
+    /// The binding of a signing subkey embeds a back signature by the subkey.
+    fn subkey_binding(
+        &self,
+        spec: &SubkeySpec,
+        primary: &packet::SecretKey,
+        key: &packet::SecretSubkey,
+        created: &DateTime<Utc>,
+        rng: &mut dyn CryptoRngCore,
+    ) -> sop::Result<Signature> {
+        let expiration = spec
+            .expiration
+            .or(self.subkey_expiration)
+            .map(expiration_time)
+            .transpose()?;
+
+        let mut config = crate::util::signature_config_with_rng(
+            &mut *rng,
+            primary,
+            SignatureType::SubkeyBinding,
+            primary.hash_alg(),
+            created,
+        )?;
+        config
+            .hashed_subpackets
+            .push(Subpacket::regular(SubpacketData::KeyFlags(
+                spec.flags.into(),
+            )));
+        if let Some(expiration) = expiration {
+            config
+                .hashed_subpackets
+                .push(Subpacket::regular(SubpacketData::KeyExpirationTime(
+                    expiration,
+                )));
+        }
+        if spec.flags.sign() {
+            let backsig = back_signature(primary, key, created, rng)?;
+            config
+                .hashed_subpackets
+                .push(Subpacket::regular(SubpacketData::EmbeddedSignature(
+                    Box::new(backsig),
+                )));
+        }
+
+        config
+            .sign_key_binding(primary, String::new, key)
+            .map_err(error::rpgp)
+    }
+}
+
+/// Make a primary key binding signature ("back signature") by the signing subkey `key`, which
+/// shows that the subkey belongs to `primary`.
+///
+/// rPGP has no function to make this signature, so we calculate it here. Unlike in a subkey
+/// binding signature, the primary key is hashed first, while the subkey is the signer.
+fn back_signature(
+    primary: &packet::SecretKey,
+    key: &packet::SecretSubkey,
+    created: &DateTime<Utc>,
+    rng: &mut dyn CryptoRngCore,
+) -> sop::Result<Signature> {
+    let config = crate::util::signature_config_with_rng(
+        rng,
+        key,
+        SignatureType::KeyBinding,
+        key.hash_alg(),
+        created,
+    )?;
+
+    let mut hasher = config.hash_alg.new_hasher().map_err(error::rpgp)?;
+    if let SignatureVersionSpecific::V6 { salt } = &config.version_specific {
+        hasher.update(salt);
+    }
+
+    let mut keys = vec![];
+    primary
+        .serialize_for_hashing(&mut keys)
+        .map_err(error::rpgp)?;
+    key.serialize_for_hashing(&mut keys).map_err(error::rpgp)?;
+    hasher.update(&keys);
+
+    let len = config
+        .hash_signature_data(&mut hasher)
+        .map_err(error::rpgp)?;
+    hasher.update(&config.trailer(len).map_err(error::rpgp)?);
+
+    let hash = hasher.finish();
+    let signature = key
+        .create_signature(String::new, config.hash_alg, &hash)
+        .map_err(error::rpgp)?;
+
+    Ok(Signature::from_config(
+        config,
+        [hash[0], hash[1]],
+        signature,
+    ))
+}
+
+/// Convert a validity period into the value of a Key Expiration Time subpacket.
+///
+/// The subpacket holds a number of seconds, where 0 signals that the key doesn't expire.
+fn expiration_time(validity: Duration) -> sop::Result<chrono::Duration> {
+    match u32::try_from(validity.as_secs()) {
+        Ok(secs) if secs > 0 => Ok(chrono::Duration::seconds(secs.into())),
+        _ => Err(sop::errors::Error::UnsupportedOption),
+    }
+}
+
+#[test]
+fn test_signing_only() {
+    use rpgpie::key::checked::CheckedCertificate;
+    use rpgpie::key::Certificate;
+    use sop::ops::GenerateKey as _;
+
+    // RSA key generation is too slow for debug builds, so we skip those profiles
+    for profile in [
+        PROFILE_EDDSA,
+        PROFILE_NISTP256,
+        PROFILE_NISTP384,
+        PROFILE_NISTP521,
+        PROFILE_RFC9580,
+        PROFILE_RFC9580_NISTP,
+        PROFILE_RFC9580_CV448,
+    ] {
+        for signing_only in [false, true] {
+            let mut generate = Box::new(GenerateKey::new())
+                .profile(profile)
+                .unwrap()
+                .userid("<alice@example.org>");
+            if signing_only {
+                generate = generate.signing_only();
+            }
+            let keys = generate.generate().unwrap();
+
+            let tsk = &keys.keys[0];
+            let now = Utc::now();
+
+            assert_eq!(
+                tsk.decryption_capable_component_keys().count(),
+                usize::from(!signing_only),
+                "{profile}"
+            );
+
+            let cert = Certificate::from(tsk);
+            let checked = CheckedCertificate::from(&cert);
+            assert_eq!(
+                checked.valid_encryption_capable_component_keys().len(),
+                usize::from(!signing_only),
+                "{profile}"
+            );
+            assert_eq!(
+                checked.valid_signing_capable_component_keys_at(&now).len(),
+                1,
+                "{profile}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_expiration() {
+    use pgp::types::PublicKeyTrait;
+    use rpgpie::key::checked::CheckedCertificate;
+    use rpgpie::key::Certificate;
+    use rpgpie::sig::stack::SigStack;
+    use sop::ops::GenerateKey as _;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    for profile in [PROFILE_EDDSA, PROFILE_RFC9580] {
+        let keys = Box::new(GenerateKey::new())
+            .key_expiration(Duration::from_secs(2 * DAY))
+            .subkey_expiration(Duration::from_secs(DAY))
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap();
+
+        let Tsk::Tsk(ssk) = &keys.keys[0] else {
+            panic!("expected a software key");
+        };
+        let created = *ssk.primary_key.created_at();
+        let at = |days: i64| created + chrono::Duration::hours(days * 24);
+
+        let cert = Certificate::from(&keys.keys[0]);
+        let checked = CheckedCertificate::from(&cert);
+
+        // The primary key, and with it the whole key, expires after two days
+        for (days, valid) in [(0, true), (1, true), (3, false)] {
+            assert_eq!(
+                checked.primary_valid_at(&at(days)).unwrap(),
+                valid,
+                "{profile}, day {days}"
+            );
+            assert_eq!(
+                checked
+                    .valid_signing_capable_component_keys_at(&at(days))
+                    .len(),
+                usize::from(valid),
+                "{profile}, day {days}"
+            );
+        }
+        assert_eq!(checked.valid_encryption_capable_component_keys().len(), 1);
+
+        // The encryption subkey expires after one day
+        let subkey = &ssk.secret_subkeys[0];
+        for (days, valid) in [(0, true), (2, false)] {
+            let stack = SigStack::from_iter(subkey.signatures.iter());
+            assert_eq!(
+                stack.has_valid_binding_at(&at(days), subkey.key.created_at()),
+                valid,
+                "{profile}, day {days}"
+            );
+        }
+    }
+
+    // Keys without expiration time stay valid
+    let keys = Box::new(GenerateKey::new())
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+    let cert = Certificate::from(&keys.keys[0]);
+    let checked = CheckedCertificate::from(&cert);
+    let later = *checked.primary_creation_time() + chrono::Duration::days(100 * 365);
+    assert!(checked.primary_valid_at(&later).unwrap());
+
+    // A Key Expiration Time of zero would mean that the key doesn't expire
+    assert!(Box::new(GenerateKey::new())
+        .key_expiration(Duration::ZERO)
+        .userid("<alice@example.org>")
+        .generate()
+        .is_err());
+}
+
+#[test]
+fn test_layout() {
+    use pgp::types::PublicKeyTrait;
+    use rpgpie::key::checked::CheckedCertificate;
+    use rpgpie::key::Certificate;
+    use rpgpie::sig::stack::SigStack;
+    use sop::ops::GenerateKey as _;
+
+    let generate = |profile: &str, layout: KeyLayout, signing_only: bool| {
+        let mut generate = Box::new(GenerateKey::new())
+            .layout(layout)
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>");
+        if signing_only {
+            generate = generate.signing_only();
+        }
+        let keys = generate.generate().unwrap();
+        keys.keys.into_iter().next().unwrap()
+    };
+
+    for profile in [PROFILE_EDDSA, PROFILE_RFC9580] {
+        // Primary key in the signing slot
+        let tsk = generate(profile, KeyLayout::card(), false);
+        let now = Utc::now();
+        let cert = Certificate::from(&tsk);
+        let checked = CheckedCertificate::from(&cert);
+        let signers = checked.valid_signing_capable_component_keys_at(&now);
+        assert_eq!(signers.len(), 1, "{profile}");
+        assert_eq!(
+            signers[0].as_componentkey().fingerprint(),
+            cert.fingerprint(),
+            "{profile}"
+        );
+        assert_eq!(checked.valid_encryption_capable_component_keys().len(), 1);
+        assert_eq!(
+            checked
+                .valid_authentication_capable_component_keys(&now)
+                .len(),
+            1,
+            "{profile}"
+        );
+
+        // Certification-only primary key, with subkeys for all slots
+        for signing_only in [false, true] {
+            let tsk = generate(profile, KeyLayout::card_subkeys(), signing_only);
+            let now = Utc::now();
+            let cert = Certificate::from(&tsk);
+            let checked = CheckedCertificate::from(&cert);
+            let signers = checked.valid_signing_capable_component_keys_at(&now);
+            assert_eq!(signers.len(), 1, "{profile}");
+            assert_ne!(
+                signers[0].as_componentkey().fingerprint(),
+                cert.fingerprint(),
+                "{profile}"
+            );
+            assert_eq!(
+                checked.valid_encryption_capable_component_keys().len(),
+                usize::from(!signing_only),
+                "{profile}"
+            );
+            assert_eq!(
+                checked
+                    .valid_authentication_capable_component_keys(&now)
+                    .len(),
+                1,
+                "{profile}"
+            );
+
+            // The signing subkey is bound with a valid back signature
+            let Tsk::Tsk(ssk) = &tsk else {
+                panic!("expected a software key");
+            };
+            let signing = &ssk.secret_subkeys[0];
+            let backsig = signing.signatures[0].embedded_signature().unwrap();
+            backsig
+                .verify_backwards_key_binding(&signing.key.public_key(), &ssk.primary_key)
+                .unwrap();
+        }
+
+        // Custom layout, with a short-lived additional encryption subkey
+        let layout = KeyLayout::new(true)
+            .subkey(SubkeySpec::encryption())
+            .subkey(SubkeySpec::encryption().expiration(Duration::from_secs(60 * 60)));
+        let tsk = generate(profile, layout, false);
+        let Tsk::Tsk(ssk) = &tsk else {
+            panic!("expected a software key");
+        };
+        assert_eq!(ssk.secret_subkeys.len(), 2);
+        let later = now + chrono::Duration::days(1);
+        for (subkey, valid) in ssk.secret_subkeys.iter().zip([true, false]) {
+            let stack = SigStack::from_iter(subkey.signatures.iter());
+            assert_eq!(
+                stack.has_valid_binding_at(&later, subkey.key.created_at()),
+                valid,
+                "{profile}"
+            );
+        }
+
+        // Subkeys need an algorithm that fits their capabilities
+        let layout = KeyLayout::new(true).subkey(SubkeySpec::signing().algorithm(KeyType::X25519));
+        assert!(matches!(
+            Box::new(GenerateKey::new())
+                .layout(layout)
+                .profile(profile)
+                .unwrap()
+                .userid("<alice@example.org>")
+                .generate(),
+            Err(sop::errors::Error::UnsupportedAsymmetricAlgo)
+        ));
+    }
+}
+
+#[test]
+fn test_deterministic() {
+    use pgp::types::PublicKeyTrait;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use sop::ops::GenerateKey as _;
+    use sop::Save;
+
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let generate = |profile: &str, seed: u64, password: bool| {
+        let mut generate = Box::new(GenerateKey::new())
+            .layout(KeyLayout::card_subkeys())
+            .deterministic(created, StdRng::seed_from_u64(seed))
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .userid("<alice@example.com>");
+        if password {
+            generate = generate
+                .with_key_password(sop::Password::new_unchecked(b"password".to_vec()))
+                .unwrap();
+        }
+        let keys = generate.generate().unwrap();
+
+        let Tsk::Tsk(ssk) = &keys.keys[0] else {
+            panic!("expected a software key");
+        };
+        assert_eq!(SystemTime::from(*ssk.primary_key.created_at()), created);
+        for subkey in &ssk.secret_subkeys {
+            assert_eq!(SystemTime::from(*subkey.key.created_at()), created);
+        }
+
+        let mut armored = vec![];
+        keys.to_writer(true, &mut armored).unwrap();
+        armored
+    };
+
+    // Password protection of version 6 keys uses Argon2, which is too slow for debug builds
+    for (profile, password) in [
+        (PROFILE_EDDSA, false),
+        (PROFILE_EDDSA, true),
+        (PROFILE_NISTP256, false),
+        (PROFILE_RFC9580, false),
+        (PROFILE_RFC9580_NISTP, false),
+    ] {
+        let key = generate(profile, 42, password);
+        assert_eq!(key, generate(profile, 42, password), "{profile}");
+        assert_ne!(key, generate(profile, 43, password), "{profile}");
     }
 }
@@ -2,16 +2,22 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use std::io;
+use std::ops::DerefMut;
+use std::sync::Mutex;
 
+use pgp::armor::BlockType;
 use pgp::cleartext::CleartextSignedMessage;
-use pgp::packet::{LiteralData, Packet};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{LiteralData, Packet, SignatureType};
 use pgp::ser::Serialize;
-use pgp::types::PublicKeyTrait;
 use pgp::{ArmorOptions, Deserializable, Message};
-use rpgpie::key::DataSigner;
+use rpgpie::key::component::ComponentKeySec;
+use rpgpie::key::{Certificate, DataSigner};
 
 use crate::cmd::sign::Sign;
-use crate::{Keys, RPGSOP};
+use crate::stream::sign::{self, Signer};
+use crate::stream::text::Utf8Reader;
+use crate::{error, Keys, RPGSOP};
 
 pub(crate) struct InlineSign {
     armor: bool,
@@ -87,10 +93,7 @@ struct InlineSignReady<'a> {
 }
 
 impl<'a> sop::ops::Ready for InlineSignReady<'a> {
-    fn to_writer(self: Box<Self>, mut sink: &mut (dyn io::Write + Send + Sync)) -> sop::Result<()> {
-        let mut data = vec![];
-        self.data.read_to_end(&mut data)?;
-
+    fn to_writer(self: Box<Self>, sink: &mut (dyn io::Write + Send + Sync)) -> sop::Result<()> {
         let hash_algo = self
             .inline_sign
             .sign
@@ -99,68 +102,130 @@ impl<'a> sop::ops::Ready for InlineSignReady<'a> {
             .cloned()
             .unwrap_or_default();
 
-        assert!(!self.inline_sign.sign.signers.is_empty()); // FIXME
-
-        // Passwords to try
-        let pws: Vec<&[u8]> = if self.inline_sign.sign.with_key_password.is_empty() {
-            vec![&[]]
-        } else {
-            self.inline_sign
-                .sign
-                .with_key_password
-                .iter()
-                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
-                .collect()
-        };
+        if self.inline_sign.sign.signers.is_empty() {
+            return Err(sop::errors::Error::MissingArg);
+        }
 
         let mut signers: Vec<DataSigner> = vec![];
-        for tsk in self.inline_sign.sign.signers {
+        for tsk in &self.inline_sign.sign.signers {
             let mut s: Vec<DataSigner> = tsk.signing_capable_component_keys().collect();
 
             if s.is_empty() {
-                panic!(
-                    "no signing capable component key found for signer {:02x?}",
-                    tsk.key().fingerprint()
+                log::warn!(
+                    "No signing capable component key found for signer {:02x?}",
+                    Certificate::from(tsk).fingerprint()
                 );
+                return Err(sop::errors::Error::KeyCannotSign);
             }
 
             signers.append(&mut s);
         }
 
+        if signers.iter().any(|ds| matches!(ds, DataSigner::Card(_))) {
+            // Signing with card-backed keys is only possible via rpgpie, which processes the
+            // message in memory
+            self.sign_buffered(signers, hash_algo, sink)
+        } else {
+            let signers = signers
+                .into_iter()
+                .filter_map(|ds| match ds {
+                    DataSigner::Software(key) => Some(key),
+                    DataSigner::Card(_) => None,
+                })
+                .collect();
+
+            self.sign_streaming(signers, hash_algo, sink)
+        }
+    }
+}
+
+impl InlineSignReady<'_> {
+    /// Sign the data as it is read, with constant memory use
+    fn sign_streaming(
+        self,
+        signers: Vec<ComponentKeySec>,
+        hash_algo: HashAlgorithm,
+        mut sink: &mut (dyn io::Write + Send + Sync),
+    ) -> sop::Result<()> {
+        let typ = match self.inline_sign.mode {
+            sop::ops::InlineSignAs::Binary => SignatureType::Binary,
+            sop::ops::InlineSignAs::Text | sop::ops::InlineSignAs::ClearSigned => {
+                SignatureType::Text
+            }
+        };
+
+        // Unlock all signers before producing any output
+        let pws = self.inline_sign.sign.passwords();
+        let signers = signers
+            .into_iter()
+            .map(|key| Signer::new(key, &pws, typ, hash_algo))
+            .collect::<sop::Result<Vec<_>>>()?;
+
+        let mut data: Box<dyn io::Read + Send + '_> = match typ {
+            SignatureType::Text => Box::new(Utf8Reader::new(self.data)),
+            _ => Box::new(self.data),
+        };
+
+        if let sop::ops::InlineSignAs::ClearSigned = self.inline_sign.mode {
+            return sign::write_cleartext_signed(&mut data, signers, &mut sink)
+                .map_err(error::rpgp);
+        }
+
+        let message = StreamedMessage {
+            text: typ == SignatureType::Text,
+            signers: Mutex::new(signers),
+            data: Mutex::new(data),
+        };
+
+        match self.inline_sign.armor {
+            true => pgp::armor::write(&message, BlockType::Message, &mut sink, None, true),
+            false => message.to_writer(&mut sink),
+        }
+        .map_err(error::rpgp)
+    }
+
+    /// Sign the data with rpgpie, which processes the message in memory
+    fn sign_buffered(
+        self,
+        signers: Vec<DataSigner>,
+        hash_algo: HashAlgorithm,
+        mut sink: &mut (dyn io::Write + Send + Sync),
+    ) -> sop::Result<()> {
+        let mut data = vec![];
+        self.data.read_to_end(&mut data)?;
+
+        // Passwords to try
+        let pws = self.inline_sign.sign.passwords();
+
         let lit = match &self.inline_sign.mode {
             sop::ops::InlineSignAs::Binary => LiteralData::from_bytes("".into(), &data),
             sop::ops::InlineSignAs::Text => {
-                LiteralData::from_str("", &String::from_utf8(data).expect("FIXME"))
+                let text = String::from_utf8(data).map_err(|_| sop::errors::Error::ExpectedText)?;
+                LiteralData::from_str("", &text)
             }
             sop::ops::InlineSignAs::ClearSigned => {
-                let body = String::from_utf8(data).expect("foo");
-
-                let s: Vec<_> = signers.into_iter().map(|s| (s, pws.as_slice())).collect();
-
-                // FIXME: This all is awkward and inefficient.
-                // We shouldn't use `ds.sign_csf`, and instead use a separate pure data signing function
+                let body = String::from_utf8(data).map_err(|_| sop::errors::Error::ExpectedText)?;
 
                 // We don't use the `_text` input to the closure, but instead let `ds.sign_csf`
                 // normalize `body` for each signature.
 
-                let signers = |_text: &[u8]| {
-                    let mut sigs = vec![];
-
-                    for x in s {
-                        let (ds, pw) = &x;
+                let mut sigs = vec![];
 
-                        let csf = ds.sign_csf(&body, pw).expect("FIXME");
-                        let s = csf.signatures();
-                        sigs.push(s[0].signature.clone());
-                    }
+                for ds in signers {
+                    let Ok(csf) = ds.sign_csf(&body, &pws) else {
+                        // FIXME: probably the password(s) were wrong, but this is a bit of a guess
+                        return Err(sop::errors::Error::KeyIsProtected);
+                    };
 
-                    Ok(sigs)
-                };
+                    let s = csf.signatures();
+                    sigs.push(s[0].signature.clone());
+                }
 
-                let csf = CleartextSignedMessage::new_many(&body, signers).expect("FIXME");
+                let csf = CleartextSignedMessage::new_many(&body, |_text: &[u8]| Ok(sigs))
+                    .map_err(error::rpgp)?;
 
                 csf.to_armored_writer(&mut sink, ArmorOptions::default())
-                    .expect("FIXME");
+                    .map_err(error::rpgp)?;
 
                 return Ok(());
             }
@@ -202,22 +267,39 @@ impl<'a> sop::ops::Ready for InlineSignReady<'a> {
                     packets.push(Packet::from(signature));
                 }
             } else {
-                panic!("foo");
+                // FIXME: probably the password(s) were wrong, but this is a bit of a guess
+                return Err(sop::errors::Error::KeyIsProtected);
             }
         }
 
         let signed = Message::from_packets(packets.into_iter().map(Ok).peekable())
             .next()
-            .expect("should be a message")
-            .expect("FIXME");
+            .ok_or(sop::errors::Error::UnspecifiedFailure)?
+            .map_err(error::rpgp)?;
 
         match self.inline_sign.armor {
-            true => signed
-                .to_armored_writer(&mut sink, ArmorOptions::default())
-                .expect("FIXME"),
-            false => signed.to_writer(&mut sink).expect("FIXME"),
+            true => signed.to_armored_writer(&mut sink, ArmorOptions::default()),
+            false => signed.to_writer(&mut sink),
         }
+        .map_err(error::rpgp)?;
 
         Ok(())
     }
 }
+
+/// An inline signed message, which is produced while it is being written
+struct StreamedMessage<'a> {
+    text: bool,
+    signers: Mutex<Vec<Signer>>,
+    data: Mutex<Box<dyn io::Read + Send + 'a>>,
+}
+
+impl Serialize for StreamedMessage<'_> {
+    fn to_writer<W: io::Write>(&self, w: &mut W) -> pgp::errors::Result<()> {
+        // The data and the signers can only be consumed once
+        let signers = std::mem::take(self.signers.lock().unwrap().deref_mut());
+        let mut data = self.data.lock().unwrap();
+
+        sign::write_signed_literal(data.deref_mut(), signers, self.text, w)
+    }
+}
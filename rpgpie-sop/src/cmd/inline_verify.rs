@@ -12,12 +12,13 @@ use rpgpie::key::component::ComponentKeyPub;
 use rpgpie::key::Certificate;
 use rpgpie::msg::MessageResult;
 
-use crate::{util, Certs, RPGSOP};
+use crate::stream::armor;
+use crate::{error, util, Certs, RPGSOP};
 
 #[derive(Default)]
 pub(crate) struct InlineVerify {
-    _not_before: Option<std::time::SystemTime>,
-    _not_after: Option<std::time::SystemTime>,
+    not_before: Option<std::time::SystemTime>,
+    not_after: Option<std::time::SystemTime>,
     certs: Vec<Certificate>,
 }
 
@@ -29,23 +30,19 @@ impl InlineVerify {
 
 impl<'a> sop::ops::InlineVerify<'a, RPGSOP, Certs> for InlineVerify {
     fn not_before(
-        self: Box<Self>,
-        _t: std::time::SystemTime,
+        mut self: Box<Self>,
+        t: std::time::SystemTime,
     ) -> Box<dyn sop::ops::InlineVerify<'a, RPGSOP, Certs> + 'a> {
-        todo!()
-
-        // self.not_before = Some(t);
-        // self
+        self.not_before = Some(t);
+        self
     }
 
     fn not_after(
-        self: Box<Self>,
-        _t: std::time::SystemTime,
+        mut self: Box<Self>,
+        t: std::time::SystemTime,
     ) -> Box<dyn sop::ops::InlineVerify<'a, RPGSOP, Certs> + 'a> {
-        todo!()
-
-        // self.not_after = Some(t);
-        // self
+        self.not_after = Some(t);
+        self
     }
 
     fn certs(
@@ -82,16 +79,31 @@ struct InlineVerifyReady<'a> {
 fn verify_msg(
     msg: Message,
     sink: &mut (dyn io::Write + Send + Sync),
-    certs: &[Certificate],
+    inline_verify: &InlineVerify,
 ) -> sop::Result<Vec<sop::ops::Verification>> {
-    let mr = rpgpie::msg::unpack(msg, &[], vec![], vec![], certs).expect("FIXME");
+    let mr =
+        rpgpie::msg::unpack(msg, &[], vec![], vec![], &inline_verify.certs).map_err(error::load)?;
 
-    if !mr.validated.is_empty() {
-        sink.write_all(mr.cleartext.data()).expect("FIXME");
+    inline_verify.output(&mr, sink)
+}
 
-        Ok(util::result_to_verifications(&mr))
-    } else {
-        Err(sop::errors::Error::NoSignature)
+impl InlineVerify {
+    /// Write the cleartext of `mr` to `sink`, if it has valid signatures within our time window
+    fn output(
+        &self,
+        mr: &MessageResult,
+        sink: &mut (dyn io::Write + Send + Sync),
+    ) -> sop::Result<Vec<sop::ops::Verification>> {
+        let verifications =
+            util::verifications_within(&mr.validated, self.not_before, self.not_after);
+
+        if !verifications.is_empty() {
+            sink.write_all(mr.cleartext.data())?;
+
+            Ok(verifications)
+        } else {
+            Err(sop::errors::Error::NoSignature)
+        }
     }
 }
 
@@ -100,20 +112,20 @@ impl sop::ops::Ready<Vec<sop::ops::Verification>> for InlineVerifyReady<'_> {
         self: Box<Self>,
         sink: &mut (dyn io::Write + Send + Sync),
     ) -> sop::Result<Vec<sop::ops::Verification>> {
-        let mut reader = io::BufReader::new(self.data);
+        let mut reader = armor::buffered(self.data)?;
 
         let buf = reader.fill_buf()?;
         if buf.is_empty() {
-            panic!("empty input");
+            return Err(sop::errors::Error::BadData);
         }
 
         if buf[0] & 0x80 != 0 {
             // the input seems to be binary data - presumably an unarmored signed message
-            let msg = Message::from_bytes(reader).expect("FIXME");
+            let msg = Message::from_bytes(reader).map_err(error::rpgp)?;
 
-            verify_msg(msg, sink, &self.inline_verify.certs)
+            verify_msg(msg, sink, &self.inline_verify)
         } else {
-            let (pgp, _) = pgp::Any::from_armor(reader).expect("foo");
+            let (pgp, _) = pgp::Any::from_armor(reader).map_err(error::rpgp)?;
 
             match pgp {
                 Any::Cleartext(csf) => {
@@ -143,23 +155,78 @@ impl sop::ops::Ready<Vec<sop::ops::Verification>> for InlineVerifyReady<'_> {
                         })
                         .collect();
 
-                    if !validated.is_empty() {
-                        let text = csf.signed_text();
-                        sink.write_all(text.as_bytes()).expect("FIXME");
-
-                        let mr = MessageResult {
-                            session_key: None,
-                            cleartext: LiteralData::from_str("", &text),
-                            validated,
-                        };
-                        Ok(util::result_to_verifications(&mr))
-                    } else {
-                        Err(sop::errors::Error::NoSignature)
-                    }
+                    let mr = MessageResult {
+                        session_key: None,
+                        cleartext: LiteralData::from_str("", &csf.signed_text()),
+                        validated,
+                    };
+
+                    self.inline_verify.output(&mr, sink)
                 }
-                Any::Message(msg) => verify_msg(msg, sink, &self.inline_verify.certs),
-                _ => panic!("unexpected data type"),
+                Any::Message(msg) => verify_msg(msg, sink, &self.inline_verify),
+                _ => Err(sop::errors::Error::BadData),
             }
         }
     }
 }
+
+#[test]
+fn test_inline_verify_time_window() {
+    use std::time::{Duration, SystemTime};
+
+    use sop::ops::{GenerateKey as _, InlineSign as _, InlineSignAs, InlineVerify as _};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    let keys = Box::new(crate::GenerateKey::new())
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+    let certs = Certs {
+        certs: keys.keys.iter().map(Certificate::from).collect(),
+        source_name: None,
+    };
+
+    for mode in [InlineSignAs::Binary, InlineSignAs::ClearSigned] {
+        let signed = Box::new(crate::cmd::inline_sign::InlineSign::new())
+            .mode(mode)
+            .keys(&keys)
+            .unwrap()
+            .data(&mut &b"hello world"[..])
+            .unwrap()
+            .to_vec()
+            .unwrap()
+            .1;
+        let now = SystemTime::now();
+
+        let verify = |not_before: SystemTime, not_after: SystemTime| {
+            let mut plaintext = vec![];
+            let result = Box::new(InlineVerify::new())
+                .not_before(not_before)
+                .not_after(not_after)
+                .certs(&certs)
+                .unwrap()
+                .message(&mut &signed[..])
+                .unwrap()
+                .to_writer(&mut plaintext);
+
+            result.map(|verifications| (verifications, plaintext))
+        };
+
+        let (verifications, plaintext) = verify(now - HOUR, now + HOUR).unwrap();
+        assert_eq!(verifications.len(), 1, "{mode:?}");
+        assert_eq!(plaintext, b"hello world", "{mode:?}");
+
+        // The signature was made after, or before the window
+        for (not_before, not_after) in [(now - 2 * HOUR, now - HOUR), (now + HOUR, now + 2 * HOUR)]
+        {
+            assert!(
+                matches!(
+                    verify(not_before, not_after),
+                    Err(sop::errors::Error::NoSignature)
+                ),
+                "{mode:?}"
+            );
+        }
+    }
+}
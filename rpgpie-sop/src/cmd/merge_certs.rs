@@ -0,0 +1,238 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use pgp::types::PublicKeyTrait;
+use pgp::{Signature, SignedKeyDetails, SignedPublicSubKey};
+use rpgpie::key::Certificate;
+
+use crate::{Certs, RPGSOP};
+
+pub(crate) struct MergeCerts {
+    updates: Vec<Certificate>,
+}
+
+impl MergeCerts {
+    pub(crate) fn new() -> Self {
+        Self { updates: vec![] }
+    }
+}
+
+impl<'a> sop::ops::MergeCerts<'a, RPGSOP, Certs> for MergeCerts {
+    fn merge_updates(
+        mut self: Box<Self>,
+        updates: &Certs,
+    ) -> sop::Result<Box<dyn sop::ops::MergeCerts<'a, RPGSOP, Certs> + 'a>> {
+        self.updates.extend(updates.certs.iter().cloned());
+        Ok(self)
+    }
+
+    fn merge(self: Box<Self>, certs: &Certs) -> sop::Result<Certs> {
+        let mut results = vec![];
+
+        for cert in &certs.certs {
+            let fp = cert.fingerprint();
+
+            let mut spk = crate::util::to_signed_public_key(cert)?;
+            for update in self.updates.iter().filter(|u| u.fingerprint() == fp) {
+                let update = crate::util::to_signed_public_key(update)?;
+
+                merge_details(&mut spk.details, &update.details);
+                merge_subkeys(&mut spk.public_subkeys, &update.public_subkeys);
+            }
+
+            results.push(spk.into());
+        }
+
+        Ok(Certs {
+            certs: results,
+            source_name: None,
+        })
+    }
+}
+
+/// Add the signatures in `update` to `base`, skipping duplicates
+pub(crate) fn merge_signatures(base: &mut Vec<Signature>, update: &[Signature]) {
+    for sig in update {
+        if !base.contains(sig) {
+            base.push(sig.clone());
+        }
+    }
+}
+
+/// Merge the primary key signatures, user ids and user attributes of `update` into `base`
+pub(crate) fn merge_details(base: &mut SignedKeyDetails, update: &SignedKeyDetails) {
+    merge_signatures(
+        &mut base.revocation_signatures,
+        &update.revocation_signatures,
+    );
+    merge_signatures(&mut base.direct_signatures, &update.direct_signatures);
+
+    for user in &update.users {
+        match base.users.iter_mut().find(|u| u.id == user.id) {
+            Some(u) => merge_signatures(&mut u.signatures, &user.signatures),
+            None => base.users.push(user.clone()),
+        }
+    }
+
+    for attr in &update.user_attributes {
+        match base
+            .user_attributes
+            .iter_mut()
+            .find(|a| a.attr == attr.attr)
+        {
+            Some(a) => merge_signatures(&mut a.signatures, &attr.signatures),
+            None => base.user_attributes.push(attr.clone()),
+        }
+    }
+}
+
+/// Merge the subkeys in `update` into `base`, matching them by fingerprint
+pub(crate) fn merge_subkeys(base: &mut Vec<SignedPublicSubKey>, update: &[SignedPublicSubKey]) {
+    for sub in update {
+        let fp = sub.key.fingerprint();
+
+        match base.iter_mut().find(|s| s.key.fingerprint() == fp) {
+            Some(s) => merge_signatures(&mut s.signatures, &sub.signatures),
+            None => base.push(sub.clone()),
+        }
+    }
+}
+
+#[test]
+fn test_merge_revocation() {
+    use pgp::KeyType;
+    use rpgpie::key::Tsk;
+    use sop::ops::{MergeCerts as _, RevokeKey as _};
+
+    use crate::Keys;
+
+    for tsk in [
+        Tsk::generate_v4(
+            KeyType::EdDSALegacy,
+            KeyType::ECDH(pgp::crypto::ecc_curve::ECCCurve::Curve25519),
+            Some("<alice@example.org>".to_string()),
+            vec![],
+            None,
+        )
+        .unwrap(),
+        Tsk::generate_v6(
+            KeyType::Ed25519,
+            KeyType::X25519,
+            Some("<bob@example.org>".to_string()),
+            vec![],
+            None,
+        )
+        .unwrap(),
+    ] {
+        let cert = Certificate::from(&tsk);
+        let keys = Keys {
+            keys: vec![tsk],
+            source_name: None,
+        };
+
+        let revoked = Box::new(crate::cmd::revoke_key::RevokeKey::new())
+            .keys(&keys)
+            .unwrap();
+
+        let certs = Certs {
+            certs: vec![cert],
+            source_name: None,
+        };
+
+        // Merging the same update twice must not duplicate the revocation
+        let merged = Box::new(MergeCerts::new())
+            .merge_updates(&revoked)
+            .unwrap()
+            .merge_updates(&revoked)
+            .unwrap()
+            .merge(&certs)
+            .unwrap();
+
+        assert_eq!(merged.certs.len(), 1);
+
+        let spk = crate::util::to_signed_public_key(&merged.certs[0]).unwrap();
+        assert_eq!(spk.details.revocation_signatures.len(), 1);
+        assert_eq!(spk.details.users.len(), 1);
+        assert_eq!(spk.details.users[0].signatures.len(), 1);
+        assert_eq!(spk.public_subkeys.len(), 1);
+    }
+}
+
+#[test]
+fn test_merge_new_subkey() {
+    use pgp::KeyType;
+    use rpgpie::key::Tsk;
+    use sop::ops::{MergeCerts as _, UpdateKey as _};
+
+    use crate::Keys;
+
+    for tsk in [
+        Tsk::generate_v4(
+            KeyType::EdDSALegacy,
+            None,
+            Some("<alice@example.org>".to_string()),
+            vec![],
+            None,
+        )
+        .unwrap(),
+        Tsk::generate_v6(
+            KeyType::Ed25519,
+            KeyType::X25519,
+            Some("<bob@example.org>".to_string()),
+            vec![],
+            None,
+        )
+        .unwrap(),
+    ] {
+        // Start out from a key without subkeys
+        let mut ssk = tsk.key().clone();
+        ssk.secret_subkeys.clear();
+        let tsk = Tsk::from(ssk);
+
+        let cert = Certificate::from(&tsk);
+        let other = Certificate::from(
+            &Tsk::generate_v4(
+                KeyType::EdDSALegacy,
+                None,
+                Some("<carol@example.org>".to_string()),
+                vec![],
+                None,
+            )
+            .unwrap(),
+        );
+
+        // update-key adds a new encryption subkey
+        let updated = Box::new(crate::cmd::update_key::UpdateKey::new())
+            .update(&Keys {
+                keys: vec![tsk],
+                source_name: None,
+            })
+            .unwrap();
+
+        let updates = Certs {
+            certs: updated.keys.iter().map(Certificate::from).collect(),
+            source_name: None,
+        };
+
+        let merged = Box::new(MergeCerts::new())
+            .merge_updates(&updates)
+            .unwrap()
+            .merge(&Certs {
+                certs: vec![cert.clone(), other.clone()],
+                source_name: None,
+            })
+            .unwrap();
+
+        assert_eq!(merged.certs.len(), 2);
+
+        let spk = crate::util::to_signed_public_key(&merged.certs[0]).unwrap();
+        assert_eq!(spk.fingerprint(), cert.fingerprint());
+        assert_eq!(spk.public_subkeys.len(), 1);
+
+        // Certificates without matching updates are left unchanged
+        assert_eq!(
+            Vec::<u8>::try_from(&merged.certs[1]).unwrap(),
+            Vec::<u8>::try_from(&other).unwrap()
+        );
+    }
+}
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 pub(crate) mod armor;
+pub(crate) mod certify_userid;
 pub(crate) mod dearmor;
 pub(crate) mod decrypt;
 pub(crate) mod detach;
@@ -10,8 +11,12 @@ pub(crate) mod extract_cert;
 pub(crate) mod generate;
 pub(crate) mod inline_sign;
 pub(crate) mod inline_verify;
+pub(crate) mod merge_certs;
 pub(crate) mod password;
+pub(crate) mod revoke_component;
 pub(crate) mod revoke_key;
 pub(crate) mod sign;
+pub(crate) mod update_key;
+pub(crate) mod validate_userid;
 pub(crate) mod verify;
 pub(crate) mod version;
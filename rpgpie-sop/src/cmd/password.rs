@@ -2,27 +2,144 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use pgp::crypto::aead::AeadAlgorithm;
+use pgp::crypto::hash::HashAlgorithm;
 use pgp::crypto::sym::SymmetricKeyAlgorithm;
-use pgp::types::{KeyVersion, PublicKeyTrait, S2kParams, SecretKeyTrait, StringToKey};
-use rand::{thread_rng, Rng};
+use pgp::packet;
+use pgp::types::{KeyVersion, S2kParams, SecretKeyTrait, SecretParams, StringToKey};
+use rand::{thread_rng, CryptoRng, Rng};
 use rpgpie::key::Tsk;
 use sop::plumbing::PasswordsAreHumanReadable;
 use sop::Password;
 
-use crate::{Keys, RPGSOP};
+use crate::{error, Keys, RPGSOP};
 
-pub(crate) struct ChangeKeyPassword {
-    pw_old: Option<Password>,
+/// Iteration count for iterated and salted S2K (the encoded value, for 16 MiB of hashed data)
+const ITERATED_SALTED_COUNT: u8 = 224;
+
+/// How secret key material is protected with a password (the String-to-Key mechanism).
+///
+/// Version 6 keys are protected with AEAD, with a key that is derived from the password with
+/// Argon2. The profiles differ in the Argon2 parameters, that is, in the memory and time needed
+/// to derive the key.
+///
+/// Version 4 keys are protected with CFB, with a key that is derived from the password with
+/// iterated and salted SHA-256, in all profiles.
+/// Optionally, they can be protected with AEAD instead (see [`S2kProfile::v4_aead`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct S2kProfile {
+    /// Argon2 parameters: number of passes, degree of parallelism, and memory size (as the
+    /// exponent of a number of KiB)
+    argon2: (u8, u8, u8),
+
+    v4_aead: bool,
+}
+
+impl S2kProfile {
+    /// Argon2 with 64 MiB of memory (the "SECOND RECOMMENDED option" of RFC 9106)
+    pub const INTERACTIVE: Self = Self::argon2(3, 4, 16);
+
+    /// Argon2 with 2 GiB of memory (the "FIRST RECOMMENDED option" of RFC 9106)
+    pub const SENSITIVE: Self = Self::argon2(1, 4, 21);
+
+    /// Argon2 with 8 MiB of memory, and more passes to make up for it.
+    ///
+    /// For systems that can't spare the memory for the other profiles.
+    pub const LOW_MEMORY: Self = Self::argon2(8, 4, 13);
+
+    const fn argon2(t: u8, p: u8, m_enc: u8) -> Self {
+        Self {
+            argon2: (t, p, m_enc),
+            v4_aead: false,
+        }
+    }
+
+    /// Look up a profile by its name: "interactive", "sensitive" or "low-memory"
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "interactive" => Some(Self::INTERACTIVE),
+            "sensitive" => Some(Self::SENSITIVE),
+            "low-memory" => Some(Self::LOW_MEMORY),
+            _ => None,
+        }
+    }
+
+    /// Protect version 4 keys with AEAD (with iterated and salted SHA-256), instead of CFB.
+    ///
+    /// RFC 9580 allows this, but many implementations that handle version 4 keys can't unlock
+    /// such keys.
+    pub fn v4_aead(mut self, v4_aead: bool) -> Self {
+        self.v4_aead = v4_aead;
+        self
+    }
+
+    /// S2K parameters for protecting a key of `version`, with fresh salt and IV from `rng`
+    pub(crate) fn params<R: CryptoRng + Rng>(
+        &self,
+        mut rng: R,
+        version: KeyVersion,
+    ) -> sop::Result<S2kParams> {
+        let sym_alg = SymmetricKeyAlgorithm::AES256;
+        let aead_mode = AeadAlgorithm::Ocb;
+
+        let s2k = match version {
+            KeyVersion::V4 => {
+                StringToKey::new_iterated(&mut rng, HashAlgorithm::SHA2_256, ITERATED_SALTED_COUNT)
+            }
+            KeyVersion::V6 => {
+                let (t, p, m_enc) = self.argon2;
+                StringToKey::new_argon2(&mut rng, t, p, m_enc)
+            }
+            _ => return Err(sop::errors::Error::UnsupportedAsymmetricAlgo),
+        };
+
+        Ok(match (version, self.v4_aead) {
+            (KeyVersion::V4, false) => {
+                let mut iv = vec![0u8; sym_alg.block_size()];
+                rng.fill(&mut iv[..]);
+
+                S2kParams::Cfb { sym_alg, s2k, iv }
+            }
+            _ => {
+                let mut nonce = vec![0u8; aead_mode.nonce_size()];
+                rng.fill(&mut nonce[..]);
+
+                S2kParams::Aead {
+                    sym_alg,
+                    aead_mode,
+                    s2k,
+                    nonce,
+                }
+            }
+        })
+    }
+}
+
+/// Changing the password of keys, as in `sop change-key-password`.
+///
+/// Beyond the SOP interface, this allows choosing how the keys are protected with the new
+/// password. This setting must be made before using the methods of
+/// [`sop::ops::ChangeKeyPassword`].
+pub struct ChangeKeyPassword {
+    pw_old: Vec<Password>,
     pw_new: Option<Password>,
+    s2k: S2kProfile,
 }
 
 impl ChangeKeyPassword {
     pub(crate) fn new() -> Self {
         Self {
-            pw_old: None,
+            pw_old: vec![],
             pw_new: None,
+            s2k: S2kProfile::SENSITIVE,
         }
     }
+
+    /// Protect the keys with the new password as described by `profile`, instead of
+    /// [`S2kProfile::SENSITIVE`]
+    pub fn s2k_profile(mut self: Box<Self>, profile: S2kProfile) -> Box<Self> {
+        self.s2k = profile;
+        self
+    }
 }
 
 impl<'a> sop::ops::ChangeKeyPassword<'a, RPGSOP, Keys> for ChangeKeyPassword {
@@ -38,101 +155,386 @@ impl<'a> sop::ops::ChangeKeyPassword<'a, RPGSOP, Keys> for ChangeKeyPassword {
         mut self: Box<Self>,
         password: Password,
     ) -> sop::Result<Box<dyn sop::ops::ChangeKeyPassword<'a, RPGSOP, Keys> + 'a>> {
-        self.pw_old = Some(password);
+        self.pw_old.push(password);
         Ok(self)
     }
 
     fn keys(self: Box<Self>, keys: &Keys) -> sop::Result<Keys> {
-        fn s2k(version: KeyVersion) -> S2kParams {
-            match version {
-                KeyVersion::V4 => {
-                    let mut rng = thread_rng();
+        // Passwords to try for unlocking the keys
+        let pws_old: Vec<String> = self
+            .pw_old
+            .iter()
+            .map(|pw| String::from_utf8_lossy(pw.normalized()).to_string())
+            .collect();
 
-                    let sym_alg = SymmetricKeyAlgorithm::AES256;
+        // The new password must be human-readable
+        let pw_new = self
+            .pw_new
+            .as_ref()
+            .map(|pw| std::str::from_utf8(pw.normalized()))
+            .transpose()
+            .map_err(|_| sop::errors::Error::PasswordNotHumanReadable)?;
 
-                    let mut iv = vec![0u8; sym_alg.block_size()];
-                    rng.fill(&mut iv[..]);
+        let mut res: Vec<Tsk> = vec![];
 
-                    S2kParams::Cfb {
-                        sym_alg,
-                        s2k: StringToKey::new_default(rng),
-                        iv,
-                    }
-                }
-                KeyVersion::V6 => {
-                    let mut rng = thread_rng();
-
-                    let sym_alg = SymmetricKeyAlgorithm::AES256;
-                    let aead_mode = AeadAlgorithm::Ocb;
-
-                    let mut nonce = vec![0u8; aead_mode.nonce_size()];
-                    rng.fill(&mut nonce[..]);
-
-                    let mut salt = [0u8; 16];
-                    rng.fill(&mut salt[..]);
-
-                    S2kParams::Aead {
-                        sym_alg,
-                        aead_mode,
-
-                        s2k: StringToKey::Argon2 {
-                            salt,
-                            t: 1,
-                            p: 4,
-                            m_enc: 21, // 2 GB
-                        },
-                        nonce,
-                    }
+        for key in &keys.keys {
+            let ssk = match key {
+                Tsk::Tsk(ssk) => ssk,
+                Tsk::Card(cert) => {
+                    // The secret key material is on a card, which we leave alone
+                    log::info!(
+                        "Not changing the password of card-backed key: {:02x?}",
+                        cert.fingerprint()
+                    );
+                    res.push(key.clone());
+                    continue;
                 }
-                _ => unimplemented!(),
+            };
+
+            let mut ssk = ssk.clone();
+
+            self.change(&mut ssk.primary_key, &pws_old, pw_new)?;
+            for sub in &mut ssk.secret_subkeys {
+                self.change(&mut sub.key, &pws_old, pw_new)?;
             }
+
+            res.push(ssk.into());
         }
 
-        let mut res: Vec<Tsk> = vec![];
+        Ok(Keys {
+            keys: res,
+            source_name: None,
+        })
+    }
+}
 
-        for key in &keys.keys {
-            let pw_old = self
-                .pw_old
-                .as_ref()
-                .map(|pw| String::from_utf8_lossy(pw.normalized()).to_string());
+impl ChangeKeyPassword {
+    /// Change the password of the component key `key`, from one of `pws_old` to `pw_new`
+    /// (or remove the password, if `pw_new` is `None`).
+    ///
+    /// Unprotected key material needs no old password, and stubs without key material are left
+    /// unchanged. Fails with `KeyIsProtected` if none of `pws_old` unlock the key material.
+    fn change(
+        &self,
+        key: &mut impl ComponentSecret,
+        pws_old: &[String],
+        pw_new: Option<&str>,
+    ) -> sop::Result<()> {
+        if is_stub(key.secret_params()) {
+            log::info!(
+                "Not changing the password of stub without secret key material: {:02x?}",
+                key.fingerprint()
+            );
+            return Ok(());
+        }
+
+        if matches!(key.secret_params(), SecretParams::Encrypted(_))
+            && !pws_old.iter().any(|pw| key.unprotect(pw).is_ok())
+        {
+            log::warn!("Couldn't unlock component key: {:02x?}", key.fingerprint());
+            return Err(sop::errors::Error::KeyIsProtected);
+        }
 
-            let pw_new = self
-                .pw_new
-                .as_ref()
-                .map(|pw| String::from_utf8_lossy(pw.normalized()).to_string());
+        if let Some(pw_new) = pw_new {
+            let s2k = self.s2k.params(thread_rng(), key.version())?;
+            key.protect(pw_new, s2k).map_err(error::rpgp)?;
+        }
 
-            let mut ssk = key.key().clone();
-            let pri = &mut ssk.primary_key;
+        Ok(())
+    }
+}
 
-            if let Some(pw_old) = &pw_old {
-                pri.remove_password(|| pw_old.clone()).expect("FIXME");
-            }
+/// Password handling for the secret key material of primary keys and subkeys
+trait ComponentSecret: SecretKeyTrait {
+    fn secret_params(&self) -> &SecretParams;
+
+    fn unprotect(&mut self, password: &str) -> pgp::errors::Result<()>;
+
+    fn protect(&mut self, password: &str, s2k: S2kParams) -> pgp::errors::Result<()>;
+}
+
+impl ComponentSecret for packet::SecretKey {
+    fn secret_params(&self) -> &SecretParams {
+        self.secret_params()
+    }
+
+    fn unprotect(&mut self, password: &str) -> pgp::errors::Result<()> {
+        self.remove_password(|| password.to_string())
+    }
+
+    fn protect(&mut self, password: &str, s2k: S2kParams) -> pgp::errors::Result<()> {
+        self.set_password_with_s2k(|| password.to_string(), s2k)
+    }
+}
+
+impl ComponentSecret for packet::SecretSubkey {
+    fn secret_params(&self) -> &SecretParams {
+        self.secret_params()
+    }
+
+    fn unprotect(&mut self, password: &str) -> pgp::errors::Result<()> {
+        self.remove_password(|| password.to_string())
+    }
+
+    fn protect(&mut self, password: &str, s2k: S2kParams) -> pgp::errors::Result<()> {
+        self.set_password_with_s2k(|| password.to_string(), s2k)
+    }
+}
 
-            if let Some(pw_new) = &pw_new {
-                pri.set_password_with_s2k(|| pw_new.clone(), s2k(pri.public_key().version()))
-                    .expect("FIXME");
+/// Are `params` a stub without secret key material?
+///
+/// GnuPG uses the private S2K type 101 for keys that it exports without their secret key material
+/// ("gnu-dummy"), and for references to keys on a card ("gnu-divert-to-card").
+fn is_stub(params: &SecretParams) -> bool {
+    let SecretParams::Encrypted(enc) = params else {
+        return false;
+    };
+
+    match enc.string_to_key_params() {
+        S2kParams::Cfb { s2k, .. }
+        | S2kParams::MalleableCfb { s2k, .. }
+        | S2kParams::Aead { s2k, .. } => matches!(s2k, StringToKey::Private { typ: 101, .. }),
+        S2kParams::Unprotected | S2kParams::LegacyCfb { .. } => false,
+    }
+}
+
+#[test]
+fn test_s2k_profile() {
+    use pgp::types::SecretParams;
+    use sop::ops::{ChangeKeyPassword as _, GenerateKey as _};
+
+    use crate::GenerateKey;
+
+    // S2K parameters of all component keys
+    let s2k = |keys: &Keys| {
+        let ssk = keys.keys[0].key();
+        std::iter::once(ssk.primary_key.secret_params())
+            .chain(ssk.secret_subkeys.iter().map(|s| s.key.secret_params()))
+            .map(|params| match params {
+                SecretParams::Encrypted(enc) => enc.string_to_key_params().clone(),
+                SecretParams::Plain(_) => S2kParams::Unprotected,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let generate = |profile: &str| {
+        Box::new(GenerateKey::new())
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap()
+    };
+
+    let change = |keys: &Keys, profile: S2kProfile| {
+        Box::new(ChangeKeyPassword::new())
+            .s2k_profile(profile)
+            .new_key_password(Password::new_unchecked(b"password".to_vec()))
+            .unwrap()
+            .keys(keys)
+            .unwrap()
+    };
+
+    let unlock = |keys: &Keys| {
+        let unlocked = Box::new(ChangeKeyPassword::new())
+            .old_key_password(Password::new_unchecked(b"password".to_vec()))
+            .unwrap()
+            .keys(keys)
+            .unwrap();
+        assert!(s2k(&unlocked)
+            .iter()
+            .all(|params| matches!(params, S2kParams::Unprotected)));
+    };
+
+    assert_eq!(
+        S2kProfile::by_name("low-memory"),
+        Some(S2kProfile::LOW_MEMORY)
+    );
+    assert_eq!(S2kProfile::by_name("paranoid"), None);
+
+    // Unless the caller chooses otherwise, a changed password gets the strongest protection
+    assert_eq!(ChangeKeyPassword::new().s2k, S2kProfile::SENSITIVE);
+
+    // Version 6 keys use Argon2 with the parameters of the profile
+    let keys = change(&generate("rfc9580"), S2kProfile::LOW_MEMORY);
+    for params in s2k(&keys) {
+        assert!(matches!(
+            params,
+            S2kParams::Aead {
+                s2k: StringToKey::Argon2 {
+                    t: 8,
+                    p: 4,
+                    m_enc: 13,
+                    ..
+                },
+                ..
             }
+        ));
+    }
+    unlock(&keys);
 
-            for sub in &mut ssk.secret_subkeys {
-                if let Some(pw_old) = &pw_old {
-                    sub.key.remove_password(|| pw_old.clone()).expect("FIXME");
-                }
-                if let Some(pw_new) = &pw_new {
-                    sub.key
-                        .set_password_with_s2k(
-                            || pw_new.clone(),
-                            s2k(sub.key.public_key().version()),
-                        )
-                        .expect("FIXME");
+    // Version 4 keys use CFB, or optionally AEAD, with iterated and salted SHA-256
+    for v4_aead in [false, true] {
+        let keys = change(
+            &generate("draft-koch-eddsa-for-openpgp-00"),
+            S2kProfile::LOW_MEMORY.v4_aead(v4_aead),
+        );
+        for params in s2k(&keys) {
+            let (S2kParams::Cfb { s2k, .. } | S2kParams::Aead { s2k, .. }) = &params else {
+                panic!("unexpected S2K parameters {params:?}");
+            };
+            assert_eq!(matches!(params, S2kParams::Aead { .. }), v4_aead);
+            assert!(matches!(
+                s2k,
+                StringToKey::IteratedAndSalted {
+                    hash_alg: HashAlgorithm::SHA2_256,
+                    ..
                 }
+            ));
+        }
+        unlock(&keys);
+    }
+
+    // Key generation uses the profile as well
+    let keys = Box::new(GenerateKey::new())
+        .s2k_profile(S2kProfile::LOW_MEMORY)
+        .profile("rfc9580")
+        .unwrap()
+        .with_key_password(Password::new_unchecked(b"password".to_vec()))
+        .unwrap()
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+    for params in s2k(&keys) {
+        assert!(matches!(
+            params,
+            S2kParams::Aead {
+                s2k: StringToKey::Argon2 { m_enc: 13, .. },
+                ..
             }
+        ));
+    }
+    unlock(&keys);
+}
 
-            res.push(ssk.into());
-        }
+#[test]
+fn test_change_partial_protection() {
+    use pgp::crypto::sym::SymmetricKeyAlgorithm;
+    use pgp::types::EncryptedSecretParams;
+    use pgp::SignedSecretKey;
+    use sop::ops::GenerateKey as _;
 
-        Ok(Keys {
-            keys: res,
+    use crate::{GenerateKey, KeyLayout, SubkeySpec};
+
+    let password = |pw: &str| Password::new_unchecked(pw.as_bytes().to_vec());
+
+    // A key with a signing and an encryption subkey, all unprotected
+    let keys = Box::new(GenerateKey::new())
+        .layout(
+            KeyLayout::new(false)
+                .subkey(SubkeySpec::signing())
+                .subkey(SubkeySpec::encryption()),
+        )
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+    let plain = keys.keys[0].key().clone();
+
+    // Lock the primary key with `pw_primary`, and the subkeys with `pw_subkeys`
+    // (with cheap S2K parameters, to keep the test fast)
+    let s2k = || S2kParams::Cfb {
+        sym_alg: SymmetricKeyAlgorithm::AES256,
+        s2k: StringToKey::new_iterated(thread_rng(), HashAlgorithm::SHA2_256, 0),
+        iv: vec![0; 16],
+    };
+    let lock = |pw_primary: Option<&str>, pw_subkeys: [Option<&str>; 2]| {
+        let mut ssk = plain.clone();
+        if let Some(pw) = pw_primary {
+            ssk.primary_key.protect(pw, s2k()).unwrap();
+        }
+        for (sub, pw) in ssk.secret_subkeys.iter_mut().zip(pw_subkeys) {
+            if let Some(pw) = pw {
+                sub.key.protect(pw, s2k()).unwrap();
+            }
+        }
+        Keys {
+            keys: vec![ssk.into()],
             source_name: None,
-        })
+        }
+    };
+
+    let change = |keys: &Keys, pws_old: &[&str], pw_new: Option<&str>| {
+        let mut change: Box<dyn sop::ops::ChangeKeyPassword<RPGSOP, Keys>> =
+            Box::new(ChangeKeyPassword::new());
+        for pw in pws_old {
+            change = change.old_key_password(password(pw)).unwrap();
+        }
+        if let Some(pw) = pw_new {
+            change = change.new_key_password(password(pw)).unwrap();
+        }
+        change.keys(keys).map(|keys| keys.keys[0].key().clone())
+    };
+
+    // Does `pw` unlock all components of `ssk` (and are all of them protected)?
+    let locked_with = |ssk: &SignedSecretKey, pw: &str| {
+        let mut ssk = ssk.clone();
+        std::iter::once(ssk.primary_key.secret_params())
+            .chain(ssk.secret_subkeys.iter().map(|s| s.key.secret_params()))
+            .all(|params| matches!(params, SecretParams::Encrypted(_)))
+            && ssk.primary_key.unprotect(pw).is_ok()
+            && ssk
+                .secret_subkeys
+                .iter_mut()
+                .all(|s| s.key.unprotect(pw).is_ok())
+    };
+
+    // Unprotected subkeys need no old password
+    let keys = lock(Some("old"), [None, None]);
+    let ssk = change(&keys, &["old"], Some("new")).unwrap();
+    assert!(locked_with(&ssk, "new"));
+
+    // Components locked with different passwords need all of them
+    let keys = lock(Some("old"), [Some("other"), None]);
+    let ssk = change(&keys, &["other", "old"], Some("new")).unwrap();
+    assert!(locked_with(&ssk, "new"));
+    assert!(matches!(
+        change(&keys, &["old"], Some("new")),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+
+    // Locked components without any old password
+    let keys = lock(None, [None, Some("old")]);
+    assert!(matches!(
+        change(&keys, &[], Some("new")),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+
+    // Removing the password from a partially protected key
+    let ssk = change(&keys, &["old"], None).unwrap();
+    assert_eq!(ssk, plain);
+
+    // A stub primary key (as in GnuPG's --export-secret-subkeys) stays as it is
+    let stub = SecretParams::Encrypted(EncryptedSecretParams::new(
+        vec![],
+        S2kParams::Cfb {
+            sym_alg: SymmetricKeyAlgorithm::Plaintext,
+            s2k: StringToKey::Private {
+                typ: 101,
+                unknown: b"\0GNU\x01".to_vec(),
+            },
+            iv: vec![],
+        },
+    ));
+    let mut keys = lock(None, [Some("old"), Some("old")]);
+    let Tsk::Tsk(ssk) = &mut keys.keys[0] else {
+        unreachable!()
+    };
+    ssk.primary_key = packet::SecretKey::new(ssk.primary_key.public_key(), stub.clone());
+
+    let ssk = change(&keys, &["old"], Some("new")).unwrap();
+    assert_eq!(ssk.primary_key.secret_params(), &stub);
+    for sub in ssk.secret_subkeys {
+        let mut key = sub.key;
+        assert!(key.unprotect("new").is_ok());
     }
 }
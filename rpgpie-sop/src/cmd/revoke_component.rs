@@ -0,0 +1,312 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Revocation of single components of a key: subkeys and User IDs.
+//!
+//! Unlike `sop revoke-key`, this leaves the rest of the key valid. These operations aren't part
+//! of the SOP interface.
+
+use chrono::{SubsecRound, Utc};
+use pgp::packet::{RevocationCode, SignatureType, Subpacket, SubpacketData};
+use pgp::types::{Fingerprint, PublicKeyTrait, SecretKeyTrait, Tag};
+use pgp::{Signature, SignedPublicKey};
+use rpgpie::key::Tsk;
+
+use crate::{Certs, Keys};
+
+/// The component of a key that is revoked
+enum Component {
+    Subkey(Fingerprint),
+    UserId(String),
+}
+
+/// Revocation of a subkey (with a signature of type 0x28) or of a User ID (with a signature of
+/// type 0x30).
+///
+/// The result is the certificate of the key, with the revocation signature added.
+pub struct RevokeComponent {
+    component: Component,
+    key_passwords: Vec<sop::Password>,
+    reason: RevocationCode,
+    message: String,
+}
+
+impl RevokeComponent {
+    fn new(component: Component) -> Self {
+        Self {
+            component,
+            key_passwords: vec![],
+            reason: RevocationCode::NoReason,
+            message: String::new(),
+        }
+    }
+
+    pub(crate) fn subkey(fingerprint: Fingerprint) -> Self {
+        Self::new(Component::Subkey(fingerprint))
+    }
+
+    pub(crate) fn user_id(user_id: &str) -> Self {
+        Self::new(Component::UserId(user_id.to_string()))
+    }
+
+    /// Try `password` for unlocking the primary key, which issues the revocation
+    pub fn with_key_password(mut self: Box<Self>, password: sop::Password) -> Box<Self> {
+        self.key_passwords.push(password);
+        self
+    }
+
+    /// Revoke the component for `reason`, instead of giving no reason.
+    ///
+    /// Subkeys can be revoked as superseded, compromised or retired, User IDs as no longer valid
+    /// ([`RevocationCode::CertUserIdInvalid`]).
+    pub fn reason(mut self: Box<Self>, reason: RevocationCode) -> Box<Self> {
+        self.reason = reason;
+        self
+    }
+
+    /// Explain the revocation with the human-readable `message` (by default, there is no
+    /// explanation)
+    pub fn message(mut self: Box<Self>, message: &str) -> Box<Self> {
+        self.message = message.to_string();
+        self
+    }
+
+    /// Revoke the component in each of `keys`
+    pub fn keys(&self, keys: &Keys) -> sop::Result<Certs> {
+        let reason_fits = match (&self.component, self.reason) {
+            (_, RevocationCode::NoReason) => true,
+            (Component::Subkey(_), reason) => reason != RevocationCode::CertUserIdInvalid,
+            (Component::UserId(_), reason) => !matches!(
+                reason,
+                RevocationCode::KeySuperseded
+                    | RevocationCode::KeyCompromised
+                    | RevocationCode::KeyRetired
+            ),
+        };
+        if !reason_fits {
+            log::warn!("Reason {:?} doesn't fit this revocation", self.reason);
+            return Err(sop::errors::Error::UnsupportedOption);
+        }
+
+        let now = Utc::now().trunc_subsecs(0);
+
+        // Passwords to try
+        let pws: Vec<&[u8]> = if self.key_passwords.is_empty() {
+            vec![&[]]
+        } else {
+            self.key_passwords
+                .iter()
+                .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
+                .collect()
+        };
+
+        let mut results = vec![];
+
+        for tsk in &keys.keys {
+            let Tsk::Tsk(ssk) = tsk else {
+                // rpgpie can't issue revocations with card-backed keys
+                return Err(sop::errors::Error::KeyCannotSign);
+            };
+            let primary = &ssk.primary_key;
+
+            let typ = match self.component {
+                Component::Subkey(_) => SignatureType::SubkeyRevocation,
+                Component::UserId(_) => SignatureType::CertRevocation,
+            };
+            let mut config = crate::util::signature_config(primary, typ, primary.hash_alg(), &now)?;
+            config
+                .hashed_subpackets
+                .push(Subpacket::regular(SubpacketData::RevocationReason(
+                    self.reason,
+                    self.message.as_str().into(),
+                )));
+
+            // Make the revocation signature with the first password that unlocks the primary key
+            let sign = |make: &dyn Fn(String) -> pgp::errors::Result<Signature>| {
+                let sig = pws
+                    .iter()
+                    .flat_map(|pw| {
+                        let result = make(String::from_utf8_lossy(pw).to_string());
+                        if result.is_err() {
+                            log::warn!("Revocation failed: {result:?}");
+                        }
+                        result
+                    })
+                    .next();
+
+                // Presumably, none of the passwords unlocked the primary key
+                sig.ok_or(sop::errors::Error::KeyIsProtected)
+            };
+
+            let mut spk = SignedPublicKey::from(ssk.clone());
+
+            match &self.component {
+                Component::Subkey(fingerprint) => {
+                    let Some(subkey) = spk
+                        .public_subkeys
+                        .iter_mut()
+                        .find(|s| &s.key.fingerprint() == fingerprint)
+                    else {
+                        log::warn!("Key has no subkey {fingerprint:02x?}");
+                        return Err(sop::errors::Error::BadData);
+                    };
+
+                    let sig =
+                        sign(&|pw| config.clone().sign_key_binding(primary, || pw, &subkey.key))?;
+                    subkey.signatures.push(sig);
+                }
+                Component::UserId(user_id) => {
+                    let Some(user) = spk
+                        .details
+                        .users
+                        .iter_mut()
+                        .find(|u| u.id.id() == user_id.as_str())
+                    else {
+                        return Err(sop::errors::Error::CertUseridNoMatch);
+                    };
+
+                    let sig = sign(&|pw| {
+                        config
+                            .clone()
+                            .sign_certification(primary, || pw, Tag::UserId, &user.id)
+                    })?;
+                    user.signatures.push(sig);
+                }
+            }
+
+            results.push(spk.into());
+        }
+
+        Ok(Certs {
+            certs: results,
+            source_name: None,
+        })
+    }
+}
+
+#[test]
+fn test_revoke_component() {
+    use std::time::{Duration, SystemTime};
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rpgpie::key::checked::CheckedCertificate;
+    use rpgpie::key::Certificate;
+    use sop::ops::GenerateKey as _;
+    use sop::Save;
+
+    use crate::{GenerateKey, KeyLayout, SubkeySpec};
+
+    // A key from yesterday, so that the revocations are clearly newer than its self-signatures
+    let created = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    let generate = |password: Option<&str>| {
+        let mut generate = Box::new(GenerateKey::new())
+            .layout(
+                KeyLayout::standard()
+                    .subkey(SubkeySpec::encryption())
+                    .subkey(SubkeySpec::signing()),
+            )
+            .deterministic(created, StdRng::seed_from_u64(0))
+            .userid("<alice@example.org>")
+            .userid("<alice@example.com>");
+        if let Some(pw) = password {
+            generate = generate
+                .with_key_password(sop::Password::new_unchecked(pw.as_bytes().to_vec()))
+                .unwrap();
+        }
+        generate.generate().unwrap()
+    };
+
+    // Save and load `certs`, as a user of the result would
+    let reload = |certs: Certs| {
+        let mut bytes = vec![];
+        certs.to_writer(false, &mut bytes).unwrap();
+        let mut loaded = Certificate::load(&mut &bytes[..]).unwrap();
+        assert_eq!(loaded.len(), 1);
+        loaded.remove(0)
+    };
+
+    let keys = generate(None);
+    let Tsk::Tsk(ssk) = &keys.keys[0] else {
+        unreachable!()
+    };
+
+    // Revoke one of the two encryption subkeys
+    let subkey = ssk.secret_subkeys[1].key.fingerprint();
+    let certs = Box::new(RevokeComponent::subkey(subkey.clone()))
+        .reason(RevocationCode::KeyCompromised)
+        .message("Laptop stolen")
+        .keys(&keys)
+        .unwrap();
+    let cert = reload(certs);
+    let checked = CheckedCertificate::from(&cert);
+    let now = Utc::now();
+    let encryption = checked.valid_encryption_capable_component_keys();
+    assert_eq!(encryption.len(), 1);
+    assert_ne!(encryption[0].fingerprint(), subkey);
+    assert!(checked.primary_valid_at(&now).unwrap());
+    assert_eq!(
+        checked.valid_signing_capable_component_keys_at(&now).len(),
+        2
+    );
+
+    // Revoke one of the User IDs
+    let certs = Box::new(RevokeComponent::user_id("<alice@example.com>"))
+        .reason(RevocationCode::CertUserIdInvalid)
+        .keys(&keys)
+        .unwrap();
+    let cert = reload(certs);
+    let checked = CheckedCertificate::from(&cert);
+    let now = Utc::now();
+    for (user_id, bound) in [
+        ("<alice@example.org>", true),
+        ("<alice@example.com>", false),
+    ] {
+        let user = checked
+            .user_ids()
+            .iter()
+            .find(|u| u.id.id() == user_id)
+            .unwrap();
+        assert_eq!(
+            crate::util::user_id_bound_at(user, checked.primary_creation_time(), &now),
+            bound,
+            "{user_id}"
+        );
+    }
+    assert!(checked.primary_valid_at(&now).unwrap());
+
+    // Components that the key doesn't have
+    assert!(matches!(
+        RevokeComponent::subkey(ssk.primary_key.fingerprint()).keys(&keys),
+        Err(sop::errors::Error::BadData)
+    ));
+    assert!(matches!(
+        RevokeComponent::user_id("<bob@example.org>").keys(&keys),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+
+    // Reasons for the wrong kind of component
+    assert!(matches!(
+        Box::new(RevokeComponent::subkey(subkey.clone()))
+            .reason(RevocationCode::CertUserIdInvalid)
+            .keys(&keys),
+        Err(sop::errors::Error::UnsupportedOption)
+    ));
+    assert!(matches!(
+        Box::new(RevokeComponent::user_id("<alice@example.com>"))
+            .reason(RevocationCode::KeyRetired)
+            .keys(&keys),
+        Err(sop::errors::Error::UnsupportedOption)
+    ));
+
+    // A password-protected key needs its password
+    let keys = generate(Some("password"));
+    assert!(matches!(
+        RevokeComponent::user_id("<alice@example.com>").keys(&keys),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+    assert!(Box::new(RevokeComponent::user_id("<alice@example.com>"))
+        .with_key_password(sop::Password::new_unchecked(b"password".to_vec()))
+        .keys(&keys)
+        .is_ok());
+}
@@ -7,12 +7,19 @@ use pgp::types::PublicKeyTrait;
 use pgp::types::{KeyVersion, SecretKeyTrait};
 use pgp::{Signature, SignedPublicKey};
 use rand::thread_rng;
+use rpgpie::key::Tsk;
 use sop::plumbing::PasswordsAreHumanReadable;
 
-use crate::{Certs, Keys, RPGSOP};
+use crate::{error, Certs, Keys, RPGSOP};
 
-pub(crate) struct RevokeKey {
+/// Revocation of keys, as in `sop revoke-key`.
+///
+/// Beyond the SOP interface, this allows stating the reason for the revocation.
+/// These settings must be made before using the methods of [`sop::ops::RevokeKey`].
+pub struct RevokeKey {
     key_passwords: Vec<sop::Password>, // Passwords for asymmetric component key material
+    reason: RevocationCode,
+    message: String,
 }
 
 impl RevokeKey {
@@ -21,8 +28,24 @@ impl RevokeKey {
 
         Self {
             key_passwords: vec![empty_pw],
+            reason: RevocationCode::NoReason,
+            message: String::new(),
         }
     }
+
+    /// Revoke the key for `reason` (e.g. [`RevocationCode::KeyCompromised`]), instead of
+    /// giving no reason
+    pub fn reason(mut self: Box<Self>, reason: RevocationCode) -> Box<Self> {
+        self.reason = reason;
+        self
+    }
+
+    /// Explain the revocation with the human-readable `message` (by default, there is no
+    /// explanation)
+    pub fn message(mut self: Box<Self>, message: &str) -> Box<Self> {
+        self.message = message.to_string();
+        self
+    }
 }
 
 impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
@@ -35,11 +58,21 @@ impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
     }
 
     fn keys(self: Box<Self>, keys: &Keys) -> sop::Result<Certs> {
+        // This reason is only meaningful for the revocation of User IDs
+        if self.reason == RevocationCode::CertUserIdInvalid {
+            log::warn!("Can't revoke a key with reason {:?}", self.reason);
+            return Err(sop::errors::Error::UnsupportedOption);
+        }
+
         let mut rng = thread_rng();
 
         let mut results = vec![];
         for tsk in &keys.keys {
-            let primary = &tsk.key().primary_key;
+            let Tsk::Tsk(ssk) = tsk else {
+                // rpgpie can't issue revocations with card-backed keys
+                return Err(sop::errors::Error::KeyCannotSign);
+            };
+            let primary = &ssk.primary_key;
 
             // Make a revocation signature
             let mut config = match primary.version() {
@@ -54,8 +87,11 @@ impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
                     primary.algorithm(),
                     primary.hash_alg(),
                 )
-                .expect("FIXME"),
-                v => panic!("unsupported key version {:?}", v),
+                .map_err(error::rpgp)?,
+                v => {
+                    log::warn!("Unsupported key version {:?}", v);
+                    return Err(sop::errors::Error::UnsupportedAsymmetricAlgo);
+                }
             };
 
             config.hashed_subpackets = vec![
@@ -64,8 +100,8 @@ impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
                 )),
                 Subpacket::regular(SubpacketData::Issuer(primary.key_id())),
                 Subpacket::regular(SubpacketData::RevocationReason(
-                    RevocationCode::NoReason,
-                    "unspecified".into(),
+                    self.reason,
+                    self.message.as_str().into(),
                 )),
                 Subpacket::regular(SubpacketData::IssuerFingerprint(primary.fingerprint())),
             ];
@@ -80,15 +116,16 @@ impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
                         rev = Some(sig);
                         break;
                     }
-                    Err(e) => eprintln!("e: {:?}", e),
+                    Err(e) => log::warn!("Revocation failed: {e:?}"),
                 };
             }
 
             let Some(rev) = rev else {
-                return Err(sop::errors::Error::KeyCannotSign);
+                // Presumably, none of the passwords unlocked the primary key
+                return Err(sop::errors::Error::KeyIsProtected);
             };
 
-            let mut revoked = tsk.key().clone();
+            let mut revoked = ssk.clone();
             revoked.details.revocation_signatures.push(rev);
 
             let spk = SignedPublicKey::from(revoked);
@@ -101,3 +138,147 @@ impl<'a> sop::ops::RevokeKey<'a, RPGSOP, Certs, Keys> for RevokeKey {
         })
     }
 }
+
+/// Describe a revocation with `reason` and `message`, in a single line of text
+pub(crate) fn describe_revocation(reason: &RevocationCode, message: &[u8]) -> String {
+    let reason = match reason {
+        RevocationCode::NoReason => "no reason specified".to_string(),
+        RevocationCode::KeySuperseded => "key superseded".to_string(),
+        RevocationCode::KeyCompromised => "key compromised".to_string(),
+        RevocationCode::KeyRetired => "key retired".to_string(),
+        RevocationCode::CertUserIdInvalid => "User ID no longer valid".to_string(),
+        code => format!("{code:?}"),
+    };
+
+    let message = String::from_utf8_lossy(message);
+    let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    match message.is_empty() {
+        true => reason,
+        false => format!("{reason} ({message})"),
+    }
+}
+
+#[test]
+fn test_revocation_reason() {
+    use rpgpie::key::checked::CheckedCertificate;
+    use sop::ops::{ExtractCert as _, GenerateKey as _, RevokeKey as _};
+    use sop::{Load, Save};
+
+    for profile in ["draft-koch-eddsa-for-openpgp-00", "rfc9580"] {
+        let keys = Box::new(crate::GenerateKey::new())
+            .profile(profile)
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap();
+
+        let certs = Box::new(RevokeKey::new())
+            .reason(RevocationCode::KeyCompromised)
+            .message("Laptop\nstolen")
+            .keys(&keys)
+            .unwrap();
+
+        let spk = crate::util::to_signed_public_key(&certs.certs[0]).unwrap();
+        let rev = &spk.details.revocation_signatures[0];
+        assert_eq!(
+            rev.revocation_reason_code(),
+            Some(&RevocationCode::KeyCompromised)
+        );
+        assert_eq!(
+            rev.revocation_reason_string(),
+            Some(b"Laptop\nstolen".as_ref().into())
+        );
+
+        let checked = CheckedCertificate::from(&certs.certs[0]);
+        assert!(!checked.primary_valid_at(&chrono::Utc::now()).unwrap());
+
+        // Inspecting the revocation certificate shows the reason, also after a roundtrip
+        assert_eq!(
+            certs.revocations().unwrap(),
+            vec!["key compromised (Laptop stolen)"],
+            "{profile}"
+        );
+
+        let mut armored = vec![];
+        certs.to_writer(true, &mut armored).unwrap();
+        let loaded = Certs::from_reader(&RPGSOP::default(), &mut &armored[..], None).unwrap();
+        assert_eq!(certs.revocations().unwrap(), loaded.revocations().unwrap());
+
+        // Saving doesn't depend on the content of the certificate
+        assert!(!String::from_utf8(armored).unwrap().contains("Comment:"));
+
+        // Keys that aren't revoked have no revocations
+        assert!(Box::new(crate::cmd::extract_cert::ExtractCert::new())
+            .keys(&keys)
+            .unwrap()
+            .revocations()
+            .unwrap()
+            .is_empty());
+
+        // User IDs have their own revocation reason
+        assert!(matches!(
+            Box::new(RevokeKey::new())
+                .reason(RevocationCode::CertUserIdInvalid)
+                .keys(&keys),
+            Err(sop::errors::Error::UnsupportedOption)
+        ));
+    }
+}
+
+#[test]
+fn test_revoke_protected() {
+    use sop::ops::{GenerateKey as _, RevokeKey as _};
+
+    let password = || sop::Password::new_unchecked(b"password".to_vec());
+
+    for profile in ["draft-koch-eddsa-for-openpgp-00", "rfc9580"] {
+        let keys = Box::new(crate::GenerateKey::new())
+            .profile(profile)
+            .unwrap()
+            .with_key_password(password())
+            .unwrap()
+            .userid("<alice@example.org>")
+            .generate()
+            .unwrap();
+
+        // Without the password, the caller can find out that it needs to ask for one
+        assert!(
+            matches!(
+                Box::new(RevokeKey::new()).keys(&keys),
+                Err(sop::errors::Error::KeyIsProtected)
+            ),
+            "{profile}"
+        );
+
+        let certs = Box::new(RevokeKey::new())
+            .with_key_password(password())
+            .unwrap()
+            .keys(&keys)
+            .unwrap();
+        let spk = crate::util::to_signed_public_key(&certs.certs[0]).unwrap();
+        assert_eq!(spk.details.revocation_signatures.len(), 1, "{profile}");
+    }
+}
+
+#[test]
+fn test_revoke_card() {
+    use rpgpie::key::Certificate;
+    use sop::ops::{GenerateKey as _, RevokeKey as _};
+
+    let keys = Box::new(crate::GenerateKey::new())
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+
+    // A key whose secret key material is on an OpenPGP card
+    let card = Keys {
+        keys: vec![Tsk::Card(Certificate::from(&keys.keys[0]))],
+        source_name: None,
+    };
+
+    assert!(matches!(
+        Box::new(RevokeKey::new()).keys(&card),
+        Err(sop::errors::Error::KeyCannotSign)
+    ));
+}
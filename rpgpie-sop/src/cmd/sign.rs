@@ -5,12 +5,15 @@ use std::io;
 
 use chrono::{DateTime, Utc};
 use pgp::crypto::hash::HashAlgorithm;
-use pgp::packet::LiteralData;
+use pgp::packet::{LiteralData, SignatureType};
 use pgp::Message;
 use rpgpie::key::checked::CheckedCertificate;
-use rpgpie::key::{Certificate, Tsk};
+use rpgpie::key::{Certificate, DataSigner, Tsk};
 
-use crate::{Keys, Sigs, RPGSOP};
+use crate::stream::sign::Signer;
+use crate::stream::text::Utf8Check;
+use crate::stream::BUF_SIZE;
+use crate::{error, Keys, Sigs, RPGSOP};
 
 pub(crate) struct Sign {
     pub(crate) mode: sop::ops::SignAs,
@@ -90,81 +93,179 @@ impl<'a> sop::ops::Sign<'a, RPGSOP, Keys, Sigs> for Sign {
 
         let hash_algo = self.hash_algos.first().cloned().unwrap_or_default();
 
-        let mut data = vec![];
-        input.read_to_end(&mut data)?;
+        let data_signers: Vec<DataSigner> = self
+            .signers
+            .iter()
+            .flat_map(|tsk| tsk.signing_capable_component_keys())
+            .collect();
 
-        let lit = match self.mode {
-            sop::ops::SignAs::Binary => LiteralData::from_bytes("".into(), &data),
-            sop::ops::SignAs::Text => {
-                LiteralData::from_str("", &String::from_utf8(data).expect("FIXME"))
-            }
+        let sigs = if data_signers
+            .iter()
+            .any(|ds| matches!(ds, DataSigner::Card(_)))
+        {
+            // Signing with card-backed keys is only possible via rpgpie, which processes the
+            // data in memory
+            self.sign_buffered(data_signers, hash_algo, input)?
+        } else {
+            self.sign_streaming(data_signers, hash_algo, input)?
         };
 
-        let msg = Message::Literal(lit);
+        if sigs.is_empty() {
+            // FIXME: probably the password(s) were wrong, but this is a bit of a guess
+            return Err(sop::errors::Error::KeyIsProtected);
+        }
 
-        let mut sigs = vec![];
+        let hash_algo_id = u8::from(hash_algo);
+
+        Ok((
+            hash_algo_id.into(),
+            Sigs {
+                sigs,
+                source_name: None,
+            },
+        ))
+    }
+}
 
-        // Passwords to try
-        let pws: Vec<&[u8]> = if self.with_key_password.is_empty() {
+impl Sign {
+    /// Passwords to try for unlocking the signers
+    pub(crate) fn passwords(&self) -> Vec<&[u8]> {
+        if self.with_key_password.is_empty() {
             vec![&[]]
         } else {
             self.with_key_password
                 .iter()
                 .map(sop::plumbing::PasswordsAreHumanReadable::normalized)
                 .collect()
+        }
+    }
+
+    /// Sign `input` with all `data_signers` that we can unlock, hashing the data as it is read
+    fn sign_streaming(
+        &self,
+        data_signers: Vec<DataSigner>,
+        hash_algo: HashAlgorithm,
+        input: &mut (dyn io::Read + Send + Sync),
+    ) -> sop::Result<Vec<pgp::Signature>> {
+        let typ = match self.mode {
+            sop::ops::SignAs::Binary => SignatureType::Binary,
+            sop::ops::SignAs::Text => SignatureType::Text,
         };
 
-        for tsk in self.signers {
-            for signer in tsk.signing_capable_component_keys() {
-                log::info!(
-                    "Trying to sign data with signer: {:02x?}",
-                    signer.fingerprint()
-                );
-                let sig = pws
-                    .iter()
-                    .flat_map(|pw| {
-                        let result = signer.sign_msg(
-                            msg.clone(),
-                            || String::from_utf8_lossy(pw).to_string(),
-                            hash_algo,
-                        );
-
-                        if result.is_err() {
-                            log::warn!("Signing failed: {result:?}");
-                        }
-
-                        result
-                    })
-                    .next();
-
-                match sig {
-                    Some(Message::Signed { signature, .. }) => sigs.push(signature),
-                    Some(_) => panic!("Unexpected message type while signing: {:?}", sig),
-                    None => {
-                        log::warn!(
-                            "Couldn't sign with signer key {:02x?}",
-                            signer.fingerprint()
-                        );
-
-                        // signing with this signing key failed but let's continue
-                    }
-                };
+        let pws = self.passwords();
+
+        let mut signers = vec![];
+        for ds in data_signers {
+            let DataSigner::Software(key) = ds else {
+                continue;
+            };
+
+            match Signer::new(key, &pws, typ, hash_algo) {
+                Ok(signer) => signers.push(signer),
+                Err(e) => {
+                    // signing with this signing key failed but let's continue
+                    log::warn!("Couldn't sign with signer key: {e:?}");
+                }
             }
         }
 
-        if sigs.is_empty() {
-            // FIXME: probably the password(s) were wrong, but this is a bit of a guess
-            return Err(sop::errors::Error::KeyIsProtected);
+        if signers.is_empty() {
+            return Ok(vec![]);
         }
 
-        let hash_algo_id = u8::from(hash_algo);
+        let mut text = (typ == SignatureType::Text).then(Utf8Check::new);
 
-        Ok((
-            hash_algo_id.into(),
-            Sigs {
-                sigs,
-                source_name: None,
-            },
-        ))
+        let mut buf = vec![0; BUF_SIZE];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if text.as_mut().is_some_and(|t| !t.update(&buf[..n])) {
+                return Err(sop::errors::Error::ExpectedText);
+            }
+
+            signers.iter_mut().for_each(|s| s.update(&buf[..n]));
+        }
+
+        if text.is_some_and(|t| !t.finish()) {
+            return Err(sop::errors::Error::ExpectedText);
+        }
+
+        signers
+            .into_iter()
+            .map(Signer::finish)
+            .collect::<Result<_, _>>()
+            .map_err(error::rpgp)
+    }
+
+    /// Sign `input` with all `data_signers` that we can unlock, via rpgpie (which processes the
+    /// data in memory)
+    fn sign_buffered(
+        &self,
+        data_signers: Vec<DataSigner>,
+        hash_algo: HashAlgorithm,
+        input: &mut (dyn io::Read + Send + Sync),
+    ) -> sop::Result<Vec<pgp::Signature>> {
+        let mut data = vec![];
+        input.read_to_end(&mut data)?;
+
+        let lit = match self.mode {
+            sop::ops::SignAs::Binary => LiteralData::from_bytes("".into(), &data),
+            sop::ops::SignAs::Text => {
+                let text = String::from_utf8(data).map_err(|_| sop::errors::Error::ExpectedText)?;
+                LiteralData::from_str("", &text)
+            }
+        };
+
+        let msg = Message::Literal(lit);
+
+        let mut sigs = vec![];
+
+        let pws = self.passwords();
+
+        for signer in data_signers {
+            log::info!(
+                "Trying to sign data with signer: {:02x?}",
+                signer.fingerprint()
+            );
+            let sig = pws
+                .iter()
+                .flat_map(|pw| {
+                    let result = signer.sign_msg(
+                        msg.clone(),
+                        || String::from_utf8_lossy(pw).to_string(),
+                        hash_algo,
+                    );
+
+                    if result.is_err() {
+                        log::warn!("Signing failed: {result:?}");
+                    }
+
+                    result
+                })
+                .next();
+
+            match sig {
+                Some(Message::Signed { signature, .. }) => sigs.push(signature),
+                Some(_) => {
+                    log::warn!("Unexpected message type while signing: {:?}", sig);
+                    return Err(sop::errors::Error::UnspecifiedFailure);
+                }
+                None => {
+                    log::warn!(
+                        "Couldn't sign with signer key {:02x?}",
+                        signer.fingerprint()
+                    );
+
+                    // signing with this signing key failed but let's continue
+                }
+            };
+        }
+
+        Ok(sigs)
     }
 }
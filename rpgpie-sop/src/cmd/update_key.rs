@@ -0,0 +1,659 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use chrono::{DateTime, SubsecRound, Utc};
+use pgp::crypto::ecc_curve::ECCCurve;
+use pgp::packet::{
+    KeyFlags, PacketTrait, SignatureConfig, SignatureType, Subpacket, SubpacketData, SubpacketType,
+};
+use pgp::types::{
+    EcdsaPublicParams, PublicKeyTrait, PublicParams, S2kParams, SecretKeyTrait, SecretParams,
+};
+use pgp::{packet, KeyType, Signature, SignedPublicKey, SignedSecretKey, SignedSecretSubKey};
+use rand::thread_rng;
+use rpgpie::key::checked::CheckedCertificate;
+use rpgpie::key::component::SignedComponentKeyPub;
+use rpgpie::key::{Certificate, Tsk};
+use rpgpie::policy::{
+    PREFERRED_AEAD_ALGORITHMS, PREFERRED_COMPRESSION_ALGORITHMS, PREFERRED_HASH_ALGORITHMS,
+    PREFERRED_SYMMETRIC_KEY_ALGORITHMS,
+};
+use rpgpie::sig::stack::SigStack;
+use sop::plumbing::PasswordsAreHumanReadable;
+
+use crate::cmd::merge_certs::{merge_details, merge_signatures, merge_subkeys};
+use crate::{Certs, Keys, S2kProfile, RPGSOP};
+
+/// Feature flag: Version 1 Symmetrically Encrypted and Integrity Protected Data packet
+pub(crate) const FEATURE_SEIPD_V1: u8 = 0x01;
+
+/// Feature flag: Version 2 Symmetrically Encrypted and Integrity Protected Data packet
+pub(crate) const FEATURE_SEIPD_V2: u8 = 0x08;
+
+/// Subpackets that we always generate afresh, instead of copying them over from the
+/// self-signature that is being replaced.
+const REGENERATED: &[SubpacketType] = &[
+    SubpacketType::SignatureCreationTime,
+    SubpacketType::Issuer,
+    SubpacketType::IssuerFingerprint,
+];
+
+/// Algorithm preference subpackets, which get replaced with our current policy.
+const PREFERENCES: &[SubpacketType] = &[
+    SubpacketType::PreferredSymmetricAlgorithms,
+    SubpacketType::PreferredHashAlgorithms,
+    SubpacketType::PreferredCompressionAlgorithms,
+    SubpacketType::PreferredAead,
+    SubpacketType::Features,
+];
+
+pub(crate) struct UpdateKey {
+    signing_only: bool,
+    no_added_capabilities: bool,
+    key_passwords: Vec<sop::Password>, // Passwords for asymmetric component key material
+    merge_certs: Vec<Certificate>,
+    s2k: S2kProfile, // Protection of newly added subkeys
+}
+
+impl UpdateKey {
+    pub(crate) fn new() -> Self {
+        let empty_pw = sop::Password::new_unchecked(vec![]);
+
+        Self {
+            signing_only: false,
+            no_added_capabilities: false,
+            key_passwords: vec![empty_pw],
+            merge_certs: vec![],
+            s2k: S2kProfile::INTERACTIVE,
+        }
+    }
+
+    /// Protect subkeys that get added with the key password as described by `profile`
+    pub(crate) fn s2k_profile(mut self, profile: S2kProfile) -> Self {
+        self.s2k = profile;
+        self
+    }
+}
+
+impl<'a> sop::ops::UpdateKey<'a, RPGSOP, Certs, Keys> for UpdateKey {
+    fn signing_only(mut self: Box<Self>) -> Box<dyn sop::ops::UpdateKey<'a, RPGSOP, Certs, Keys>> {
+        self.signing_only = true;
+        self
+    }
+
+    fn no_added_capabilities(
+        mut self: Box<Self>,
+    ) -> Box<dyn sop::ops::UpdateKey<'a, RPGSOP, Certs, Keys>> {
+        self.no_added_capabilities = true;
+        self
+    }
+
+    fn with_key_password(
+        mut self: Box<Self>,
+        password: sop::Password,
+    ) -> sop::Result<Box<dyn sop::ops::UpdateKey<'a, RPGSOP, Certs, Keys>>> {
+        self.key_passwords.push(password);
+        Ok(self)
+    }
+
+    fn merge_updates(
+        mut self: Box<Self>,
+        updates: &Certs,
+    ) -> sop::Result<Box<dyn sop::ops::UpdateKey<'a, RPGSOP, Certs, Keys>>> {
+        self.merge_certs.extend(updates.certs.iter().cloned());
+        Ok(self)
+    }
+
+    fn update(self: Box<Self>, keys: &Keys) -> sop::Result<Keys> {
+        let mut results = vec![];
+
+        for tsk in &keys.keys {
+            let Tsk::Tsk(ssk) = tsk else {
+                // We can't issue self-signatures with card-backed keys
+                return Err(sop::errors::Error::BadData);
+            };
+
+            let mut ssk = ssk.clone();
+
+            let fp = ssk.fingerprint();
+            for update in self.merge_certs.iter().filter(|c| c.fingerprint() == fp) {
+                merge(&mut ssk, &crate::util::to_signed_public_key(update)?);
+            }
+
+            let Some(pw) = self.password(&ssk.primary_key) else {
+                return Err(sop::errors::Error::KeyIsProtected);
+            };
+
+            self.refresh(&mut ssk, &pw)?;
+
+            results.push(ssk.into());
+        }
+
+        Ok(Keys {
+            keys: results,
+            source_name: None,
+        })
+    }
+}
+
+impl UpdateKey {
+    /// Find the password (out of `key_passwords`) that unlocks `primary`
+    fn password(&self, primary: &packet::SecretKey) -> Option<String> {
+        self.key_passwords
+            .iter()
+            .map(|pw| String::from_utf8_lossy(pw.normalized()).to_string())
+            .find(|pw| primary.unlock(|| pw.clone(), |_| Ok(())).is_ok())
+    }
+
+    /// Replace the self-signatures of all usable components of `ssk` with fresh ones that
+    /// reflect our current algorithm policy. Drop subkeys that have no valid binding.
+    fn refresh(&self, ssk: &mut SignedSecretKey, pw: &str) -> sop::Result<()> {
+        let now = Utc::now().trunc_subsecs(0);
+
+        // Only cryptographically valid self-signatures, as a basis for the refreshed signatures
+        let checked = checked_spk(&Tsk::from(ssk.clone()));
+        let primary = ssk.primary_key.clone();
+
+        // Direct key signature
+        let dks = SigStack::from_iter(checked.details.direct_signatures.iter()).active();
+        if let Some(dks) = dks {
+            let sig = self
+                .config(&primary, dks, SignatureType::Key, &now, true)?
+                .sign_key(&primary, || pw.to_string(), &primary)
+                .map_err(crate::error::rpgp)?;
+
+            replace(
+                &mut ssk.details.direct_signatures,
+                &checked.details.direct_signatures,
+                sig,
+            );
+        }
+
+        // User ID bindings
+        for user in &mut ssk.details.users {
+            let Some(checked_user) = checked.details.users.iter().find(|u| u.id == user.id) else {
+                continue;
+            };
+
+            let stack = SigStack::from_iter(checked_user.signatures.iter());
+            let Some(active) = stack.active() else {
+                continue;
+            };
+
+            // We leave revoked or expired user ids alone
+            if active.typ() == SignatureType::CertRevocation
+                || !stack.has_valid_binding_at(&now, primary.created_at())
+            {
+                continue;
+            }
+
+            let sig = self
+                .config(&primary, active, active.typ(), &now, true)?
+                .sign_certification(&primary, || pw.to_string(), user.id.tag(), &user.id)
+                .map_err(crate::error::rpgp)?;
+
+            replace(&mut user.signatures, &checked_user.signatures, sig);
+        }
+
+        // Subkey bindings
+        let mut secret_subkeys = vec![];
+        for mut sub in ssk.secret_subkeys.drain(..) {
+            if self.refresh_subkey(&primary, pw, &checked, &sub.key, &mut sub.signatures, &now)? {
+                secret_subkeys.push(sub);
+            }
+        }
+        ssk.secret_subkeys = secret_subkeys;
+
+        let mut public_subkeys = vec![];
+        for mut sub in ssk.public_subkeys.drain(..) {
+            if self.refresh_subkey(&primary, pw, &checked, &sub.key, &mut sub.signatures, &now)? {
+                public_subkeys.push(sub);
+            }
+        }
+        ssk.public_subkeys = public_subkeys;
+
+        // Make sure that the key remains usable for encryption, unless the caller didn't ask for
+        // that, or doesn't want the key to gain capabilities
+        if !self.signing_only && !self.no_added_capabilities {
+            let cert = Certificate::from(&Tsk::from(ssk.clone()));
+            if CheckedCertificate::from(&cert)
+                .valid_encryption_capable_component_keys()
+                .is_empty()
+            {
+                let sub = self.encryption_subkey(&primary, pw, &now)?;
+                ssk.secret_subkeys.push(sub);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refresh the binding signature of one subkey.
+    ///
+    /// Returns `false` if the subkey is unusable and should be dropped.
+    fn refresh_subkey(
+        &self,
+        primary: &packet::SecretKey,
+        pw: &str,
+        checked: &SignedPublicKey,
+        key: &impl PublicKeyTrait,
+        signatures: &mut Vec<Signature>,
+        now: &DateTime<Utc>,
+    ) -> sop::Result<bool> {
+        let fp = key.fingerprint();
+        let Some(checked_sub) = checked
+            .public_subkeys
+            .iter()
+            .find(|sk| sk.key.fingerprint() == fp)
+        else {
+            return Ok(false);
+        };
+
+        let stack = SigStack::from_iter(checked_sub.signatures.iter());
+        let Some(active) = stack.active() else {
+            log::info!(
+                "Dropping subkey {} without valid binding",
+                hex::encode(fp.as_bytes())
+            );
+            return Ok(false);
+        };
+
+        // Revoked subkeys are kept as they are, so that the revocation stays visible
+        if active.typ() == SignatureType::SubkeyRevocation {
+            return Ok(true);
+        }
+
+        if !stack.has_valid_binding_at(now, checked_sub.key.created_at()) {
+            log::info!("Dropping unusable subkey {}", hex::encode(fp.as_bytes()));
+            return Ok(false);
+        }
+
+        let sig = self
+            .config(primary, active, SignatureType::SubkeyBinding, now, false)?
+            .sign_key_binding(primary, || pw.to_string(), key)
+            .map_err(crate::error::rpgp)?;
+
+        replace(signatures, &checked_sub.signatures, sig);
+
+        Ok(true)
+    }
+
+    /// Generate a new encryption subkey that matches the algorithm family of `primary`
+    fn encryption_subkey(
+        &self,
+        primary: &packet::SecretKey,
+        pw: &str,
+        now: &DateTime<Utc>,
+    ) -> sop::Result<SignedSecretSubKey> {
+        let mut rng = thread_rng();
+
+        let key_type = match primary.public_params() {
+            PublicParams::Ed25519 { .. } => KeyType::X25519,
+            PublicParams::EdDSALegacy { .. } => KeyType::ECDH(ECCCurve::Curve25519),
+            PublicParams::ECDSA(EcdsaPublicParams::P256 { .. }) => KeyType::ECDH(ECCCurve::P256),
+            PublicParams::ECDSA(EcdsaPublicParams::P384 { .. }) => KeyType::ECDH(ECCCurve::P384),
+            PublicParams::ECDSA(EcdsaPublicParams::P521 { .. }) => KeyType::ECDH(ECCCurve::P521),
+            PublicParams::RSA { n, .. } => KeyType::Rsa((n.as_bytes().len() * 8) as u32),
+            _ => return Err(sop::errors::Error::UnsupportedAsymmetricAlgo),
+        };
+
+        let (public_params, secret_params) =
+            key_type.generate(&mut rng).map_err(crate::error::rpgp)?;
+
+        let public = packet::PublicSubkey::new(
+            primary.packet_version(),
+            primary.version(),
+            key_type.to_alg(),
+            *now,
+            None,
+            public_params,
+        )
+        .map_err(crate::error::rpgp)?;
+
+        let mut key = packet::SecretSubkey::new(public, secret_params);
+
+        let mut flags = KeyFlags::default();
+        flags.set_encrypt_comms(true);
+        flags.set_encrypt_storage(true);
+
+        let mut config = crate::util::signature_config(
+            primary,
+            SignatureType::SubkeyBinding,
+            primary.hash_alg(),
+            now,
+        )?;
+        config
+            .hashed_subpackets
+            .push(Subpacket::regular(SubpacketData::KeyFlags(flags.into())));
+
+        let sig = config
+            .sign_key_binding(primary, || pw.to_string(), &key)
+            .map_err(crate::error::rpgp)?;
+
+        // Protect the new subkey with the password of the primary. Version 4 keys use AEAD
+        // protection only if the primary does, so that the key stays usable wherever it was.
+        if let SecretParams::Encrypted(encrypted) = primary.secret_params() {
+            let v4_aead = matches!(encrypted.string_to_key_params(), S2kParams::Aead { .. });
+            let s2k = self
+                .s2k
+                .v4_aead(v4_aead)
+                .params(&mut rng, primary.version())?;
+
+            key.set_password_with_s2k(|| pw.to_string(), s2k)
+                .map_err(crate::error::rpgp)?;
+        }
+
+        log::info!(
+            "Added new encryption subkey {}",
+            hex::encode(key.fingerprint().as_bytes())
+        );
+
+        Ok(SignedSecretSubKey::new(key, vec![sig]))
+    }
+
+    /// Make a signature config for a new self-signature of type `typ`, based on `template`.
+    ///
+    /// Subpackets of the template are copied over (both hashed and unhashed), except for issuer
+    /// information and creation time. If `preferences` is set, algorithm preferences and
+    /// features are set based on our current policy.
+    fn config(
+        &self,
+        primary: &packet::SecretKey,
+        template: &Signature,
+        typ: SignatureType,
+        now: &DateTime<Utc>,
+        preferences: bool,
+    ) -> sop::Result<SignatureConfig> {
+        let mut config = crate::util::signature_config(primary, typ, primary.hash_alg(), now)?;
+
+        config.hashed_subpackets.extend(
+            template
+                .config
+                .hashed_subpackets
+                .iter()
+                .filter(|sp| !REGENERATED.contains(&sp.typ()))
+                .filter(|sp| !preferences || !PREFERENCES.contains(&sp.typ()))
+                .cloned(),
+        );
+        config.unhashed_subpackets.extend(
+            template
+                .config
+                .unhashed_subpackets
+                .iter()
+                .filter(|sp| !REGENERATED.contains(&sp.typ()))
+                .cloned(),
+        );
+
+        if preferences {
+            let features = match (template.features().first(), self.no_added_capabilities) {
+                (Some(&f), true) => Some(f),
+                (None, true) => None,
+                (f, false) => Some(f.unwrap_or(&0) | FEATURE_SEIPD_V1 | FEATURE_SEIPD_V2),
+            };
+
+            config.hashed_subpackets.extend([
+                Subpacket::regular(SubpacketData::PreferredSymmetricAlgorithms(
+                    PREFERRED_SYMMETRIC_KEY_ALGORITHMS.into(),
+                )),
+                Subpacket::regular(SubpacketData::PreferredHashAlgorithms(
+                    PREFERRED_HASH_ALGORITHMS.into(),
+                )),
+                Subpacket::regular(SubpacketData::PreferredCompressionAlgorithms(
+                    PREFERRED_COMPRESSION_ALGORITHMS.into(),
+                )),
+            ]);
+
+            if let Some(features) = features {
+                if features & FEATURE_SEIPD_V2 != 0 {
+                    config.hashed_subpackets.push(Subpacket::regular(
+                        SubpacketData::PreferredAeadAlgorithms(PREFERRED_AEAD_ALGORITHMS.into()),
+                    ));
+                }
+
+                config
+                    .hashed_subpackets
+                    .push(Subpacket::regular(SubpacketData::Features(
+                        [features][..].into(),
+                    )));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Get a copy of the certificate of `tsk`, stripped of all signatures that are not valid
+fn checked_spk(tsk: &Tsk) -> SignedPublicKey {
+    let checked = CheckedCertificate::from(&Certificate::from(tsk));
+    match checked.primary_key() {
+        SignedComponentKeyPub::Primary((spk, _)) => spk,
+        SignedComponentKeyPub::Subkey(_) => unreachable!("primary_key() returned a subkey"),
+    }
+}
+
+/// Replace the (non-revocation) self-signatures in `signatures` that appear in `superseded` with
+/// `new`.
+///
+/// Note: Self-signatures only have a resolution of one second, so a new signature is not reliably
+/// preferred over older ones. We drop the old bindings to avoid any ambiguity.
+fn replace(signatures: &mut Vec<Signature>, superseded: &[Signature], new: Signature) {
+    signatures.retain(|s| {
+        matches!(
+            s.typ(),
+            SignatureType::KeyRevocation
+                | SignatureType::SubkeyRevocation
+                | SignatureType::CertRevocation
+        ) || !superseded.contains(s)
+    });
+    signatures.push(new);
+}
+
+/// Merge the components and signatures of `update` into `ssk`
+fn merge(ssk: &mut SignedSecretKey, update: &SignedPublicKey) {
+    merge_details(&mut ssk.details, &update.details);
+
+    // Signatures on subkeys that we hold secret key material for get merged in place,
+    // all other subkeys are handled as public subkeys.
+    let mut public_subkeys = vec![];
+    for sub in &update.public_subkeys {
+        let fp = sub.key.fingerprint();
+
+        match ssk
+            .secret_subkeys
+            .iter_mut()
+            .find(|s| s.key.fingerprint() == fp)
+        {
+            Some(s) => merge_signatures(&mut s.signatures, &sub.signatures),
+            None => public_subkeys.push(sub.clone()),
+        }
+    }
+    merge_subkeys(&mut ssk.public_subkeys, &public_subkeys);
+}
+
+#[test]
+fn test_update_key_v4_password() {
+    use sop::ops::UpdateKey as _;
+
+    let tsk = Tsk::generate_v4(
+        KeyType::EdDSALegacy,
+        KeyType::ECDH(ECCCurve::Curve25519),
+        Some("<alice@example.org>".to_string()),
+        vec![],
+        Some("password"),
+    )
+    .unwrap();
+
+    let keys = Keys {
+        keys: vec![tsk],
+        source_name: None,
+    };
+
+    assert!(matches!(
+        Box::new(UpdateKey::new()).update(&keys),
+        Err(sop::errors::Error::KeyIsProtected)
+    ));
+
+    let updated = Box::new(UpdateKey::new())
+        .with_key_password(sop::Password::new_unchecked(b"password".to_vec()))
+        .unwrap()
+        .update(&keys)
+        .unwrap();
+
+    let cert = Certificate::from(&updated.keys[0]);
+    let checked = CheckedCertificate::from(&cert);
+
+    let now = Utc::now();
+    assert_eq!(
+        checked.features(&now),
+        Some(FEATURE_SEIPD_V1 | FEATURE_SEIPD_V2)
+    );
+    assert_eq!(
+        checked.preferred_aead_algo(&now),
+        Some(PREFERRED_AEAD_ALGORITHMS)
+    );
+    assert_eq!(
+        checked.preferred_hash_algorithms(&now),
+        Some(PREFERRED_HASH_ALGORITHMS)
+    );
+    assert_eq!(checked.user_ids()[0].signatures.len(), 1);
+    assert_eq!(checked.valid_encryption_capable_component_keys().len(), 1);
+}
+
+#[test]
+fn test_update_key_v6() {
+    use sop::ops::UpdateKey as _;
+
+    let tsk = Tsk::generate_v6(
+        KeyType::Ed25519,
+        KeyType::X25519,
+        Some("<bob@example.org>".to_string()),
+        vec![],
+        None,
+    )
+    .unwrap();
+
+    let keys = Keys {
+        keys: vec![tsk],
+        source_name: None,
+    };
+
+    let updated = Box::new(UpdateKey::new()).update(&keys).unwrap();
+
+    let cert = Certificate::from(&updated.keys[0]);
+    let checked = CheckedCertificate::from(&cert);
+
+    let now = Utc::now();
+    assert_eq!(
+        checked.features(&now),
+        Some(FEATURE_SEIPD_V1 | FEATURE_SEIPD_V2)
+    );
+    assert_eq!(
+        checked.preferred_symmetric_key_algo(&now),
+        Some(PREFERRED_SYMMETRIC_KEY_ALGORITHMS)
+    );
+    assert_eq!(
+        checked.preferred_aead_algo(&now),
+        Some(PREFERRED_AEAD_ALGORITHMS)
+    );
+    assert_eq!(checked.valid_encryption_capable_component_keys().len(), 1);
+}
+
+#[test]
+fn test_update_key_added_subkey() {
+    use pgp::packet::Notation;
+    use pgp::types::StringToKey;
+    use sop::ops::{GenerateKey as _, UpdateKey as _};
+
+    let password = || sop::Password::new_unchecked(b"password".to_vec());
+
+    let keys = Box::new(crate::GenerateKey::new())
+        .profile("rfc9580")
+        .unwrap()
+        .signing_only()
+        .with_key_password(password())
+        .unwrap()
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+
+    // A notation in the unhashed area of the User ID binding, which isn't covered by the
+    // signature
+    let Tsk::Tsk(mut ssk) = keys.keys[0].clone() else {
+        unreachable!()
+    };
+    let notation = Notation {
+        readable: true,
+        name: "note@example.org".into(),
+        value: "unhashed".into(),
+    };
+    ssk.details.users[0].signatures[0]
+        .config
+        .unhashed_subpackets
+        .push(Subpacket::regular(SubpacketData::Notation(
+            notation.clone(),
+        )));
+    let keys = Keys {
+        keys: vec![ssk.into()],
+        source_name: None,
+    };
+
+    let updated = Box::new(UpdateKey::new().s2k_profile(S2kProfile::LOW_MEMORY))
+        .with_key_password(password())
+        .unwrap()
+        .update(&keys)
+        .unwrap();
+
+    let Tsk::Tsk(ssk) = &updated.keys[0] else {
+        unreachable!()
+    };
+
+    // The added encryption subkey is protected as described by the S2K profile
+    assert_eq!(ssk.secret_subkeys.len(), 1);
+    let SecretParams::Encrypted(encrypted) = ssk.secret_subkeys[0].key.secret_params() else {
+        panic!("added subkey is unprotected");
+    };
+    assert!(matches!(
+        encrypted.string_to_key_params(),
+        S2kParams::Aead {
+            s2k: StringToKey::Argon2 {
+                t: 8,
+                p: 4,
+                m_enc: 13,
+                ..
+            },
+            ..
+        }
+    ));
+
+    // The refreshed User ID binding keeps the unhashed notation
+    let binding = &ssk.details.users[0].signatures[0];
+    assert!(binding
+        .config
+        .unhashed_subpackets
+        .iter()
+        .any(|sp| sp.data == SubpacketData::Notation(notation.clone())));
+}
+
+#[test]
+fn test_update_key_no_added_capabilities() {
+    use sop::ops::{GenerateKey as _, UpdateKey as _};
+
+    let keys = Box::new(crate::GenerateKey::new())
+        .profile("rfc9580")
+        .unwrap()
+        .signing_only()
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+
+    let updated = Box::new(UpdateKey::new())
+        .no_added_capabilities()
+        .update(&keys)
+        .unwrap();
+
+    // The signing-only key doesn't become capable of encryption
+    let cert = Certificate::from(&updated.keys[0]);
+    assert!(CheckedCertificate::from(&cert)
+        .valid_encryption_capable_component_keys()
+        .is_empty());
+}
@@ -0,0 +1,415 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{SignatureType, UserId};
+use pgp::types::{PublicKeyTrait, Tag};
+use pgp::{Signature, SignedPublicKey};
+use rpgpie::key::checked::CheckedCertificate;
+use rpgpie::key::Certificate;
+
+use crate::{Certs, RPGSOP};
+
+/// Check that User IDs on target certificates are directly certified by a set of trust roots.
+///
+/// A User ID is considered valid if it is correctly self-bound on the target certificate, and
+/// either directly certified by the primary key of one of the trust roots, or if the target
+/// certificate itself is one of the trust roots.
+///
+/// Only such direct (one-hop) certifications are considered. Certification paths through
+/// intermediate certificates, and trust signatures that delegate to other certifiers, are not
+/// supported.
+pub(crate) struct ValidateUserID {
+    trust_roots: Vec<Certificate>,
+    target_certs: Vec<Certificate>,
+    validate_at: Option<DateTime<Utc>>,
+}
+
+impl ValidateUserID {
+    pub(crate) fn new() -> Self {
+        Self {
+            trust_roots: Default::default(),
+            target_certs: Default::default(),
+            validate_at: None,
+        }
+    }
+}
+
+impl<'a> sop::ops::ValidateUserID<'a, RPGSOP, Certs> for ValidateUserID {
+    fn trust_roots(
+        mut self: Box<Self>,
+        certs: &Certs,
+    ) -> sop::Result<Box<dyn sop::ops::ValidateUserID<'a, RPGSOP, Certs> + 'a>> {
+        self.trust_roots.extend(certs.certs.iter().cloned());
+        Ok(self)
+    }
+
+    fn target_certs(
+        mut self: Box<Self>,
+        certs: &Certs,
+    ) -> sop::Result<Box<dyn sop::ops::ValidateUserID<'a, RPGSOP, Certs> + 'a>> {
+        self.target_certs.extend(certs.certs.iter().cloned());
+        Ok(self)
+    }
+
+    fn validate_at(
+        mut self: Box<Self>,
+        at: SystemTime,
+    ) -> sop::Result<Box<dyn sop::ops::ValidateUserID<'a, RPGSOP, Certs> + 'a>> {
+        self.validate_at = Some(at.into());
+        Ok(self)
+    }
+
+    fn userid(self: Box<Self>, userid: &str) -> sop::Result<()> {
+        self.validate(|uid| uid == userid)
+    }
+
+    fn email(self: Box<Self>, email: &str) -> sop::Result<()> {
+        self.validate(|uid| addr_spec(uid).is_some_and(|addr| addr.eq_ignore_ascii_case(email)))
+    }
+}
+
+impl ValidateUserID {
+    /// Check that every target certificate has a valid User ID for which `matches` returns true
+    fn validate(&self, matches: impl Fn(&str) -> bool) -> sop::Result<()> {
+        if self.trust_roots.is_empty() || self.target_certs.is_empty() {
+            return Err(sop::errors::Error::MissingArg);
+        }
+
+        let at = self.validate_at.unwrap_or_else(Utc::now);
+
+        // Only trust roots that are valid at the reference time can certify anything
+        let mut roots = vec![];
+        for root in &self.trust_roots {
+            if CheckedCertificate::from(root)
+                .primary_valid_at(&at)
+                .unwrap_or(false)
+            {
+                roots.push(crate::util::to_signed_public_key(root)?);
+            }
+        }
+
+        for target in &self.target_certs {
+            let checked = CheckedCertificate::from(target);
+            if !checked.primary_valid_at(&at).unwrap_or(false) {
+                return Err(sop::errors::Error::CertUseridNoMatch);
+            }
+
+            let spk = crate::util::to_signed_public_key(target)?;
+
+            let valid = checked
+                .user_ids()
+                .iter()
+                .filter(|u| matches(&String::from_utf8_lossy(u.id.id())))
+                .filter(|u| crate::util::user_id_bound_at(u, checked.primary_creation_time(), &at))
+                .any(|u| {
+                    roots.iter().any(|root| {
+                        root.fingerprint() == spk.fingerprint()
+                            || directly_certified(&spk, &u.id, root, &at)
+                    })
+                });
+
+            if !valid {
+                return Err(sop::errors::Error::CertUseridNoMatch);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Is `id` on `spk` directly certified by the primary key of `certifier` at the reference time
+/// `at`?
+///
+/// The latest valid certification or certification revocation by `certifier` determines the
+/// result.
+fn directly_certified(
+    spk: &SignedPublicKey,
+    id: &UserId,
+    certifier: &SignedPublicKey,
+    at: &DateTime<Utc>,
+) -> bool {
+    let Some(user) = spk.details.users.iter().find(|u| u.id == *id) else {
+        return false;
+    };
+
+    let latest = user
+        .signatures
+        .iter()
+        .filter(|s| acceptable_at(s, at))
+        .filter(|s| {
+            s.verify_third_party_certification(
+                &spk.primary_key,
+                &certifier.primary_key,
+                Tag::UserId,
+                id,
+            )
+            .is_ok()
+        })
+        .max_by_key(|s| s.created().cloned());
+
+    latest.is_some_and(|s| s.typ() != SignatureType::CertRevocation)
+}
+
+/// Is `sig` a certification (or certification revocation) that is in effect at `at`?
+fn acceptable_at(sig: &Signature, at: &DateTime<Utc>) -> bool {
+    // Note: this includes certification revocations
+    if !sig.is_certification() {
+        return false;
+    }
+
+    // We don't rely on third-party certifications that use broken hash algorithms
+    if matches!(sig.hash_alg(), HashAlgorithm::MD5 | HashAlgorithm::SHA1) {
+        return false;
+    }
+
+    let Some(created) = sig.created() else {
+        return false;
+    };
+
+    if created > at {
+        return false;
+    }
+
+    match sig.signature_expiration_time() {
+        Some(exp) if exp.num_seconds() != 0 => *created + *exp > *at,
+        _ => true,
+    }
+}
+
+/// Get the email address part of a User ID.
+///
+/// This is either the part in angle brackets (as in `Alice <alice@example.org>`), or the full
+/// User ID, if it consists of only an email address.
+fn addr_spec(user_id: &str) -> Option<&str> {
+    let addr = match (user_id.rfind('<'), user_id.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &user_id[start + 1..end],
+        (None, None) => user_id.trim(),
+        _ => return None,
+    };
+
+    if addr.contains('@') && !addr.contains(char::is_whitespace) {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_addr_spec() {
+    assert_eq!(
+        addr_spec("Alice <alice@example.org>"),
+        Some("alice@example.org")
+    );
+    assert_eq!(addr_spec("<alice@example.org>"), Some("alice@example.org"));
+    assert_eq!(addr_spec("alice@example.org"), Some("alice@example.org"));
+    assert_eq!(addr_spec("Alice"), None);
+    assert_eq!(addr_spec("Alice <alice>"), None);
+}
+
+#[cfg(test)]
+const DAY: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Generate a key with `user_id`, created at `created`, that expires after `validity`
+#[cfg(test)]
+fn generate(
+    user_id: &str,
+    created: SystemTime,
+    validity: Option<std::time::Duration>,
+    seed: u64,
+) -> pgp::SignedSecretKey {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rpgpie::key::Tsk;
+    use sop::ops::GenerateKey as _;
+
+    let mut generate =
+        Box::new(crate::GenerateKey::new()).deterministic(created, StdRng::seed_from_u64(seed));
+    if let Some(validity) = validity {
+        generate = generate.key_expiration(validity);
+    }
+
+    let keys = generate
+        .profile("draft-koch-eddsa-for-openpgp-00")
+        .unwrap()
+        .userid(user_id)
+        .generate()
+        .unwrap();
+
+    let Tsk::Tsk(ssk) = keys.keys.into_iter().next().unwrap() else {
+        unreachable!()
+    };
+    ssk
+}
+
+/// Add a signature of type `typ` by `certifier` over the first User ID of `target`, created at
+/// `created`
+#[cfg(test)]
+fn certify(
+    certifier: &pgp::SignedSecretKey,
+    target: &mut SignedPublicKey,
+    typ: SignatureType,
+    created: SystemTime,
+) {
+    use pgp::types::SecretKeyTrait;
+
+    let primary = &certifier.primary_key;
+
+    let sig = crate::util::signature_config(primary, typ, primary.hash_alg(), &created.into())
+        .unwrap()
+        .sign_certification_third_party(
+            primary,
+            String::new,
+            &target.primary_key,
+            Tag::UserId,
+            &target.details.users[0].id,
+        )
+        .unwrap();
+    target.details.users[0].signatures.push(sig);
+}
+
+#[cfg(test)]
+fn certs(spk: &SignedPublicKey) -> Certs {
+    Certs {
+        certs: vec![spk.clone().into()],
+        source_name: None,
+    }
+}
+
+/// Validate User IDs of `target` against `root`, at the reference time `at`
+#[cfg(test)]
+fn validator<'a>(
+    root: &SignedPublicKey,
+    target: &SignedPublicKey,
+    at: SystemTime,
+) -> Box<dyn sop::ops::ValidateUserID<'a, RPGSOP, Certs> + 'a> {
+    use sop::ops::ValidateUserID as _;
+
+    Box::new(ValidateUserID::new())
+        .trust_roots(&certs(root))
+        .unwrap()
+        .target_certs(&certs(target))
+        .unwrap()
+        .validate_at(at)
+        .unwrap()
+}
+
+#[test]
+fn test_validate_certified() {
+    let created = SystemTime::now() - 2 * DAY;
+    let root = generate("<ca@example.org>", created, None, 0);
+    let bob = generate("Bob <bob@example.org>", created, None, 1);
+
+    let mut target = SignedPublicKey::from(bob);
+    certify(
+        &root,
+        &mut target,
+        SignatureType::CertGeneric,
+        created + DAY,
+    );
+    let root = SignedPublicKey::from(root);
+
+    let now = SystemTime::now();
+    assert!(validator(&root, &target, now)
+        .userid("Bob <bob@example.org>")
+        .is_ok());
+    assert!(validator(&root, &target, now)
+        .email("BOB@example.org")
+        .is_ok());
+
+    // The certification didn't exist yet
+    assert!(matches!(
+        validator(&root, &target, created + DAY / 2).userid("Bob <bob@example.org>"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+
+    // User IDs that the target doesn't have
+    assert!(matches!(
+        validator(&root, &target, now).userid("<bob@example.org>"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+    assert!(matches!(
+        validator(&root, &target, now).email("carol@example.org"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+
+    // The trust root is valid for its own User IDs
+    assert!(validator(&root, &root, now)
+        .userid("<ca@example.org>")
+        .is_ok());
+}
+
+#[test]
+fn test_validate_uncertified() {
+    let created = SystemTime::now() - 2 * DAY;
+    let root = SignedPublicKey::from(generate("<ca@example.org>", created, None, 0));
+    let bob = generate("<bob@example.org>", created, None, 1);
+
+    // Bob certified his own User ID (and the trust root didn't)
+    let mut target = SignedPublicKey::from(bob.clone());
+    certify(&bob, &mut target, SignatureType::CertGeneric, created + DAY);
+
+    assert!(matches!(
+        validator(&root, &target, SystemTime::now()).userid("<bob@example.org>"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+}
+
+#[test]
+fn test_validate_revoked_certification() {
+    let created = SystemTime::now() - 3 * DAY;
+    let root = generate("<ca@example.org>", created, None, 0);
+    let bob = generate("<bob@example.org>", created, None, 1);
+
+    let mut target = SignedPublicKey::from(bob);
+    certify(
+        &root,
+        &mut target,
+        SignatureType::CertGeneric,
+        created + DAY,
+    );
+    certify(
+        &root,
+        &mut target,
+        SignatureType::CertRevocation,
+        created + 2 * DAY,
+    );
+    let root = SignedPublicKey::from(root);
+
+    // Before the revocation, the certification was in effect
+    assert!(validator(&root, &target, created + DAY * 3 / 2)
+        .userid("<bob@example.org>")
+        .is_ok());
+    assert!(matches!(
+        validator(&root, &target, SystemTime::now()).userid("<bob@example.org>"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+}
+
+#[test]
+fn test_validate_expired_root() {
+    let created = SystemTime::now() - 3 * DAY;
+    let root = generate("<ca@example.org>", created, Some(2 * DAY), 0);
+    let bob = generate("<bob@example.org>", created, None, 1);
+
+    let mut target = SignedPublicKey::from(bob);
+    certify(
+        &root,
+        &mut target,
+        SignatureType::CertGeneric,
+        created + DAY,
+    );
+    let root = SignedPublicKey::from(root);
+
+    // While the trust root was valid, it certified the User ID
+    assert!(validator(&root, &target, created + DAY * 3 / 2)
+        .userid("<bob@example.org>")
+        .is_ok());
+    assert!(matches!(
+        validator(&root, &target, SystemTime::now()).userid("<bob@example.org>"),
+        Err(sop::errors::Error::CertUseridNoMatch)
+    ));
+}
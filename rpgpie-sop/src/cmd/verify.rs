@@ -4,16 +4,18 @@
 use std::io;
 use std::time::SystemTime;
 
-use rpgpie::key::checked::CheckedCertificate;
 use rpgpie::key::Certificate;
 
-use crate::util::to_verification;
+use crate::stream::hash::DataHasher;
+use crate::stream::verify;
+use crate::stream::BUF_SIZE;
+use crate::util::{created_within, to_verification};
 use crate::{Certs, Sigs, RPGSOP};
 
 #[derive(Default)]
 pub(crate) struct Verify {
-    _not_before: Option<SystemTime>,
-    _not_after: Option<SystemTime>,
+    pub(crate) not_before: Option<SystemTime>,
+    pub(crate) not_after: Option<SystemTime>,
     pub(crate) certs: Vec<Certificate>,
 }
 
@@ -25,23 +27,19 @@ impl Verify {
 
 impl<'a> sop::ops::Verify<'a, RPGSOP, Certs, Sigs> for Verify {
     fn not_before(
-        self: Box<Self>,
-        _t: SystemTime,
+        mut self: Box<Self>,
+        t: SystemTime,
     ) -> Box<dyn sop::ops::Verify<'a, RPGSOP, Certs, Sigs> + 'a> {
-        todo!()
-
-        // self.not_before = Some(t);
-        // self
+        self.not_before = Some(t);
+        self
     }
 
     fn not_after(
-        self: Box<Self>,
-        _t: SystemTime,
+        mut self: Box<Self>,
+        t: SystemTime,
     ) -> Box<dyn sop::ops::Verify<'a, RPGSOP, Certs, Sigs> + 'a> {
-        todo!()
-
-        // self.not_after = Some(t);
-        // self
+        self.not_after = Some(t);
+        self
     }
 
     fn certs(
@@ -81,27 +79,43 @@ impl sop::ops::VerifySignatures<'_> for VerifySignatures<'_> {
             return Err(sop::errors::Error::MissingArg);
         }
 
+        // Only consider signatures that were created within the requested time window.
+        // Each of them gets a hasher for the data.
+        let mut hashers: Vec<_> = self
+            .signatures
+            .sigs
+            .iter()
+            .filter(|sig| created_within(sig, self.verify.not_before, self.verify.not_after))
+            .filter_map(|sig| match DataHasher::for_signature(sig) {
+                Ok(hasher) => Some((sig, hasher)),
+                Err(e) => {
+                    log::warn!("Can't hash data for signature: {e:?}");
+                    None
+                }
+            })
+            .collect();
+
+        let mut buf = vec![0; BUF_SIZE];
+        loop {
+            let n = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            hashers.iter_mut().for_each(|(_, h)| h.update(&buf[..n]));
+        }
+
         let mut verifications = vec![];
 
-        // FIXME: stream input data?
-        let mut payload = vec![];
-        data.read_to_end(&mut payload)?;
-
-        for sig in &self.signatures.sigs {
-            for cert in &self.verify.certs {
-                let ccert: CheckedCertificate = cert.into();
-
-                // Verify at signature creation time.
-                // FIXME: does the signature need to be valid "now", as well?
-                let reference = sig.created().expect("FIXME");
-
-                ccert
-                    .valid_signing_capable_component_keys_at(reference)
-                    .iter()
-                    .filter(|c| c.verify(sig, &payload).is_ok())
-                    .map(|v| to_verification(sig, cert, v.as_componentkey()))
-                    .for_each(|v| verifications.push(v));
-            }
+        for (sig, hasher) in hashers {
+            // Verify at signature creation time.
+            // FIXME: does the signature need to be valid "now", as well?
+            verify::signers(hasher, sig, &self.verify.certs)
+                .iter()
+                .filter_map(|(cert, key)| to_verification(sig, cert, key))
+                .for_each(|v| verifications.push(v));
         }
 
         if verifications.is_empty() {
@@ -111,3 +125,83 @@ impl sop::ops::VerifySignatures<'_> for VerifySignatures<'_> {
         }
     }
 }
+
+#[test]
+fn test_verify_time_window() {
+    use std::time::Duration;
+
+    use pgp::packet::SignatureType;
+    use pgp::types::SecretKeyTrait;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rpgpie::key::Tsk;
+    use sop::ops::{GenerateKey as _, Verify as _};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    let data = b"hello world";
+    let created = SystemTime::now() - 2 * DAY;
+
+    let keys = Box::new(crate::GenerateKey::new())
+        .deterministic(created, StdRng::seed_from_u64(0))
+        .userid("<alice@example.org>")
+        .generate()
+        .unwrap();
+    let cert = Certs {
+        certs: keys.keys.iter().map(Certificate::from).collect(),
+        source_name: None,
+    };
+    let Tsk::Tsk(ssk) = &keys.keys[0] else {
+        unreachable!()
+    };
+    let signer = &ssk.primary_key;
+
+    // Signatures over `data`, one from yesterday and one from now
+    let sign = |at: SystemTime| {
+        crate::util::signature_config(signer, SignatureType::Binary, signer.hash_alg(), &at.into())
+            .unwrap()
+            .sign(signer, String::new, &data[..])
+            .unwrap()
+    };
+    let now = SystemTime::now();
+    let sigs = Sigs {
+        sigs: vec![sign(created + DAY), sign(now)],
+        source_name: None,
+    };
+
+    let verify = |not_before: Option<SystemTime>, not_after: Option<SystemTime>| {
+        let mut verify = Box::new(Verify::new()).certs(&cert).unwrap();
+        if let Some(t) = not_before {
+            verify = verify.not_before(t);
+        }
+        if let Some(t) = not_after {
+            verify = verify.not_after(t);
+        }
+
+        verify.signatures(&sigs).unwrap().data(&mut &data[..])
+    };
+
+    assert_eq!(verify(None, None).unwrap().len(), 2);
+
+    // Only one of the signatures was made within each of these windows
+    for (not_before, not_after) in [
+        (Some(now - HOUR), None),
+        (None, Some(now - HOUR)),
+        (Some(created), Some(created + DAY + HOUR)),
+    ] {
+        assert_eq!(verify(not_before, not_after).unwrap().len(), 1);
+    }
+
+    // All signatures are outside of these windows
+    for (not_before, not_after) in [
+        (Some(now + HOUR), None),
+        (None, Some(created)),
+        (Some(created + DAY + HOUR), Some(now - HOUR)),
+    ] {
+        assert!(matches!(
+            verify(not_before, not_after),
+            Err(sop::errors::Error::NoSignature)
+        ));
+    }
+}
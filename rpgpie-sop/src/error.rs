@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Mapping of rPGP and rpgpie errors to SOP errors.
+//!
+//! Where the context of an operation determines the appropriate SOP error (e.g. a failure to
+//! unlock a key, or to decrypt a message), the commands map errors explicitly. The functions in
+//! this module handle all remaining cases.
+
+use std::io;
+
+use sop::errors::Error;
+
+use crate::stream::text::NotText;
+
+/// Map an rPGP error to the closest SOP error
+pub(crate) fn rpgp(e: pgp::errors::Error) -> Error {
+    use pgp::errors::Error as E;
+
+    log::warn!("rPGP error: {e:?}");
+
+    match e {
+        E::IOError { source, .. } => self::io(source),
+
+        // Failures of integrity protection: the encrypted data is corrupt
+        E::MdcError | E::Gcm | E::Eax | E::Ocb => Error::BadData,
+
+        // Failures to unwrap a session key
+        E::AesKek(_) | E::UnpadError => Error::CannotDecrypt,
+
+        // Public key algorithms (and curves) that rPGP can't handle. Other unsupported input,
+        // such as compression algorithms, packet versions or S2K types, is unusable data.
+        E::Unimplemented(msg) | E::Unsupported(msg) if asymmetric_algorithm(&msg) => {
+            Error::UnsupportedAsymmetricAlgo
+        }
+
+        // Everything else signals malformed or unusable input data
+        _ => Error::BadData,
+    }
+}
+
+/// Does the message of an "unsupported" or "unimplemented" rPGP error refer to a public key
+/// algorithm?
+///
+/// rPGP doesn't signal this in a structured way, so we recognize its messages.
+fn asymmetric_algorithm(msg: &str) -> bool {
+    const MARKERS: &[&str] = &[
+        "curve",
+        "Elgamal",
+        "ECDH",
+        "ECDSA",
+        "EdDSA",
+        "PublicParams::Unknown",
+        "Invalid algorithm",
+    ];
+
+    MARKERS.iter().any(|m| msg.contains(m))
+}
+
+/// Map an IO error to the closest SOP error.
+///
+/// Readers that process OpenPGP data in a stream wrap rPGP errors in IO errors, those are mapped
+/// like the original rPGP error. Running out of input in the middle of a structure means that the
+/// input is truncated, and input that should be text but isn't UTF-8 is rejected as such.
+pub(crate) fn io(e: io::Error) -> Error {
+    match e.downcast::<pgp::errors::Error>() {
+        Ok(e) => rpgp(e),
+        Err(e) if e.get_ref().is_some_and(|e| e.is::<NotText>()) => Error::ExpectedText,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Error::BadData,
+        Err(e) => Error::IoError(e),
+    }
+}
+
+/// Map an rpgpie error to the closest SOP error
+pub(crate) fn rpgpie(e: rpgpie::Error) -> Error {
+    match e {
+        rpgpie::Error::Rpgp(e) => rpgp(e),
+        rpgpie::Error::Io(e) => io(e),
+        rpgpie::Error::NoPrimaryBinding => Error::BadData,
+        e => {
+            log::warn!("rpgpie error: {e:?}");
+            Error::UnspecifiedFailure
+        }
+    }
+}
+
+/// Map a failure to read (or unpack) OpenPGP data from an input.
+///
+/// Apart from IO errors, any such failure means that the input is malformed (or of the wrong
+/// type).
+pub(crate) fn load(e: rpgpie::Error) -> Error {
+    match rpgpie(e) {
+        Error::IoError(e) => Error::IoError(e),
+        _ => Error::BadData,
+    }
+}
+
+#[test]
+fn test_rpgp() {
+    assert!(matches!(
+        rpgp(pgp::errors::Error::InvalidArmorWrappers),
+        Error::BadData
+    ));
+    assert!(matches!(rpgp(pgp::errors::Error::MdcError), Error::BadData));
+    assert!(matches!(
+        rpgp(pgp::errors::Error::UnpadError),
+        Error::CannotDecrypt
+    ));
+    assert!(matches!(
+        rpgp(pgp::errors::Error::Unsupported(
+            "curve \"brainpoolP256r1\" for ECDSA".to_string()
+        )),
+        Error::UnsupportedAsymmetricAlgo
+    ));
+    assert!(matches!(
+        rpgp(pgp::errors::Error::Unimplemented("Elgamal".to_string())),
+        Error::UnsupportedAsymmetricAlgo
+    ));
+    assert!(matches!(
+        rpgp(pgp::errors::Error::Unsupported(
+            "CompressionAlgorithm 110 is unsupported".to_string()
+        )),
+        Error::BadData
+    ));
+    assert!(matches!(
+        rpgp(pgp::errors::Error::Unimplemented(
+            "SymmetricKeyAlgorithm 100 is unsupported".to_string()
+        )),
+        Error::BadData
+    ));
+    assert!(matches!(
+        rpgpie(rpgpie::Error::Io(std::io::Error::other("foo"))),
+        Error::IoError(_)
+    ));
+    assert!(matches!(
+        io(std::io::Error::other(pgp::errors::Error::MdcError)),
+        Error::BadData
+    ));
+    assert!(matches!(
+        io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            NotText
+        )),
+        Error::ExpectedText
+    ));
+    assert!(matches!(
+        load(rpgpie::Error::Message("No certificates found".to_string())),
+        Error::BadData
+    ));
+}
@@ -2,25 +2,142 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 mod cmd;
+mod error;
+mod stream;
 mod util;
 
 use std::io;
+use std::time::Duration;
 
+use pgp::packet::RevocationCode;
+use pgp::types::Fingerprint;
 use pgp::Signature;
 use rpgpie::key::{Certificate, Tsk};
-use sop::ops::{CertifyUserID, MergeCerts, UpdateKey, ValidateUserID};
+use sop::ops::{MergeCerts, UpdateKey, ValidateUserID};
+
+pub use crate::cmd::certify_userid::CertifyUserID;
+pub use crate::cmd::decrypt::{DecryptError, DecryptFailure, DecryptMany, Decrypted};
+pub use crate::cmd::generate::{GenerateKey, KeyLayout, SubkeySpec};
+pub use crate::cmd::password::{ChangeKeyPassword, S2kProfile};
+pub use crate::cmd::revoke_component::RevokeComponent;
+pub use crate::cmd::revoke_key::RevokeKey;
 
 #[derive(Clone, Copy, Default)]
-pub struct RPGSOP {}
+pub struct RPGSOP {
+    key_expiration: Option<Duration>,
+    subkey_expiration: Option<Duration>,
+    s2k_profile: Option<S2kProfile>,
+}
 
 // SOP singleton
-const SOP: RPGSOP = RPGSOP {};
+const SOP: RPGSOP = RPGSOP {
+    key_expiration: None,
+    subkey_expiration: None,
+    s2k_profile: None,
+};
+
+impl RPGSOP {
+    /// Let keys from the SOP generate-key operation expire after `validity`
+    pub fn with_key_expiration(mut self, validity: Duration) -> Self {
+        self.key_expiration = Some(validity);
+        self
+    }
+
+    /// Let subkeys from the SOP generate-key operation expire after `validity`
+    pub fn with_subkey_expiration(mut self, validity: Duration) -> Self {
+        self.subkey_expiration = Some(validity);
+        self
+    }
+
+    /// Protect keys from the SOP generate-key and change-key-password operations (and subkeys
+    /// that update-key adds) with their password as described by `profile`
+    pub fn with_s2k_profile(mut self, profile: S2kProfile) -> Self {
+        self.s2k_profile = Some(profile);
+        self
+    }
+
+    /// Generate a key (the SOP generate-key operation, with extensions)
+    pub fn key_generator(&self) -> Box<GenerateKey> {
+        let mut generate = Box::new(GenerateKey::new());
+
+        if let Some(validity) = self.key_expiration {
+            generate = generate.key_expiration(validity);
+        }
+        if let Some(validity) = self.subkey_expiration {
+            generate = generate.subkey_expiration(validity);
+        }
+        if let Some(profile) = self.s2k_profile {
+            generate = generate.s2k_profile(profile);
+        }
+
+        generate
+    }
+
+    /// Change the password of keys (the SOP change-key-password operation, with extensions)
+    pub fn password_changer(&self) -> Box<ChangeKeyPassword> {
+        let mut change = Box::new(ChangeKeyPassword::new());
+
+        if let Some(profile) = self.s2k_profile {
+            change = change.s2k_profile(profile);
+        }
+
+        change
+    }
+
+    /// Certify User IDs (the SOP certify-userid operation, with extensions)
+    pub fn certifier(&self) -> Box<CertifyUserID> {
+        Box::new(CertifyUserID::new())
+    }
+
+    /// Revoke a key (the SOP revoke-key operation, with extensions)
+    pub fn key_revoker(&self) -> Box<RevokeKey> {
+        Box::new(RevokeKey::new())
+    }
+
+    /// Revoke the subkey with `fingerprint` of a key, which otherwise stays valid
+    pub fn subkey_revoker(&self, fingerprint: Fingerprint) -> Box<RevokeComponent> {
+        Box::new(RevokeComponent::subkey(fingerprint))
+    }
+
+    /// Revoke the User ID `user_id` of a key, which otherwise stays valid
+    pub fn user_id_revoker(&self, user_id: &str) -> Box<RevokeComponent> {
+        Box::new(RevokeComponent::user_id(user_id))
+    }
+
+    /// Decrypt a sequence of concatenated messages (an extension of the SOP decrypt operation)
+    pub fn decrypt_many(&self) -> DecryptMany {
+        DecryptMany::new()
+    }
+}
 
 pub struct Certs {
     certs: Vec<Certificate>,
     source_name: Option<String>,
 }
 
+impl Certs {
+    /// Describe the revocations of the primary keys of these certificates, in a line of text
+    /// each (e.g. "key compromised (Laptop stolen)")
+    pub fn revocations(&self) -> sop::Result<Vec<String>> {
+        let mut revocations = vec![];
+
+        for cert in &self.certs {
+            let spk = util::to_signed_public_key(cert)?;
+
+            revocations.extend(spk.details.revocation_signatures.iter().map(|sig| {
+                let reason = sig
+                    .revocation_reason_code()
+                    .unwrap_or(&RevocationCode::NoReason);
+                let message = sig.revocation_reason_string().unwrap_or_default();
+
+                cmd::revoke_key::describe_revocation(reason, message)
+            }));
+        }
+
+        Ok(revocations)
+    }
+}
+
 pub struct Keys {
     keys: Vec<Tsk>,
     source_name: Option<String>,
@@ -36,7 +153,7 @@ impl sop::SOP<'_> for RPGSOP {
     type Certs = Certs;
     type Sigs = Sigs;
 
-    fn version(&'_ self) -> sop::Result<Box<dyn sop::ops::Version>> {
+    fn version(&'_ self) -> sop::Result<Box<dyn sop::ops::Version<'_>>> {
         Ok(Box::new(cmd::version::Version::new()))
     }
 
@@ -46,88 +163,102 @@ impl sop::SOP<'_> for RPGSOP {
 
     fn generate_key(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::GenerateKey<Self, Self::Keys> + '_>> {
-        Ok(Box::new(cmd::generate::GenerateKey::new()))
+    ) -> sop::Result<Box<dyn sop::ops::GenerateKey<'_, Self, Self::Keys> + '_>> {
+        Ok(self.key_generator())
     }
 
     fn change_key_password(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::ChangeKeyPassword<Self, Self::Keys>>> {
-        Ok(Box::new(cmd::password::ChangeKeyPassword::new()))
+    ) -> sop::Result<Box<dyn sop::ops::ChangeKeyPassword<'_, Self, Self::Keys>>> {
+        Ok(self.password_changer())
     }
 
     fn revoke_key(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::RevokeKey<Self, Self::Certs, Self::Keys>>> {
-        Ok(Box::new(cmd::revoke_key::RevokeKey::new()))
+    ) -> sop::Result<Box<dyn sop::ops::RevokeKey<'_, Self, Self::Certs, Self::Keys>>> {
+        Ok(self.key_revoker())
     }
 
     fn extract_cert(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::ExtractCert<Self, Self::Certs, Self::Keys> + '_>> {
+    ) -> sop::Result<Box<dyn sop::ops::ExtractCert<'_, Self, Self::Certs, Self::Keys> + '_>> {
         Ok(Box::new(cmd::extract_cert::ExtractCert::new()))
     }
 
-    fn sign(&'_ self) -> sop::Result<Box<dyn sop::ops::Sign<Self, Self::Keys, Self::Sigs> + '_>> {
+    fn sign(
+        &'_ self,
+    ) -> sop::Result<Box<dyn sop::ops::Sign<'_, Self, Self::Keys, Self::Sigs> + '_>> {
         Ok(Box::new(cmd::sign::Sign::new()))
     }
 
     fn verify(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::Verify<Self, Self::Certs, Self::Sigs> + '_>> {
+    ) -> sop::Result<Box<dyn sop::ops::Verify<'_, Self, Self::Certs, Self::Sigs> + '_>> {
         Ok(Box::new(cmd::verify::Verify::new()))
     }
 
     fn encrypt(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::Encrypt<Self, Self::Certs, Self::Keys> + '_>> {
+    ) -> sop::Result<Box<dyn sop::ops::Encrypt<'_, Self, Self::Certs, Self::Keys> + '_>> {
         Ok(Box::new(cmd::encrypt::Encrypt::new()))
     }
 
     fn decrypt(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::Decrypt<Self, Self::Certs, Self::Keys> + '_>> {
+    ) -> sop::Result<Box<dyn sop::ops::Decrypt<'_, Self, Self::Certs, Self::Keys> + '_>> {
         Ok(Box::new(cmd::decrypt::Decrypt::new()))
     }
 
-    fn armor(&'_ self) -> sop::Result<Box<dyn sop::ops::Armor>> {
+    fn armor(&'_ self) -> sop::Result<Box<dyn sop::ops::Armor<'_>>> {
         Ok(Box::new(cmd::armor::Armor::new()))
     }
 
-    fn dearmor(&'_ self) -> sop::Result<Box<dyn sop::ops::Dearmor>> {
+    fn dearmor(&'_ self) -> sop::Result<Box<dyn sop::ops::Dearmor<'_>>> {
         Ok(Box::new(cmd::dearmor::Dearmor::new()))
     }
 
-    fn inline_detach(&'_ self) -> sop::Result<Box<dyn sop::ops::InlineDetach<Self::Sigs>>> {
+    fn inline_detach(&'_ self) -> sop::Result<Box<dyn sop::ops::InlineDetach<'_, Self::Sigs>>> {
         Ok(Box::new(cmd::detach::InlineDetach::new()))
     }
 
     fn inline_verify(
         &'_ self,
-    ) -> sop::Result<Box<dyn sop::ops::InlineVerify<Self, Self::Certs> + '_>> {
+    ) -> sop::Result<Box<dyn sop::ops::InlineVerify<'_, Self, Self::Certs> + '_>> {
         Ok(Box::new(cmd::inline_verify::InlineVerify::new()))
     }
 
-    fn inline_sign(&'_ self) -> sop::Result<Box<dyn sop::ops::InlineSign<Self, Self::Keys> + '_>> {
+    fn inline_sign(
+        &'_ self,
+    ) -> sop::Result<Box<dyn sop::ops::InlineSign<'_, Self, Self::Keys> + '_>> {
         Ok(Box::new(cmd::inline_sign::InlineSign::new()))
     }
 
-    fn update_key(&'_ self) -> sop::Result<Box<dyn UpdateKey<Self, Self::Certs, Self::Keys> + '_>> {
-        todo!()
+    fn update_key(
+        &'_ self,
+    ) -> sop::Result<Box<dyn UpdateKey<'_, Self, Self::Certs, Self::Keys> + '_>> {
+        let mut update = cmd::update_key::UpdateKey::new();
+
+        if let Some(profile) = self.s2k_profile {
+            update = update.s2k_profile(profile);
+        }
+
+        Ok(Box::new(update))
     }
 
-    fn merge_certs(&'_ self) -> sop::Result<Box<dyn MergeCerts<Self, Self::Certs> + '_>> {
-        todo!()
+    fn merge_certs(&'_ self) -> sop::Result<Box<dyn MergeCerts<'_, Self, Self::Certs> + '_>> {
+        Ok(Box::new(cmd::merge_certs::MergeCerts::new()))
     }
 
     fn certify_userid(
         &'_ self,
-    ) -> sop::Result<Box<dyn CertifyUserID<Self, Self::Certs, Self::Keys> + '_>> {
-        todo!()
+    ) -> sop::Result<Box<dyn sop::ops::CertifyUserID<'_, Self, Self::Certs, Self::Keys> + '_>> {
+        Ok(self.certifier())
     }
 
-    fn validate_userid(&'_ self) -> sop::Result<Box<dyn ValidateUserID<Self, Self::Certs> + '_>> {
-        todo!()
+    fn validate_userid(
+        &'_ self,
+    ) -> sop::Result<Box<dyn ValidateUserID<'_, Self, Self::Certs> + '_>> {
+        Ok(Box::new(cmd::validate_userid::ValidateUserID::new()))
     }
 }
 
@@ -137,7 +268,7 @@ impl sop::Load<'_, RPGSOP> for Certs {
         mut source: &mut (dyn io::Read + Send + Sync),
         source_name: Option<String>,
     ) -> sop::Result<Self> {
-        let certs = Certificate::load(&mut source).expect("FIXME");
+        let certs = Certificate::load(&mut source).map_err(error::load)?;
 
         Ok(Certs { certs, source_name })
     }
@@ -153,7 +284,7 @@ impl sop::Save for Certs {
         armored: bool,
         sink: &mut (dyn io::Write + Send + Sync),
     ) -> sop::Result<()> {
-        Certificate::save(&self.certs, armored, sink).expect("FIXME");
+        Certificate::save(&self.certs, armored, sink).map_err(error::rpgpie)?;
 
         Ok(())
     }
@@ -165,7 +296,7 @@ impl sop::Load<'_, RPGSOP> for Keys {
         mut source: &mut (dyn io::Read + Send + Sync),
         source_name: Option<String>,
     ) -> sop::Result<Self> {
-        let keys = Tsk::load(&mut source).expect("FIXME");
+        let keys = Tsk::load(&mut source).map_err(error::load)?;
 
         Ok(Keys { keys, source_name })
     }
@@ -181,7 +312,7 @@ impl sop::Save for Keys {
         armored: bool,
         sink: &mut (dyn io::Write + Send + Sync),
     ) -> sop::Result<()> {
-        Tsk::save(&self.keys, armored, sink).expect("FIXME");
+        Tsk::save(&self.keys, armored, sink).map_err(error::rpgpie)?;
 
         Ok(())
     }
@@ -193,7 +324,12 @@ impl sop::Load<'_, RPGSOP> for Sigs {
         mut source: &mut (dyn io::Read + Send + Sync),
         source_name: Option<String>,
     ) -> sop::Result<Self> {
-        let sigs = rpgpie::sig::load(&mut source).expect("FIXME");
+        let sigs = rpgpie::sig::load(&mut source).map_err(error::load)?;
+
+        // rpgpie skips over packets that it can't parse as signatures
+        if sigs.is_empty() {
+            return Err(sop::errors::Error::BadData);
+        }
 
         Ok(Sigs { sigs, source_name })
     }
@@ -209,7 +345,7 @@ impl sop::Save for Sigs {
         armored: bool,
         mut sink: &mut (dyn io::Write + Send + Sync),
     ) -> sop::Result<()> {
-        rpgpie::sig::save(&self.sigs, armored, &mut sink).expect("FIXME");
+        rpgpie::sig::save(&self.sigs, armored, &mut sink).map_err(error::rpgpie)?;
 
         Ok(())
     }
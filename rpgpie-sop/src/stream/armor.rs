@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Transparent removal and addition of ASCII armor for a stream
+
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, Chain, Cursor, Read, Write};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crc24::Crc24Hasher;
+use pgp::armor::{BlockType, Dearmor};
+
+/// How much of the input we look at up front.
+///
+/// The armor header lines need to be available in one piece, even when the input arrives in
+/// small pieces (e.g. from a pipe).
+const HEAD_LEN: u64 = 4096;
+
+/// Get a buffered reader for `source`, which makes the start of the input available in one piece
+/// to parsers that look at its buffer (such as the armor parser of rPGP)
+pub(crate) fn buffered<R: Read>(mut source: R) -> io::Result<BufReader<Chain<Cursor<Vec<u8>>, R>>> {
+    let mut head = vec![];
+    source.by_ref().take(HEAD_LEN).read_to_end(&mut head)?;
+
+    Ok(BufReader::new(Cursor::new(head).chain(source)))
+}
+
+/// Get a reader for the binary OpenPGP data in `source`, which may be ASCII armored
+pub(crate) fn dearmor<'a>(source: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = buffered(source)?;
+
+    let binary = reader.fill_buf()?.first().is_none_or(|b| b & 0x80 != 0);
+
+    match binary {
+        true => Ok(Box::new(reader)),
+        false => Ok(Box::new(ArmorReader(Dearmor::new(reader)))),
+    }
+}
+
+/// Reports failures to read armor as malformed data
+struct ArmorReader<R: BufRead>(Dearmor<R>);
+
+impl<R: BufRead> Read for ArmorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| {
+            log::warn!("Reading armor failed: {e:?}");
+            io::Error::other(pgp::errors::Error::InvalidArmorWrappers)
+        })
+    }
+}
+
+/// Number of bytes of data that make up one line of armor (64 characters of base64)
+const LINE_LEN: usize = 48;
+
+/// Adds ASCII armor (with a checksum, like rPGP does) to the binary OpenPGP data written to it.
+///
+/// The armor is only complete after [`ArmorWriter::finish`].
+pub(crate) struct ArmorWriter<W: Write> {
+    inner: W,
+    typ: BlockType,
+    crc: Crc24Hasher,
+
+    // Data that doesn't fill a line yet
+    line: Vec<u8>,
+}
+
+impl<W: Write> ArmorWriter<W> {
+    /// Write the armor header for a block of type `typ` to `inner`
+    pub(crate) fn new(mut inner: W, typ: BlockType) -> io::Result<Self> {
+        write!(inner, "-----BEGIN {typ}-----\n\n")?;
+
+        Ok(Self {
+            inner,
+            typ,
+            crc: Crc24Hasher::new(),
+            line: Vec::with_capacity(LINE_LEN),
+        })
+    }
+
+    /// Write the last line of data, the checksum and the armor footer
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if !self.line.is_empty() {
+            writeln!(self.inner, "{}", STANDARD.encode(&self.line))?;
+        }
+
+        let crc = (self.crc.finish() as u32).to_be_bytes();
+        writeln!(self.inner, "={}", STANDARD.encode(&crc[1..]))?;
+
+        writeln!(self.inner, "-----END {}-----", self.typ)?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.crc.write(data);
+
+        let mut rest = data;
+        let mut lines = String::new();
+
+        // Complete the pending line
+        if !self.line.is_empty() {
+            let n = rest.len().min(LINE_LEN - self.line.len());
+            self.line.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+
+            if self.line.len() == LINE_LEN {
+                STANDARD.encode_string(&self.line, &mut lines);
+                lines.push('\n');
+                self.line.clear();
+            }
+        }
+
+        let mut chunks = rest.chunks_exact(LINE_LEN);
+        for chunk in chunks.by_ref() {
+            STANDARD.encode_string(chunk, &mut lines);
+            lines.push('\n');
+        }
+        self.line.extend_from_slice(chunks.remainder());
+
+        self.inner.write_all(lines.as_bytes())?;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_armor_writer() {
+    struct Binary<'a>(&'a [u8]);
+
+    impl pgp::ser::Serialize for Binary<'_> {
+        fn to_writer<W: Write>(&self, w: &mut W) -> pgp::errors::Result<()> {
+            w.write_all(self.0)?;
+            Ok(())
+        }
+    }
+
+    for len in [0, 1, 47, 48, 49, 96, 1000] {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+        let mut armor = ArmorWriter::new(vec![], BlockType::Message).unwrap();
+        for piece in data.chunks(7) {
+            armor.write_all(piece).unwrap();
+        }
+        let armored = armor.finish().unwrap();
+
+        // The same armor as rPGP produces
+        let mut expected = vec![];
+        pgp::armor::write(
+            &Binary(&data),
+            BlockType::Message,
+            &mut expected,
+            None,
+            true,
+        )
+        .unwrap();
+        assert_eq!(armored, expected, "{len}");
+
+        let mut dearmored = vec![];
+        dearmor(&armored[..])
+            .unwrap()
+            .read_to_end(&mut dearmored)
+            .unwrap();
+        assert_eq!(dearmored, data, "{len}");
+    }
+}
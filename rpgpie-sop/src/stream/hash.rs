@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Hashing of streamed data for data signatures
+
+use std::convert::Infallible;
+
+use pgp::crypto::hash::{HashAlgorithm, Hasher};
+use pgp::packet::{SignatureConfig, SignatureType, SignatureVersionSpecific};
+use pgp::Signature;
+
+use crate::stream::text::Crlf;
+
+/// Hashes streamed data for a data signature.
+///
+/// For signatures of type [SignatureType::Text], line endings are normalized to CR LF on the fly.
+pub(crate) struct DataHasher {
+    typ: SignatureType,
+    hash_alg: HashAlgorithm,
+    salt: Option<Vec<u8>>,
+    hasher: Box<dyn Hasher>,
+    crlf: Crlf,
+}
+
+impl DataHasher {
+    /// Set up a hasher for signatures of type `typ`, using `hash_alg` (and `salt`, for version 6
+    /// signatures)
+    pub(crate) fn new(
+        typ: SignatureType,
+        hash_alg: HashAlgorithm,
+        salt: Option<&[u8]>,
+    ) -> pgp::errors::Result<Self> {
+        let mut hasher = hash_alg.new_hasher()?;
+        if let Some(salt) = salt {
+            hasher.update(salt);
+        }
+
+        Ok(Self {
+            typ,
+            hash_alg,
+            salt: salt.map(<[u8]>::to_vec),
+            hasher,
+            crlf: Crlf::new(),
+        })
+    }
+
+    /// Set up a hasher for the data that `sig` is made over
+    pub(crate) fn for_signature(sig: &Signature) -> pgp::errors::Result<Self> {
+        let salt = match &sig.config.version_specific {
+            SignatureVersionSpecific::V6 { salt } => Some(&salt[..]),
+            _ => None,
+        };
+
+        Self::new(sig.typ(), sig.config.hash_alg, salt)
+    }
+
+    /// Add signed data
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        if self.typ != SignatureType::Text {
+            self.hasher.update(data);
+            return;
+        }
+
+        let hasher = &mut self.hasher;
+        let Ok(()) = self.crlf.update(data, |d| -> Result<(), Infallible> {
+            hasher.update(d);
+            Ok(())
+        });
+    }
+
+    /// Finish hashing for a signature with `config`, and return the digest
+    pub(crate) fn finish(mut self, config: &SignatureConfig) -> pgp::errors::Result<Vec<u8>> {
+        self.finish_data();
+
+        let len = config.hash_signature_data(&mut self.hasher)?;
+        self.hasher.update(&config.trailer(len)?);
+
+        Ok(self.hasher.finish())
+    }
+
+    /// Complete the hashed data, after the last call to [Self::update]
+    fn finish_data(&mut self) {
+        let hasher = &mut self.hasher;
+        let crlf = std::mem::take(&mut self.crlf);
+        let Ok(()) = crlf.finish(|d| -> Result<(), Infallible> {
+            hasher.update(d);
+            Ok(())
+        });
+    }
+
+    /// The signature type that this hasher prepares data for
+    pub(crate) fn typ(&self) -> SignatureType {
+        self.typ
+    }
+
+    /// The hash algorithm of this hasher
+    pub(crate) fn hash_alg(&self) -> HashAlgorithm {
+        self.hash_alg
+    }
+
+    /// The salt of this hasher (for version 6 signatures)
+    pub(crate) fn salt(&self) -> Option<&[u8]> {
+        self.salt.as_deref()
+    }
+}
+
+#[test]
+fn test_text_normalization() {
+    fn digest(typ: SignatureType, input: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = DataHasher::new(typ, HashAlgorithm::SHA2_256, None).unwrap();
+        input.iter().for_each(|i| hasher.update(i));
+
+        // Finishing without signature metadata leaves only the (normalized) data in the hash
+        hasher.finish_data();
+        hasher.hasher.finish()
+    }
+
+    for (input, expected) in [
+        (&[&b"a\nb"[..]][..], &b"a\r\nb"[..]),
+        (&[b"a\r\nb"], b"a\r\nb"),
+        (&[b"a\r", b"\nb"], b"a\r\nb"),
+        (&[b"a\r", b"b\r"], b"a\r\nb\r\n"),
+        (&[b"\n\n", b"\r"], b"\r\n\r\n\r\n"),
+    ] {
+        assert_eq!(
+            digest(SignatureType::Text, input),
+            digest(SignatureType::Binary, &[expected]),
+            "{input:?}"
+        );
+    }
+
+    assert_ne!(
+        digest(SignatureType::Text, &[b"a\nb"]),
+        digest(SignatureType::Binary, &[b"a\nb"])
+    );
+}
@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Processing of (decrypted) OpenPGP messages in a stream
+
+use std::io::{self, Read, Write};
+
+use flate2::read::{DeflateDecoder, ZlibDecoder};
+use pgp::packet::{OpsVersionSpecific, Packet};
+use pgp::types::Tag;
+use pgp::Signature;
+use rpgpie::key::component::ComponentKeyPub;
+use rpgpie::key::Certificate;
+use rpgpie::policy::MAX_RECURSION;
+
+use crate::stream::hash::DataHasher;
+use crate::stream::packet::{self, BodyReader};
+use crate::stream::verify;
+use crate::stream::BUF_SIZE;
+
+/// A valid signature, and the certificate and component key it was issued by
+pub(crate) type Validated = (Certificate, ComponentKeyPub, Signature);
+
+/// Write the literal data of the message in `source` to `sink`, while verifying its signatures
+/// against `certs`.
+///
+/// The message may be compressed and signed (with one pass signatures, or with signatures that
+/// precede the signed data). Returns the valid signatures, innermost first.
+pub(crate) fn process(
+    source: &mut dyn Read,
+    certs: &[Certificate],
+    sink: &mut dyn Write,
+) -> pgp::errors::Result<Vec<Validated>> {
+    let mut processor = Processor {
+        certs,
+        sink,
+        one_pass: vec![],
+        prefixed: vec![],
+        validated: vec![],
+        literal: false,
+    };
+
+    processor.packets(source, 0)?;
+    processor.finish()
+}
+
+struct Processor<'a> {
+    certs: &'a [Certificate],
+    sink: &'a mut dyn Write,
+
+    // Hashers for the one pass signatures whose signature packets we haven't seen yet
+    one_pass: Vec<DataHasher>,
+
+    // Signatures that precede the signed data, and their hashers
+    prefixed: Vec<(Signature, DataHasher)>,
+
+    validated: Vec<Validated>,
+
+    // Have we seen the literal data packet?
+    literal: bool,
+}
+
+impl Processor<'_> {
+    /// Process a sequence of packets from `reader`
+    fn packets(&mut self, reader: &mut dyn Read, depth: usize) -> pgp::errors::Result<()> {
+        if depth > MAX_RECURSION {
+            return Err(pgp::errors::Error::Message(
+                "Excessive message nesting depth".to_string(),
+            ));
+        }
+
+        while let Some(header) = packet::read_header(reader)? {
+            let mut body = BodyReader::new(&mut *reader, &header);
+
+            match header.tag {
+                Tag::OnePassSignature => {
+                    let Packet::OnePassSignature(ops) =
+                        packet::parse(header.tag, &body.read_to_vec()?)?
+                    else {
+                        return Err(pgp::errors::Error::Message(
+                            "Malformed one pass signature".to_string(),
+                        ));
+                    };
+
+                    let salt = match &ops.version_specific {
+                        OpsVersionSpecific::V6 { salt, .. } => Some(&salt[..]),
+                        OpsVersionSpecific::V3 { .. } => None,
+                    };
+
+                    self.one_pass
+                        .push(DataHasher::new(ops.typ, ops.hash_algorithm, salt)?);
+                }
+                Tag::Signature => {
+                    let Packet::Signature(sig) = packet::parse(header.tag, &body.read_to_vec()?)?
+                    else {
+                        return Err(pgp::errors::Error::Message(
+                            "Malformed signature".to_string(),
+                        ));
+                    };
+
+                    if !self.literal {
+                        let hasher = DataHasher::for_signature(&sig)?;
+                        self.prefixed.push((sig, hasher));
+                    } else {
+                        // Signatures that follow the data close the innermost one pass signature
+                        let Some(hasher) = self.one_pass.pop() else {
+                            return Err(pgp::errors::Error::Message(
+                                "Signature without one pass signature".to_string(),
+                            ));
+                        };
+                        self.verify(hasher, sig);
+                    }
+                }
+                Tag::CompressedData => {
+                    let mut alg = [0u8];
+                    body.read_exact(&mut alg)?;
+
+                    match alg[0] {
+                        0 => self.packets(&mut body, depth + 1)?,
+                        1 => self.packets(&mut DeflateDecoder::new(&mut body), depth + 1)?,
+                        2 => self.packets(&mut ZlibDecoder::new(&mut body), depth + 1)?,
+                        alg => {
+                            return Err(pgp::errors::Error::Unsupported(format!(
+                                "Compression algorithm {alg}"
+                            )))
+                        }
+                    }
+                }
+                Tag::LiteralData => {
+                    if self.literal {
+                        return Err(pgp::errors::Error::Message(
+                            "More than one literal data packet".to_string(),
+                        ));
+                    }
+                    self.literal = true;
+
+                    self.literal_data(&mut body)?;
+                }
+                Tag::Marker | Tag::Padding => {}
+                tag => {
+                    return Err(pgp::errors::Error::Message(format!(
+                        "Unexpected {tag:?} packet in message"
+                    )))
+                }
+            }
+
+            // Skip over any remainder of the packet
+            io::copy(&mut body, &mut io::sink())?;
+        }
+
+        Ok(())
+    }
+
+    /// Stream the contents of a literal data packet to the sink, and to all hashers
+    fn literal_data(&mut self, body: &mut dyn Read) -> pgp::errors::Result<()> {
+        // Format, file name and date
+        let mut header = [0u8; 2];
+        body.read_exact(&mut header)?;
+        let mut rest = vec![0u8; header[1] as usize + 4];
+        body.read_exact(&mut rest)?;
+
+        let mut buf = vec![0; BUF_SIZE];
+        loop {
+            let n = match body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let data = &buf[..n];
+            self.one_pass.iter_mut().for_each(|h| h.update(data));
+            self.prefixed.iter_mut().for_each(|(_, h)| h.update(data));
+            self.sink.write_all(data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check `sig` against the data in `hasher`, and collect the result
+    fn verify(&mut self, hasher: DataHasher, sig: Signature) {
+        for (cert, key) in verify::signers(hasher, &sig, self.certs) {
+            self.validated.push((cert, key, sig.clone()));
+        }
+    }
+
+    /// Check that the message was complete, and verify the signatures that preceded the data
+    fn finish(mut self) -> pgp::errors::Result<Vec<Validated>> {
+        if !self.literal {
+            return Err(pgp::errors::Error::Message(
+                "No literal data packet in message".to_string(),
+            ));
+        }
+        if !self.one_pass.is_empty() {
+            return Err(pgp::errors::Error::Message(
+                "One pass signature without signature".to_string(),
+            ));
+        }
+
+        // The first prefixed signature is the outermost one
+        while let Some((sig, hasher)) = self.prefixed.pop() {
+            self.verify(hasher, sig);
+        }
+
+        Ok(self.validated)
+    }
+}
@@ -0,0 +1,21 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Incremental production and processing of OpenPGP messages.
+//!
+//! rPGP and rpgpie assemble messages in memory, which limits the size of the data we can process.
+//! The types in this module handle the same packet structures piece by piece, using partial
+//! body lengths, so that memory use doesn't depend on the size of the data.
+
+pub(crate) mod armor;
+pub(crate) mod hash;
+pub(crate) mod message;
+pub(crate) mod packet;
+pub(crate) mod seipd;
+pub(crate) mod sign;
+pub(crate) mod spill;
+pub(crate) mod text;
+pub(crate) mod verify;
+
+/// Buffer size for copying data through the types in this module
+pub(crate) const BUF_SIZE: usize = 64 * 1024;
@@ -0,0 +1,293 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Framing of packets in a stream
+
+use std::io::{self, Read, Write};
+
+use chrono::Utc;
+use pgp::packet::{Packet, PacketParser};
+use pgp::types::{Tag, Version};
+
+/// Size of the partial body chunks we emit (as a power of two)
+const PARTIAL_POWER: u8 = 13;
+const PARTIAL_LEN: usize = 1 << PARTIAL_POWER;
+
+/// Writes the body of one packet, using partial body lengths.
+///
+/// The packet header is written on construction, the final chunk on [PacketWriter::finish].
+pub(crate) struct PacketWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> PacketWriter<W> {
+    pub(crate) fn new(mut inner: W, tag: Tag) -> io::Result<Self> {
+        inner.write_all(&[tag.encode()])?;
+
+        Ok(Self {
+            inner,
+            buf: Vec::with_capacity(PARTIAL_LEN),
+        })
+    }
+
+    /// Write the last chunk of the packet body, with a definite length
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let len = self.buf.len();
+        match len {
+            0..=191 => self.inner.write_all(&[len as u8])?,
+            192..=8383 => {
+                let l = len - 192;
+                self.inner.write_all(&[(l >> 8) as u8 + 192, l as u8])?
+            }
+            _ => {
+                self.inner.write_all(&[0xff])?;
+                self.inner.write_all(&(len as u32).to_be_bytes())?
+            }
+        }
+        self.inner.write_all(&self.buf)?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for PacketWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full chunk is only emitted once more data arrives, because the last chunk of the
+        // body must have a definite length
+        if self.buf.len() == PARTIAL_LEN && !data.is_empty() {
+            self.inner.write_all(&[0xe0 | PARTIAL_POWER])?;
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+
+        let n = data.len().min(PARTIAL_LEN - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Start a literal data packet with an empty filename, for binary data or (if `text` is set)
+/// UTF-8 text
+pub(crate) fn literal<W: Write>(inner: W, text: bool) -> io::Result<PacketWriter<W>> {
+    let mut lit = PacketWriter::new(inner, Tag::LiteralData)?;
+
+    let created = Utc::now().timestamp() as u32;
+
+    let mode = match text {
+        true => b'u',
+        false => b'b',
+    };
+
+    lit.write_all(&[mode, 0])?;
+    lit.write_all(&created.to_be_bytes())?;
+
+    Ok(lit)
+}
+
+/// Largest body we read into memory for a packet that isn't processed as a stream (such as a
+/// signature or an encrypted session key)
+const MAX_BUFFERED_LEN: usize = 1024 * 1024;
+
+/// Length of a packet body, as encoded in the packet header
+enum BodyLength {
+    /// The body has a known length
+    Fixed(usize),
+
+    /// The body is split into chunks, this is the length of the first one
+    Partial(usize),
+
+    /// The body extends until the end of the input (legacy format only)
+    Indeterminate,
+}
+
+/// The header of a packet in a stream
+pub(crate) struct Header {
+    pub(crate) tag: Tag,
+    length: BodyLength,
+
+    /// The encoded header, as it was read
+    pub(crate) raw: Vec<u8>,
+}
+
+/// Read a header of either packet format from `reader`.
+///
+/// Returns `None` at the end of the input.
+pub(crate) fn read_header(
+    reader: &mut (impl Read + ?Sized),
+) -> pgp::errors::Result<Option<Header>> {
+    let mut raw = vec![0u8];
+    if reader.read(&mut raw)? == 0 {
+        return Ok(None);
+    }
+
+    let ctb = raw[0];
+    if ctb & 0x80 == 0 {
+        return Err(pgp::errors::Error::Message(format!(
+            "Invalid packet header {ctb:#04x}"
+        )));
+    }
+
+    let (tag, length) = if ctb & 0x40 != 0 {
+        // OpenPGP packet format
+        (ctb & 0x3f, read_length(reader, &mut raw)?)
+    } else {
+        // Legacy packet format
+        let length = match ctb & 0x03 {
+            3 => BodyLength::Indeterminate,
+            l => {
+                let len = read_exact(reader, &mut raw, 1 << l)?;
+                BodyLength::Fixed(len.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+            }
+        };
+
+        ((ctb >> 2) & 0x0f, length)
+    };
+
+    Ok(Some(Header {
+        tag: Tag::from(tag),
+        length,
+        raw,
+    }))
+}
+
+/// Read an OpenPGP format length (RFC 9580, 4.2.1), appending the encoded octets to `raw`
+fn read_length(
+    reader: &mut (impl Read + ?Sized),
+    raw: &mut Vec<u8>,
+) -> pgp::errors::Result<BodyLength> {
+    let first = read_exact(reader, raw, 1)?[0] as usize;
+
+    Ok(match first {
+        0..=191 => BodyLength::Fixed(first),
+        192..=223 => {
+            let second = read_exact(reader, raw, 1)?[0] as usize;
+            BodyLength::Fixed(((first - 192) << 8) + second + 192)
+        }
+        224..=254 => BodyLength::Partial(1 << (first & 0x1f)),
+        _ => {
+            let len = read_exact(reader, raw, 4)?;
+            BodyLength::Fixed(u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        }
+    })
+}
+
+/// Read exactly `len` octets, append them to `raw` and return them.
+///
+/// Reaching the end of the input is an error: packets must not be truncated.
+fn read_exact<'r>(
+    reader: &mut (impl Read + ?Sized),
+    raw: &'r mut Vec<u8>,
+    len: usize,
+) -> pgp::errors::Result<&'r [u8]> {
+    let start = raw.len();
+    raw.resize(start + len, 0);
+
+    reader
+        .read_exact(&mut raw[start..])
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                pgp::errors::Error::Message("Unexpected end of packet".to_string())
+            }
+            _ => e.into(),
+        })?;
+
+    Ok(&raw[start..])
+}
+
+/// Reads the body of a packet, across partial body length chunks
+pub(crate) struct BodyReader<R: Read> {
+    inner: R,
+
+    // Remaining octets in the current chunk (unused for an indeterminate length)
+    remaining: usize,
+
+    // Are there more chunks after the current one?
+    partial: bool,
+
+    indeterminate: bool,
+}
+
+impl<R: Read> BodyReader<R> {
+    pub(crate) fn new(inner: R, header: &Header) -> Self {
+        let (remaining, partial, indeterminate) = match header.length {
+            BodyLength::Fixed(len) => (len, false, false),
+            BodyLength::Partial(len) => (len, true, false),
+            BodyLength::Indeterminate => (0, false, true),
+        };
+
+        Self {
+            inner,
+            remaining,
+            partial,
+            indeterminate,
+        }
+    }
+
+    /// Read the full body into memory, and check that it is of a reasonable size
+    pub(crate) fn read_to_vec(&mut self) -> pgp::errors::Result<Vec<u8>> {
+        let mut body = vec![];
+        self.by_ref()
+            .take(MAX_BUFFERED_LEN as u64 + 1)
+            .read_to_end(&mut body)?;
+
+        if body.len() > MAX_BUFFERED_LEN {
+            return Err(pgp::errors::Error::Message(
+                "Packet body too large".to_string(),
+            ));
+        }
+
+        Ok(body)
+    }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.indeterminate {
+            return self.inner.read(buf);
+        }
+
+        while self.remaining == 0 {
+            if !self.partial {
+                return Ok(0);
+            }
+
+            let mut raw = vec![];
+            match read_length(&mut self.inner, &mut raw).map_err(io::Error::other)? {
+                BodyLength::Fixed(len) => {
+                    self.remaining = len;
+                    self.partial = false;
+                }
+                BodyLength::Partial(len) => self.remaining = len,
+                BodyLength::Indeterminate => unreachable!(),
+            }
+        }
+
+        let len = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::other(pgp::errors::Error::Message(
+                "Unexpected end of packet".to_string(),
+            )));
+        }
+        self.remaining -= n;
+
+        Ok(n)
+    }
+}
+
+/// Parse a packet from its `tag` and `body`
+pub(crate) fn parse(tag: Tag, body: &[u8]) -> pgp::errors::Result<Packet> {
+    let mut buf = vec![];
+    Version::New.write_header(&mut buf, tag.into(), body.len())?;
+    buf.extend_from_slice(body);
+
+    PacketParser::new(&buf[..])
+        .next()
+        .unwrap_or_else(|| Err(pgp::errors::Error::Message("Missing packet".to_string())))
+}
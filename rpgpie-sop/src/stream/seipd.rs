@@ -0,0 +1,767 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Symmetrically Encrypted Integrity Protected Data (SEIPD) packets in a stream
+
+use std::io::{self, Read, Write};
+
+use pgp::composed::PlainSessionKey;
+use pgp::crypto::aead::AeadAlgorithm;
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::types::Tag;
+use rand::{CryptoRng, Rng};
+use sha1::{Digest, Sha1};
+use zeroize::Zeroizing;
+
+use crate::stream::packet::PacketWriter;
+use crate::stream::BUF_SIZE;
+
+/// Encrypts data into a version 1 Symmetrically Encrypted Integrity Protected Data packet
+/// (CFB mode with a modification detection code)
+pub(crate) struct SeipdV1Writer<W: Write> {
+    inner: PacketWriter<W>,
+    sym_alg: SymmetricKeyAlgorithm,
+    key: Zeroizing<Vec<u8>>,
+
+    // The last ciphertext block, which is the IV for the next chunk
+    iv: Vec<u8>,
+    buf: Vec<u8>,
+    mdc: Sha1,
+}
+
+impl<W: Write> SeipdV1Writer<W> {
+    pub(crate) fn new<R: Rng + CryptoRng>(
+        mut rng: R,
+        inner: W,
+        sym_alg: SymmetricKeyAlgorithm,
+        session_key: &[u8],
+    ) -> pgp::errors::Result<Self> {
+        let mut inner = PacketWriter::new(inner, Tag::SymEncryptedProtectedData)?;
+        inner.write_all(&[0x01])?;
+
+        let bs = sym_alg.block_size();
+
+        let mut seipd = Self {
+            inner,
+            sym_alg,
+            key: Zeroizing::new(session_key.to_vec()),
+            iv: vec![0; bs],
+            buf: Vec::with_capacity(BUF_SIZE + 32),
+            mdc: Sha1::new(),
+        };
+
+        // Random prefix, with the last two octets repeated as a quick check
+        let mut prefix = vec![0; bs + 2];
+        rng.fill_bytes(&mut prefix[..bs]);
+        prefix[bs] = prefix[bs - 2];
+        prefix[bs + 1] = prefix[bs - 1];
+
+        seipd.push(&prefix)?;
+
+        Ok(seipd)
+    }
+
+    /// Add plaintext, and encrypt all complete blocks
+    fn push(&mut self, data: &[u8]) -> pgp::errors::Result<()> {
+        self.mdc.update(data);
+        self.buf.extend_from_slice(data);
+
+        let bs = self.sym_alg.block_size();
+        let len = self.buf.len() - self.buf.len() % bs;
+        if len > 0 {
+            self.encrypt(len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt and write the first `len` bytes of the buffer
+    fn encrypt(&mut self, len: usize) -> pgp::errors::Result<()> {
+        let chunk = &mut self.buf[..len];
+        self.sym_alg
+            .encrypt_with_iv_regular(&self.key, &self.iv, chunk)?;
+        self.inner.write_all(chunk)?;
+
+        let bs = self.sym_alg.block_size();
+        if len >= bs {
+            self.iv.copy_from_slice(&chunk[len - bs..]);
+        }
+        self.buf.drain(..len);
+
+        Ok(())
+    }
+
+    /// Append the modification detection code and finish the packet
+    pub(crate) fn finish(mut self) -> pgp::errors::Result<W> {
+        self.mdc.update([0xd3, 0x14]);
+        let mdc = self.mdc.finalize_reset();
+
+        self.buf.extend_from_slice(&[0xd3, 0x14]);
+        self.buf.extend_from_slice(&mdc);
+        self.encrypt(self.buf.len())?;
+
+        Ok(self.inner.finish()?)
+    }
+}
+
+impl<W: Write> Write for SeipdV1Writer<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BUF_SIZE);
+        self.push(&data[..n]).map_err(io::Error::other)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypts data into a version 2 Symmetrically Encrypted Integrity Protected Data packet
+/// (chunked AEAD)
+pub(crate) struct SeipdV2Writer<W: Write> {
+    inner: PacketWriter<W>,
+    sym_alg: SymmetricKeyAlgorithm,
+    aead: AeadAlgorithm,
+    key: Zeroizing<Vec<u8>>,
+    info: [u8; 5],
+
+    // The nonce for the current chunk (the last 8 bytes are the chunk index)
+    nonce: Vec<u8>,
+    chunk_len: usize,
+    chunk_index: u64,
+    total: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SeipdV2Writer<W> {
+    pub(crate) fn new<R: Rng + CryptoRng>(
+        mut rng: R,
+        inner: W,
+        sym_alg: SymmetricKeyAlgorithm,
+        aead: AeadAlgorithm,
+        chunk_size: u8,
+        session_key: &[u8],
+    ) -> pgp::errors::Result<Self> {
+        let mut salt = [0u8; 32];
+        rng.fill_bytes(&mut salt);
+
+        let info = [
+            Tag::SymEncryptedProtectedData.encode(),
+            0x02,
+            sym_alg.into(),
+            aead.into(),
+            chunk_size,
+        ];
+
+        // Derive message key and IV from the session key (RFC 9580, 5.13.2)
+        let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(&salt), session_key);
+        let mut okm = Zeroizing::new([0u8; 42]);
+        hk.expand(&info, okm.as_mut_slice())
+            .map_err(|_| pgp::errors::Error::Message("HKDF expand failed".to_string()))?;
+
+        let key_size = sym_alg.key_size();
+        let iv_len = aead.nonce_size() - 8;
+
+        let key = Zeroizing::new(okm[..key_size].to_vec());
+        let mut nonce = vec![0u8; aead.nonce_size()];
+        nonce[..iv_len].copy_from_slice(&okm[key_size..key_size + iv_len]);
+
+        let mut inner = PacketWriter::new(inner, Tag::SymEncryptedProtectedData)?;
+        inner.write_all(&info[1..])?;
+        inner.write_all(&salt)?;
+
+        let chunk_len = 1usize << (chunk_size + 6);
+
+        Ok(Self {
+            inner,
+            sym_alg,
+            aead,
+            key,
+            info,
+            nonce,
+            chunk_len,
+            chunk_index: 0,
+            total: 0,
+            buf: Vec::with_capacity(chunk_len),
+        })
+    }
+
+    /// Encrypt the buffered data as one chunk, and write it with its authentication tag
+    fn encrypt_chunk(&mut self) -> pgp::errors::Result<()> {
+        let tag = self.aead.encrypt_in_place(
+            &self.sym_alg,
+            &self.key,
+            &self.nonce,
+            &self.info,
+            &mut self.buf,
+        )?;
+
+        self.inner.write_all(&self.buf)?;
+        self.inner.write_all(&tag)?;
+
+        self.total += self.buf.len() as u64;
+        self.buf.clear();
+
+        self.chunk_index += 1;
+        let l = self.nonce.len() - 8;
+        self.nonce[l..].copy_from_slice(&self.chunk_index.to_be_bytes());
+
+        Ok(())
+    }
+
+    /// Encrypt the last (possibly partial) chunk, append the final authentication tag, and
+    /// finish the packet
+    pub(crate) fn finish(mut self) -> pgp::errors::Result<W> {
+        if !self.buf.is_empty() {
+            self.encrypt_chunk()?;
+        }
+
+        let mut final_info = self.info.to_vec();
+        final_info.extend_from_slice(&self.total.to_be_bytes());
+
+        let tag = self.aead.encrypt_in_place(
+            &self.sym_alg,
+            &self.key,
+            &self.nonce,
+            &final_info,
+            &mut [],
+        )?;
+        self.inner.write_all(&tag)?;
+
+        Ok(self.inner.finish()?)
+    }
+}
+
+impl<W: Write> Write for SeipdV2Writer<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == self.chunk_len && !data.is_empty() {
+            self.encrypt_chunk().map_err(io::Error::other)?;
+        }
+
+        let n = data.len().min(self.chunk_len - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Length of the modification detection code packet at the end of a version 1 SEIPD packet
+const MDC_LEN: usize = 22;
+
+/// Decrypts the body of a version 1 Symmetrically Encrypted Integrity Protected Data packet.
+///
+/// The modification detection code can only be checked at the end of the packet, so the
+/// plaintext that this reader returns is unauthenticated until it has reached the end of its
+/// input without an error.
+pub(crate) struct SeipdV1Reader<R: Read> {
+    inner: R,
+    sym_alg: SymmetricKeyAlgorithm,
+    key: Zeroizing<Vec<u8>>,
+
+    // The last ciphertext block, which is the IV for the next chunk
+    iv: Vec<u8>,
+
+    // Ciphertext that doesn't fill a complete block yet
+    cbuf: Vec<u8>,
+
+    // Decrypted data, of which we hold back the modification detection code
+    plain: Vec<u8>,
+    pos: usize,
+
+    mdc: Sha1,
+    eof: bool,
+
+    // The modification detection code didn't match, so all further reads fail
+    mdc_failed: bool,
+}
+
+impl<R: Read> SeipdV1Reader<R> {
+    /// Start decrypting `inner` (which is positioned after the version octet).
+    ///
+    /// Fails if the quick check of the random prefix doesn't match, which means that
+    /// `session_key` is almost certainly wrong.
+    fn new(
+        inner: R,
+        sym_alg: SymmetricKeyAlgorithm,
+        session_key: &[u8],
+    ) -> pgp::errors::Result<Self> {
+        let bs = sym_alg.block_size();
+        if bs == 0 || session_key.len() != sym_alg.key_size() {
+            return Err(pgp::errors::Error::InvalidKeyLength);
+        }
+
+        let mut seipd = Self {
+            inner,
+            sym_alg,
+            key: Zeroizing::new(session_key.to_vec()),
+            iv: vec![0; bs],
+            cbuf: Vec::with_capacity(BUF_SIZE + 32),
+            plain: Vec::with_capacity(BUF_SIZE + 32),
+            pos: 0,
+            mdc: Sha1::new(),
+            eof: false,
+            mdc_failed: false,
+        };
+
+        while seipd.plain.len() < bs + 2 + MDC_LEN && !seipd.eof {
+            seipd.fill()?;
+        }
+        if seipd.plain.len() < bs + 2 + MDC_LEN {
+            return Err(pgp::errors::Error::Message(
+                "SEIPD packet too short".to_string(),
+            ));
+        }
+
+        let prefix = &seipd.plain[..bs + 2];
+        if prefix[bs - 2..bs] != prefix[bs..] {
+            return Err(pgp::errors::Error::Message(
+                "Session key quick check failed".to_string(),
+            ));
+        }
+
+        seipd.mdc.update(prefix);
+        seipd.pos = bs + 2;
+
+        Ok(seipd)
+    }
+
+    /// Decrypt the next chunk of the input
+    fn fill(&mut self) -> pgp::errors::Result<()> {
+        self.plain.drain(..self.pos);
+        self.pos = 0;
+
+        let start = self.cbuf.len();
+        self.cbuf.resize(start + BUF_SIZE, 0);
+        let n = self.inner.read(&mut self.cbuf[start..])?;
+        self.cbuf.truncate(start + n);
+
+        let bs = self.sym_alg.block_size();
+        let len = match n {
+            0 => {
+                self.eof = true;
+                self.cbuf.len()
+            }
+            _ => self.cbuf.len() - self.cbuf.len() % bs,
+        };
+
+        if len > 0 {
+            let mut chunk: Vec<u8> = self.cbuf.drain(..len).collect();
+            let iv = chunk[len.saturating_sub(bs)..].to_vec();
+
+            self.sym_alg
+                .decrypt_with_iv_regular(&self.key, &self.iv, &mut chunk)?;
+            self.plain.extend_from_slice(&chunk);
+
+            if len >= bs {
+                self.iv = iv;
+            }
+        }
+
+        if self.eof {
+            if let Err(e) = self.check_mdc() {
+                self.mdc_failed = true;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the modification detection code at the end of the decrypted data
+    fn check_mdc(&self) -> pgp::errors::Result<()> {
+        let len = self.plain.len();
+        if len - self.pos < MDC_LEN {
+            return Err(pgp::errors::Error::MdcError);
+        }
+
+        let (data, mdc) = self.plain[self.pos..].split_at(len - self.pos - MDC_LEN);
+        if mdc[..2] != [0xd3, 0x14] {
+            return Err(pgp::errors::Error::MdcError);
+        }
+
+        let mut hasher = self.mdc.clone();
+        hasher.update(data);
+        hasher.update(&mdc[..2]);
+
+        if hasher.finalize()[..] != mdc[2..] {
+            return Err(pgp::errors::Error::MdcError);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SeipdV1Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.mdc_failed {
+            return Err(io::Error::other(pgp::errors::Error::MdcError));
+        }
+
+        while self.plain.len() - self.pos <= MDC_LEN && !self.eof {
+            self.fill().map_err(io::Error::other)?;
+        }
+
+        let available = self.plain.len().saturating_sub(self.pos + MDC_LEN);
+        let n = buf.len().min(available);
+
+        let data = &self.plain[self.pos..self.pos + n];
+        buf[..n].copy_from_slice(data);
+        self.mdc.update(data);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+/// Decrypts the body of a version 2 Symmetrically Encrypted Integrity Protected Data packet.
+///
+/// Each chunk is authenticated before this reader returns any of its plaintext.
+/// Truncation of the packet is detected by the final authentication tag, at the end of the input.
+pub(crate) struct SeipdV2Reader<R: Read> {
+    inner: R,
+    sym_alg: SymmetricKeyAlgorithm,
+    aead: AeadAlgorithm,
+    key: Zeroizing<Vec<u8>>,
+    info: [u8; 5],
+
+    // The nonce for the current chunk (the last 8 bytes are the chunk index)
+    nonce: Vec<u8>,
+    chunk_len: usize,
+    chunk_index: u64,
+    total: u64,
+
+    // Ciphertext of the chunks we haven't decrypted yet
+    cbuf: Vec<u8>,
+
+    // Authenticated plaintext
+    plain: Zeroizing<Vec<u8>>,
+    pos: usize,
+
+    eof: bool,
+
+    // Authentication failed, so all further reads fail
+    failed: bool,
+}
+
+impl<R: Read> SeipdV2Reader<R> {
+    /// Start decrypting `inner` (which is positioned after the version octet).
+    ///
+    /// Decrypts the first chunk, so that a wrong `session_key` is detected right away.
+    fn new(mut inner: R, session_key: &[u8]) -> pgp::errors::Result<Self> {
+        let mut header = [0u8; 3 + 32];
+        inner.read_exact(&mut header)?;
+
+        let (params, salt) = header.split_at(3);
+        let sym_alg = SymmetricKeyAlgorithm::from(params[0]);
+        let aead = AeadAlgorithm::from(params[1]);
+        let chunk_size = params[2];
+
+        if chunk_size > 16 {
+            return Err(pgp::errors::Error::Message(format!(
+                "Illegal AEAD chunk size {chunk_size}"
+            )));
+        }
+        if session_key.len() != sym_alg.key_size() {
+            return Err(pgp::errors::Error::InvalidKeyLength);
+        }
+        if aead.nonce_size() < 8 {
+            return Err(pgp::errors::Error::Unsupported(format!(
+                "AEAD algorithm {aead:?}"
+            )));
+        }
+
+        let info = [
+            Tag::SymEncryptedProtectedData.encode(),
+            0x02,
+            sym_alg.into(),
+            aead.into(),
+            chunk_size,
+        ];
+
+        // Derive message key and IV from the session key (RFC 9580, 5.13.2)
+        let hk = hkdf::Hkdf::<sha2::Sha256>::new(Some(salt), session_key);
+        let mut okm = Zeroizing::new([0u8; 42]);
+        hk.expand(&info, okm.as_mut_slice())
+            .map_err(|_| pgp::errors::Error::Message("HKDF expand failed".to_string()))?;
+
+        let key_size = sym_alg.key_size();
+        let iv_len = aead.nonce_size() - 8;
+
+        let key = Zeroizing::new(okm[..key_size].to_vec());
+        let mut nonce = vec![0u8; aead.nonce_size()];
+        nonce[..iv_len].copy_from_slice(&okm[key_size..key_size + iv_len]);
+
+        let chunk_len = 1usize << (chunk_size + 6);
+
+        let mut seipd = Self {
+            inner,
+            sym_alg,
+            aead,
+            key,
+            info,
+            nonce,
+            chunk_len,
+            chunk_index: 0,
+            total: 0,
+            cbuf: Vec::with_capacity(chunk_len + 2 * aead.tag_size()),
+            plain: Zeroizing::new(Vec::with_capacity(chunk_len)),
+            pos: 0,
+            eof: false,
+            failed: false,
+        };
+        seipd.fill()?;
+
+        Ok(seipd)
+    }
+
+    /// Decrypt and authenticate the next chunk.
+    ///
+    /// At the end of the input, this also checks the final authentication tag.
+    fn fill(&mut self) -> pgp::errors::Result<()> {
+        self.plain.clear();
+        self.pos = 0;
+
+        let tag_size = self.aead.tag_size();
+
+        // We can only tell the last chunk apart from the final tag once we see the end of the input
+        let want = self.chunk_len + 2 * tag_size;
+        while self.cbuf.len() < want {
+            let start = self.cbuf.len();
+            self.cbuf.resize(want, 0);
+            let n = self.inner.read(&mut self.cbuf[start..])?;
+            self.cbuf.truncate(start + n);
+
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+        }
+
+        if !self.eof {
+            let chunk = self.decrypt_chunk(self.chunk_len)?;
+            self.plain.extend_from_slice(&chunk);
+
+            return Ok(());
+        }
+
+        // The last chunk is only released once the final tag shows that nothing is missing
+        let len = self.cbuf.len();
+        let last = match len.checked_sub(tag_size) {
+            None => {
+                return Err(pgp::errors::Error::Message(
+                    "SEIPD packet too short".to_string(),
+                ))
+            }
+            Some(0) => Zeroizing::new(vec![]),
+            Some(l) if l < tag_size => {
+                return Err(pgp::errors::Error::Message(
+                    "SEIPD packet too short".to_string(),
+                ))
+            }
+            Some(l) => self.decrypt_chunk(l - tag_size)?,
+        };
+
+        let mut final_info = self.info.to_vec();
+        final_info.extend_from_slice(&self.total.to_be_bytes());
+
+        self.aead.decrypt_in_place(
+            &self.sym_alg,
+            &self.key,
+            &self.nonce,
+            &final_info,
+            &self.cbuf,
+            &mut [],
+        )?;
+        self.cbuf.clear();
+
+        self.plain.extend_from_slice(&last);
+
+        Ok(())
+    }
+
+    /// Decrypt the first `len` bytes of buffered ciphertext, which are followed by their
+    /// authentication tag.
+    ///
+    /// Returns the authenticated plaintext of the chunk.
+    fn decrypt_chunk(&mut self, len: usize) -> pgp::errors::Result<Zeroizing<Vec<u8>>> {
+        let tag_size = self.aead.tag_size();
+
+        let mut chunk = Zeroizing::new(self.cbuf.drain(..len + tag_size).collect::<Vec<u8>>());
+        let (data, tag) = chunk.split_at_mut(len);
+
+        self.aead
+            .decrypt_in_place(&self.sym_alg, &self.key, &self.nonce, &self.info, tag, data)?;
+        chunk.truncate(len);
+
+        self.total += len as u64;
+        self.chunk_index += 1;
+        let l = self.nonce.len() - 8;
+        self.nonce[l..].copy_from_slice(&self.chunk_index.to_be_bytes());
+
+        Ok(chunk)
+    }
+}
+
+impl<R: Read> Read for SeipdV2Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other(pgp::errors::Error::Message(
+                "SEIPD packet failed to authenticate".to_string(),
+            )));
+        }
+
+        while self.pos == self.plain.len() && !self.eof {
+            if let Err(e) = self.fill() {
+                self.failed = true;
+                return Err(io::Error::other(e));
+            }
+        }
+
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+/// Decrypts the body of a Symmetrically Encrypted Integrity Protected Data packet of either
+/// version
+pub(crate) enum SeipdReader<R: Read> {
+    V1(SeipdV1Reader<R>),
+    V2(SeipdV2Reader<R>),
+}
+
+impl<R: Read> SeipdReader<R> {
+    /// Start decrypting the body of a SEIPD packet with `session_key`.
+    ///
+    /// `inner` is positioned after the `version` octet of the packet body.
+    pub(crate) fn new(
+        inner: R,
+        version: u8,
+        session_key: &PlainSessionKey,
+    ) -> pgp::errors::Result<Self> {
+        match (version, session_key) {
+            (1, PlainSessionKey::V3_4 { sym_alg, key }) => {
+                Ok(Self::V1(SeipdV1Reader::new(inner, *sym_alg, key)?))
+            }
+            (2, PlainSessionKey::V3_4 { key, .. } | PlainSessionKey::V6 { key }) => {
+                Ok(Self::V2(SeipdV2Reader::new(inner, key)?))
+            }
+            (1 | 2, _) => Err(pgp::errors::Error::Message(format!(
+                "Session key doesn't fit SEIPD version {version}"
+            ))),
+            _ => Err(pgp::errors::Error::Unsupported(format!(
+                "SEIPD version {version}"
+            ))),
+        }
+    }
+
+    /// The symmetric algorithm that the packet is encrypted with
+    pub(crate) fn sym_alg(&self) -> SymmetricKeyAlgorithm {
+        match self {
+            Self::V1(r) => r.sym_alg,
+            Self::V2(r) => r.sym_alg,
+        }
+    }
+
+    /// Is the plaintext authenticated before it is returned from `read`?
+    ///
+    /// If not, the plaintext must not be released before the end of the input.
+    pub(crate) fn is_authenticated(&self) -> bool {
+        matches!(self, Self::V2(_))
+    }
+}
+
+impl<R: Read> Read for SeipdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::V1(r) => r.read(buf),
+            Self::V2(r) => r.read(buf),
+        }
+    }
+}
+
+#[test]
+fn test_seipd_v1_mdc_failure() {
+    use crate::stream::packet::{read_header, BodyReader};
+
+    let sym_alg = SymmetricKeyAlgorithm::AES128;
+    let key = [0x42; 16];
+
+    let mut writer = SeipdV1Writer::new(rand::thread_rng(), vec![], sym_alg, &key).unwrap();
+    writer.write_all(&[0x2a; 1000]).unwrap();
+    let mut packet = writer.finish().unwrap();
+
+    // Flip a bit in the modification detection code
+    let len = packet.len();
+    packet[len - 1] ^= 0x01;
+
+    let mut source = &packet[..];
+    let header = read_header(&mut source).unwrap().unwrap();
+    let mut body = BodyReader::new(source, &header);
+    body.read_exact(&mut [0u8]).unwrap();
+
+    let mut reader = SeipdV1Reader::new(body, sym_alg, &key).unwrap();
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+
+    // The failure persists, and no data is returned after it
+    let mut buf = [0u8; 100];
+    assert!(reader.read(&mut buf).is_err());
+    assert!(reader.read(&mut buf).is_err());
+}
+
+#[test]
+fn test_seipd_v2_final_tag_failure() {
+    use crate::stream::packet::{read_header, BodyReader};
+
+    let sym_alg = SymmetricKeyAlgorithm::AES128;
+    let key = [0x42; 16];
+
+    // Chunks of 64 bytes, the last one with 40 bytes
+    let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut writer = SeipdV2Writer::new(
+        rand::thread_rng(),
+        vec![],
+        sym_alg,
+        AeadAlgorithm::Ocb,
+        0,
+        &key,
+    )
+    .unwrap();
+    writer.write_all(&data).unwrap();
+    let mut packet = writer.finish().unwrap();
+
+    // Flip a bit in the final authentication tag
+    let len = packet.len();
+    packet[len - 1] ^= 0x01;
+
+    let mut source = &packet[..];
+    let header = read_header(&mut source).unwrap().unwrap();
+    let mut body = BodyReader::new(source, &header);
+    body.read_exact(&mut [0u8]).unwrap();
+
+    let mut reader = SeipdV2Reader::new(body, &key).unwrap();
+
+    let mut plaintext = vec![];
+    let mut buf = [0u8; 100];
+    let failure = loop {
+        match reader.read(&mut buf) {
+            Ok(0) => panic!("final tag failure not detected"),
+            Ok(n) => plaintext.extend_from_slice(&buf[..n]),
+            Err(e) => break e,
+        }
+    };
+    assert!(failure.downcast::<pgp::errors::Error>().is_ok());
+
+    // The chunks before the last one are authenticated, the last one isn't released
+    assert_eq!(plaintext, data[..960]);
+
+    // The failure persists
+    assert!(reader.read(&mut buf).is_err());
+    assert!(reader.read(&mut buf).is_err());
+}
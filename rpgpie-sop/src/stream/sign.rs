@@ -0,0 +1,242 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Signing of streamed data
+
+use std::io::{self, Write};
+
+use chrono::{SubsecRound, Utc};
+use pgp::armor::BlockType;
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{OnePassSignature, SignatureConfig, SignatureType, SignatureVersionSpecific};
+use pgp::types::{Fingerprint, SecretKeyTrait};
+use pgp::{Signature, StandaloneSignature};
+use rpgpie::key::component::ComponentKeySec;
+
+use crate::stream::hash::DataHasher;
+use crate::stream::packet;
+use crate::stream::text::Crlf;
+use crate::stream::BUF_SIZE;
+
+/// Calculates a data signature over streamed data
+pub(crate) struct Signer {
+    key: ComponentKeySec,
+    password: String,
+    config: SignatureConfig,
+    hasher: DataHasher,
+}
+
+impl Signer {
+    /// Prepare a signature of type `typ` by `key`.
+    ///
+    /// Fails with [sop::errors::Error::KeyIsProtected] if none of `pws` unlocks `key`.
+    pub(crate) fn new(
+        key: ComponentKeySec,
+        pws: &[&[u8]],
+        typ: SignatureType,
+        hash_alg: HashAlgorithm,
+    ) -> sop::Result<Self> {
+        log::info!("Preparing signer: {:02x?}", key.fingerprint());
+
+        let password = pws
+            .iter()
+            .map(|pw| String::from_utf8_lossy(pw).to_string())
+            .find(|pw| {
+                let result = match &key {
+                    ComponentKeySec::Primary(sk) => sk.unlock(|| pw.clone(), |_| Ok(())),
+                    ComponentKeySec::Subkey(ssk) => ssk.unlock(|| pw.clone(), |_| Ok(())),
+                };
+
+                if result.is_err() {
+                    log::warn!("Unlocking signer failed: {result:?}");
+                }
+
+                result.is_ok()
+            })
+            .ok_or(sop::errors::Error::KeyIsProtected)?;
+
+        let now = Utc::now().trunc_subsecs(0);
+        let config = match &key {
+            ComponentKeySec::Primary(sk) => crate::util::signature_config(sk, typ, hash_alg, &now),
+            ComponentKeySec::Subkey(ssk) => crate::util::signature_config(ssk, typ, hash_alg, &now),
+        }?;
+
+        let salt = match &config.version_specific {
+            SignatureVersionSpecific::V6 { salt } => Some(&salt[..]),
+            _ => None,
+        };
+        let hasher = DataHasher::new(typ, hash_alg, salt).map_err(crate::error::rpgp)?;
+
+        Ok(Self {
+            key,
+            password,
+            config,
+            hasher,
+        })
+    }
+
+    /// The one pass signature packet that announces this signature
+    pub(crate) fn one_pass_signature(&self, last: bool) -> pgp::errors::Result<OnePassSignature> {
+        let typ = self.config.typ;
+        let hash_alg = self.config.hash_alg;
+        let pub_alg = self.config.pub_alg;
+
+        let mut ops = match (&self.config.version_specific, self.key.fingerprint()) {
+            (SignatureVersionSpecific::V6 { salt }, Fingerprint::V6(fp)) => {
+                OnePassSignature::v6(typ, hash_alg, pub_alg, salt.clone(), fp)
+            }
+            (SignatureVersionSpecific::V4, _) => {
+                OnePassSignature::v3(typ, hash_alg, pub_alg, self.key.key_id())
+            }
+            _ => {
+                return Err(pgp::errors::Error::Message(
+                    "Inconsistent signature and key versions".to_string(),
+                ))
+            }
+        };
+
+        if !last {
+            ops.last = 0;
+        }
+
+        Ok(ops)
+    }
+
+    /// Add signed data (which is normalized on the fly, for text signatures)
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Calculate the signature over all data that was passed to [Signer::update]
+    pub(crate) fn finish(self) -> pgp::errors::Result<Signature> {
+        let Self {
+            key,
+            password,
+            config,
+            hasher,
+        } = self;
+
+        let hash = hasher.finish(&config)?;
+
+        let hash_alg = config.hash_alg;
+        let signature = match &key {
+            ComponentKeySec::Primary(sk) => sk.create_signature(|| password, hash_alg, &hash),
+            ComponentKeySec::Subkey(ssk) => ssk.create_signature(|| password, hash_alg, &hash),
+        }?;
+
+        Ok(Signature::from_config(
+            config,
+            [hash[0], hash[1]],
+            signature,
+        ))
+    }
+}
+
+/// Stream a literal data packet with the contents of `source` to `sink`, signed by `signers`.
+///
+/// If `text` is set, the literal data is marked as UTF-8 text, and its line endings are normalized
+/// to CR LF.
+///
+/// The signatures are nested: the first signer's signature is innermost.
+pub(crate) fn write_signed_literal<W: Write>(
+    source: &mut dyn io::Read,
+    mut signers: Vec<Signer>,
+    text: bool,
+    sink: &mut W,
+) -> pgp::errors::Result<()> {
+    for (i, signer) in signers.iter().enumerate().rev() {
+        pgp::packet::write_packet(sink, &signer.one_pass_signature(i == 0)?)?;
+    }
+
+    let mut lit = packet::literal(&mut *sink, text)?;
+    let mut crlf = text.then(Crlf::new);
+
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        signers.iter_mut().for_each(|s| s.update(&buf[..n]));
+
+        match crlf.as_mut() {
+            Some(crlf) => crlf.update(&buf[..n], |d| lit.write_all(d))?,
+            None => lit.write_all(&buf[..n])?,
+        }
+    }
+
+    if let Some(crlf) = crlf {
+        crlf.finish(|d| lit.write_all(d))?;
+    }
+
+    lit.finish()?;
+
+    for signer in signers {
+        pgp::packet::write_packet(sink, &signer.finish()?)?;
+    }
+
+    Ok(())
+}
+
+/// Stream a cleartext signed message with the text from `source` to `sink`, signed by `signers`
+/// (which must make signatures of type [SignatureType::Text]).
+///
+/// The output matches that of [pgp::cleartext::CleartextSignedMessage]: lines of the text that
+/// start with a dash are dash-escaped, and the signatures follow in an armored signature block.
+pub(crate) fn write_cleartext_signed<W: Write>(
+    source: &mut dyn io::Read,
+    mut signers: Vec<Signer>,
+    sink: &mut W,
+) -> pgp::errors::Result<()> {
+    sink.write_all(b"-----BEGIN PGP SIGNED MESSAGE-----\n")?;
+
+    let mut hash_algs: Vec<HashAlgorithm> = vec![];
+    for signer in &signers {
+        if !hash_algs.contains(&signer.config.hash_alg) {
+            hash_algs.push(signer.config.hash_alg);
+        }
+    }
+    for hash_alg in hash_algs {
+        sink.write_all(format!("Hash: {hash_alg}\n").as_bytes())?;
+    }
+    sink.write_all(b"\n")?;
+
+    // Are we at the start of a line of the text?
+    let mut line_start = true;
+
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let data = &buf[..n];
+
+        signers.iter_mut().for_each(|s| s.update(data));
+
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            if line_start && *b == b'-' {
+                sink.write_all(&data[start..i])?;
+                sink.write_all(b"- ")?;
+                start = i;
+            }
+            line_start = *b == b'\n';
+        }
+        sink.write_all(&data[start..])?;
+    }
+
+    sink.write_all(b"\n")?;
+
+    let signatures = signers
+        .into_iter()
+        .map(|s| s.finish().map(StandaloneSignature::new))
+        .collect::<pgp::errors::Result<Vec<_>>>()?;
+
+    pgp::armor::write(&signatures, BlockType::Signature, sink, None, true)
+}
@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Holding back data until it may be released
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use aes::Aes256;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+use crate::stream::BUF_SIZE;
+
+/// Amount of data we hold in memory, before spilling to a file
+const MEMORY_LIMIT: usize = 1024 * 1024;
+
+type Cipher = ctr::Ctr128BE<Aes256>;
+
+/// A temporary file, with the data in it encrypted with an ephemeral key.
+///
+/// The data may be (unauthenticated) plaintext of a message, which must not be left on disk in
+/// the clear.
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+
+    key: Zeroizing<[u8; 32]>,
+    iv: [u8; 16],
+
+    // Encrypts the data as it is written
+    cipher: Cipher,
+}
+
+impl SpillFile {
+    /// Create a temporary file that only the current user can access
+    fn create() -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let (path, file) = loop {
+            let name = format!("rsop-{:016x}", rand::thread_rng().gen::<u64>());
+            let path = std::env::temp_dir().join(name);
+
+            match options.open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill(&mut key[..]);
+        let iv: [u8; 16] = rand::thread_rng().gen();
+        let cipher = Cipher::new(key.as_ref().into(), &iv.into());
+
+        Ok(Self {
+            path,
+            file,
+            key,
+            iv,
+            cipher,
+        })
+    }
+
+    /// Encrypt `data` (in place) and append it to the file
+    fn write(&mut self, data: &mut [u8]) -> io::Result<()> {
+        self.cipher.apply_keystream(data);
+        self.file.write_all(data)
+    }
+
+    /// Decrypt the content of the file to `sink`
+    fn release(&mut self, sink: &mut dyn Write) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut cipher = Cipher::new(self.key.as_ref().into(), &self.iv.into());
+
+        let mut buf = Zeroizing::new(vec![0; BUF_SIZE]);
+        loop {
+            match self.file.read(&mut buf)? {
+                0 => return Ok(()),
+                n => {
+                    cipher.apply_keystream(&mut buf[..n]);
+                    sink.write_all(&buf[..n])?;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Collects data in memory, or in a temporary file once it gets large.
+///
+/// The data in the temporary file is encrypted with a key that only this `Spill` knows, and the
+/// file is removed when the `Spill` is dropped.
+pub(crate) struct Spill {
+    buf: Zeroizing<Vec<u8>>,
+    file: Option<SpillFile>,
+}
+
+impl Spill {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: Zeroizing::new(vec![]),
+            file: None,
+        }
+    }
+
+    /// Copy all collected data to `sink`
+    pub(crate) fn release(mut self, sink: &mut dyn Write) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.release(sink)?;
+        }
+
+        sink.write_all(&self.buf)
+    }
+}
+
+impl Write for Spill {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+
+        if self.buf.len() > MEMORY_LIMIT {
+            if self.file.is_none() {
+                self.file = Some(SpillFile::create()?);
+            }
+            if let Some(file) = &mut self.file {
+                file.write(&mut self.buf)?;
+                self.buf.clear();
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_spill() {
+    let data: Vec<u8> = (0..3 * MEMORY_LIMIT).map(|i| (i % 251) as u8).collect();
+
+    let mut spill = Spill::new();
+    for chunk in data.chunks(100_000) {
+        spill.write_all(chunk).unwrap();
+    }
+
+    // The spilled part of the data is not stored in the clear
+    let file = spill.file.as_ref().unwrap();
+    let stored = std::fs::read(&file.path).unwrap();
+    assert!(!stored.is_empty());
+    assert_ne!(stored[..], data[..stored.len()]);
+    let path = file.path.clone();
+
+    let mut released = vec![];
+    spill.release(&mut released).unwrap();
+    assert_eq!(released, data);
+
+    // The temporary file is gone after the release
+    assert!(!path.exists());
+}
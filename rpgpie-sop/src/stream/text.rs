@@ -0,0 +1,159 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Handling of streamed text
+
+use std::fmt;
+use std::io::{self, Read};
+
+/// Normalizes the line endings of streamed text to CR LF.
+///
+/// Like rPGP, this treats a lone CR as a line ending as well.
+#[derive(Default)]
+pub(crate) struct Crlf {
+    // Was the last byte of text a CR?
+    cr: bool,
+}
+
+impl Crlf {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    /// Normalize the next piece of text, passing the output to `out` (possibly in several pieces)
+    pub(crate) fn update<E>(
+        &mut self,
+        data: &[u8],
+        mut out: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            match b {
+                b'\n' if !self.cr => {
+                    out(&data[start..i])?;
+                    out(b"\r")?;
+                    start = i;
+                }
+                b'\n' => {}
+                _ if self.cr => {
+                    // A lone CR is a line break as well
+                    out(&data[start..i])?;
+                    out(b"\n")?;
+                    start = i;
+                }
+                _ => {}
+            }
+            self.cr = *b == b'\r';
+        }
+
+        out(&data[start..])
+    }
+
+    /// Finish the text, completing a trailing lone CR
+    pub(crate) fn finish<E>(self, mut out: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        match self.cr {
+            true => out(b"\n"),
+            false => Ok(()),
+        }
+    }
+}
+
+/// The error that [Utf8Reader] returns for data that isn't UTF-8 text
+#[derive(Debug)]
+pub(crate) struct NotText;
+
+impl fmt::Display for NotText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Input is not UTF-8 text")
+    }
+}
+
+impl std::error::Error for NotText {}
+
+/// Passes through data from the inner reader, failing with [NotText] if it isn't valid UTF-8
+pub(crate) struct Utf8Reader<R: Read> {
+    inner: R,
+    check: Option<Utf8Check>,
+}
+
+impl<R: Read> Utf8Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            check: Some(Utf8Check::new()),
+        }
+    }
+}
+
+impl<R: Read> Read for Utf8Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        let valid = match n {
+            0 => self.check.take().is_none_or(Utf8Check::finish),
+            _ => self.check.as_mut().is_some_and(|c| c.update(&buf[..n])),
+        };
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, NotText));
+        }
+
+        Ok(n)
+    }
+}
+
+/// Checks that streamed data is valid UTF-8
+#[derive(Default)]
+pub(crate) struct Utf8Check {
+    // An incomplete character at the end of the data so far
+    pending: Vec<u8>,
+}
+
+impl Utf8Check {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    /// Check the next piece of data.
+    ///
+    /// Returns `false` if the data so far is not valid UTF-8.
+    pub(crate) fn update(&mut self, data: &[u8]) -> bool {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.extend_from_slice(data);
+
+        match std::str::from_utf8(&buf) {
+            Ok(_) => true,
+            Err(e) if e.error_len().is_some() => false,
+            Err(e) => {
+                self.pending = buf.split_off(e.valid_up_to());
+                true
+            }
+        }
+    }
+
+    /// Returns `false` if the data ended in the middle of a character
+    pub(crate) fn finish(self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[test]
+fn test_utf8_check() {
+    let text = "Grüße, 世界! 🦀".as_bytes();
+
+    // Valid text, split at every position
+    for i in 0..text.len() {
+        let mut check = Utf8Check::new();
+        assert!(check.update(&text[..i]));
+        assert!(check.update(&text[i..]));
+        assert!(check.finish());
+    }
+
+    // Truncated in the middle of a character
+    let mut check = Utf8Check::new();
+    assert!(check.update(&text[..text.len() - 1]));
+    assert!(!check.finish());
+
+    // Invalid data
+    let mut check = Utf8Check::new();
+    assert!(!check.update(&[b'a', 0xff, b'b']));
+}
@@ -0,0 +1,148 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Verification of signatures over streamed data
+
+use chrono::{DateTime, Utc};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{SignatureType, SignatureVersion, SignatureVersionSpecific, SubpacketData};
+use pgp::types::{KeyVersion, PublicKeyTrait};
+use pgp::Signature;
+use rpgpie::key::checked::CheckedCertificate;
+use rpgpie::key::component::ComponentKeyPub;
+use rpgpie::key::Certificate;
+
+use crate::stream::hash::DataHasher;
+
+// Cutoff times of rpgpie's hash algorithm policy
+const MD5_REJECT_AFTER: i64 = 1262304000;
+const SHA1_FOR_DATA_REJECT_AFTER: i64 = 1388534400;
+const SHA1_REJECT_AFTER: i64 = 1675209600;
+
+/// Check `sig` against the data in `hasher`, and find the signing keys in `certs` that it is valid
+/// for.
+///
+/// Signatures that our policy doesn't accept, or that were made with parameters that don't fit
+/// `hasher`, have no valid signers.
+pub(crate) fn signers(
+    hasher: DataHasher,
+    sig: &Signature,
+    certs: &[Certificate],
+) -> Vec<(Certificate, ComponentKeyPub)> {
+    let salt = match &sig.config.version_specific {
+        SignatureVersionSpecific::V6 { salt } => Some(&salt[..]),
+        _ => None,
+    };
+    if sig.typ() != hasher.typ()
+        || sig.config.hash_alg != hasher.hash_alg()
+        || salt != hasher.salt()
+    {
+        log::warn!("Signature doesn't match its one pass signature");
+        return vec![];
+    }
+
+    if salt.is_some() && salt.map(<[u8]>::len) != hasher.hash_alg().salt_len() {
+        log::warn!("Illegal salt length for {:?}", hasher.hash_alg());
+        return vec![];
+    }
+
+    let Some(created) = sig.created().filter(|_| acceptable(sig)) else {
+        log::warn!("Signature doesn't satisfy our policy");
+        return vec![];
+    };
+
+    let hash = match hasher.finish(&sig.config) {
+        Ok(hash) => hash,
+        Err(e) => {
+            log::warn!("Hashing signature data failed: {e:?}");
+            return vec![];
+        }
+    };
+
+    if sig.signed_hash_value[..] != hash[..2] {
+        log::warn!("Signature has an invalid signed hash value");
+        return vec![];
+    }
+
+    let mut valid = vec![];
+    for cert in certs {
+        // Only consider signers that are valid at signature creation time
+        let cv = CheckedCertificate::from(cert);
+
+        for verifier in cv.valid_signing_capable_component_keys_at(created) {
+            let key: ComponentKeyPub = verifier.into();
+
+            let result = match &key {
+                ComponentKeyPub::Primary(pk) => verify_hash(sig, pk, &hash),
+                ComponentKeyPub::Subkey(psk) => verify_hash(sig, psk, &hash),
+            };
+
+            if result {
+                valid.push((cert.clone(), key));
+            }
+        }
+    }
+
+    valid
+}
+
+/// Is `sig` by `key` valid for `hash`?
+fn verify_hash(sig: &Signature, key: &impl PublicKeyTrait, hash: &[u8]) -> bool {
+    // Only v6 keys make v6 signatures, and v6 keys only make v6 signatures
+    if (key.version() == KeyVersion::V6) != (sig.config.version() == SignatureVersion::V6) {
+        return false;
+    }
+
+    // If the signature names its issuer, it must be `key`
+    let issuers = sig.issuer();
+    let issuer_fps = sig.issuer_fingerprint();
+    let anonymous = issuers.is_empty() && issuer_fps.is_empty();
+    let named = issuers.iter().any(|&id| id == &key.key_id())
+        || issuer_fps.iter().any(|&fp| fp == &key.fingerprint());
+    if !(anonymous || named) {
+        return false;
+    }
+
+    key.verify_signature(sig.config.hash_alg, hash, &sig.signature)
+        .is_ok()
+}
+
+/// Does `sig` satisfy the policy that rpgpie applies to signatures?
+fn acceptable(sig: &Signature) -> bool {
+    let Some(created) = sig.created() else {
+        return false;
+    };
+
+    // A signature with a future creation time is not currently valid
+    if *created > Utc::now() {
+        return false;
+    }
+
+    // Critical notations are unknown to us, and invalidate the signature
+    if sig
+        .config
+        .hashed_subpackets
+        .iter()
+        .any(|sp| sp.is_critical && matches!(sp.data, SubpacketData::Notation(_)))
+    {
+        return false;
+    }
+
+    let data_sig = matches!(sig.typ(), SignatureType::Binary | SignatureType::Text);
+
+    acceptable_hash_algorithm(sig.config.hash_alg, created, data_sig)
+}
+
+/// Does our policy accept signatures with `hash_alg` that were created at `created`?
+fn acceptable_hash_algorithm(
+    hash_alg: HashAlgorithm,
+    created: &DateTime<Utc>,
+    data_sig: bool,
+) -> bool {
+    match (hash_alg, data_sig) {
+        (HashAlgorithm::MD5, _) => created.timestamp() < MD5_REJECT_AFTER,
+        (HashAlgorithm::SHA1, true) => created.timestamp() < SHA1_FOR_DATA_REJECT_AFTER,
+        (HashAlgorithm::SHA1, false) => created.timestamp() < SHA1_REJECT_AFTER,
+        _ => true,
+    }
+}
@@ -3,18 +3,25 @@
 
 use std::time::SystemTime;
 
-use pgp::packet::SignatureType;
-use pgp::Signature;
+use chrono::{DateTime, Utc};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData};
+use pgp::types::{KeyVersion, SecretKeyTrait, SignedUser};
+use pgp::{Deserializable, Signature, SignedPublicKey};
+use rand::{thread_rng, CryptoRng, Rng};
 use rpgpie::key::component::ComponentKeyPub;
 use rpgpie::key::Certificate;
-use rpgpie::msg::MessageResult;
+use rpgpie::sig::stack::SigStack;
 
+/// Get the SOP representation of a valid data signature.
+///
+/// Returns `None` for signatures that lack a creation time, or aren't data signatures.
 pub(crate) fn to_verification(
     signature: &Signature,
     cert: &Certificate,
     key: &ComponentKeyPub,
-) -> sop::ops::Verification {
-    let ct: SystemTime = (*signature.created().expect("FIXME")).into();
+) -> Option<sop::ops::Verification> {
+    let ct: SystemTime = (*signature.created()?).into();
 
     let key_fp = hex::encode(key.fingerprint().as_bytes());
     let cert_fp = hex::encode(cert.fingerprint().as_bytes());
@@ -22,15 +29,114 @@ pub(crate) fn to_verification(
     let mode = match signature.typ() {
         SignatureType::Binary => sop::ops::SignatureMode::Binary,
         SignatureType::Text => sop::ops::SignatureMode::Text,
-        _ => panic!("unexpected data signature type"),
+        typ => {
+            log::warn!("Ignoring signature of unexpected type {typ:?}");
+            return None;
+        }
     };
 
-    sop::ops::Verification::new(ct, key_fp, cert_fp, mode, None).expect("FIXME")
+    sop::ops::Verification::new(ct, key_fp, cert_fp, mode, None).ok()
 }
 
-pub(crate) fn result_to_verifications(mr: &MessageResult) -> Vec<sop::ops::Verification> {
-    mr.validated
+/// Was `signature` created within the (inclusive) time window between `not_before` and
+/// `not_after`?
+///
+/// Signatures without a creation time are never within the window.
+pub(crate) fn created_within(
+    signature: &Signature,
+    not_before: Option<SystemTime>,
+    not_after: Option<SystemTime>,
+) -> bool {
+    let Some(created) = signature.created() else {
+        return false;
+    };
+    let created: SystemTime = (*created).into();
+
+    not_before.is_none_or(|nb| created >= nb) && not_after.is_none_or(|na| created <= na)
+}
+
+/// Get the verifications for the `validated` signatures that were created within the time window
+/// between `not_before` and `not_after`
+pub(crate) fn verifications_within(
+    validated: &[(Certificate, ComponentKeyPub, Signature)],
+    not_before: Option<SystemTime>,
+    not_after: Option<SystemTime>,
+) -> Vec<sop::ops::Verification> {
+    validated
         .iter()
-        .map(|(cert, key, sig)| to_verification(sig, cert, key))
+        .filter(|(_, _, sig)| created_within(sig, not_before, not_after))
+        .filter_map(|(cert, key, sig)| to_verification(sig, cert, key))
         .collect()
 }
+
+/// Get the rPGP representation of `cert`
+pub(crate) fn to_signed_public_key(cert: &Certificate) -> sop::Result<SignedPublicKey> {
+    let bytes: Vec<u8> = cert.try_into().map_err(crate::error::rpgpie)?;
+
+    SignedPublicKey::from_bytes(&bytes[..]).map_err(crate::error::rpgp)
+}
+
+/// Make a signature config for a signature of type `typ`, issued by `signer` at `created`, using
+/// `hash_alg`.
+///
+/// The hashed area contains the creation time and issuer information.
+pub(crate) fn signature_config(
+    signer: &impl SecretKeyTrait,
+    typ: SignatureType,
+    hash_alg: HashAlgorithm,
+    created: &DateTime<Utc>,
+) -> sop::Result<SignatureConfig> {
+    signature_config_with_rng(thread_rng(), signer, typ, hash_alg, created)
+}
+
+/// Like [`signature_config`], but takes the salt of v6 signatures from `rng`
+pub(crate) fn signature_config_with_rng<R: CryptoRng + Rng>(
+    rng: R,
+    signer: &impl SecretKeyTrait,
+    typ: SignatureType,
+    hash_alg: HashAlgorithm,
+    created: &DateTime<Utc>,
+) -> sop::Result<SignatureConfig> {
+    let mut config = match signer.version() {
+        KeyVersion::V4 => SignatureConfig::v4(typ, signer.algorithm(), hash_alg),
+        KeyVersion::V6 => SignatureConfig::v6(rng, typ, signer.algorithm(), hash_alg)
+            .map_err(crate::error::rpgp)?,
+        _ => return Err(sop::errors::Error::UnsupportedAsymmetricAlgo),
+    };
+
+    config.hashed_subpackets = vec![Subpacket::regular(SubpacketData::SignatureCreationTime(
+        *created,
+    ))];
+
+    // v6 signatures only use the issuer fingerprint
+    if signer.version() == KeyVersion::V4 {
+        config
+            .hashed_subpackets
+            .push(Subpacket::regular(SubpacketData::Issuer(signer.key_id())));
+    }
+
+    config
+        .hashed_subpackets
+        .push(Subpacket::regular(SubpacketData::IssuerFingerprint(
+            signer.fingerprint(),
+        )));
+
+    Ok(config)
+}
+
+/// Does `user` have a valid self-signature at `reference`, and is not revoked?
+pub(crate) fn user_id_bound_at(
+    user: &SignedUser,
+    primary_creation: &DateTime<Utc>,
+    reference: &DateTime<Utc>,
+) -> bool {
+    // Only consider signatures that already existed at the reference time
+    let stack = SigStack::from_iter(
+        user.signatures
+            .iter()
+            .filter(|s| s.created().is_some_and(|c| c <= reference)),
+    );
+
+    stack.active().map(|s| s.typ()) != Some(SignatureType::CertRevocation)
+        && stack.has_valid_binding_at(reference, primary_creation)
+}
@@ -1,6 +1,21 @@
 // SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
 // SPDX-License-Identifier: CC0-1.0
 
+use std::time::Duration;
+
+/// Environment variable that sets the validity period of keys from `generate-key`
+const KEY_EXPIRATION: &str = "RSOP_KEY_EXPIRATION";
+
+/// Environment variable that sets the validity period of subkeys from `generate-key`
+const SUBKEY_EXPIRATION: &str = "RSOP_SUBKEY_EXPIRATION";
+
+/// Environment variable that sets how keys from `generate-key`, `change-key-password` and
+/// `update-key` are protected with a password
+const S2K_PROFILE: &str = "RSOP_S2K_PROFILE";
+
+/// Exit code for the SOP "UNSUPPORTED_OPTION" error
+const UNSUPPORTED_OPTION: i32 = 37;
+
 fn main() {
     #[cfg(feature = "cliv")]
     let variant = sop::cli::Variant::Verification;
@@ -9,5 +24,63 @@ fn main() {
     let variant = sop::cli::Variant::Full;
 
     env_logger::init();
-    sop::cli::main(&mut rpgpie_sop::RPGSOP::default(), variant);
+
+    let mut rpgsop = rpgpie_sop::RPGSOP::default();
+
+    // The SOP command line interface has no options for key expiration and password protection
+    // parameters, so we take these settings from the environment
+    if let Some(validity) = validity_from_env(KEY_EXPIRATION) {
+        rpgsop = rpgsop.with_key_expiration(validity);
+    }
+    if let Some(validity) = validity_from_env(SUBKEY_EXPIRATION) {
+        rpgsop = rpgsop.with_subkey_expiration(validity);
+    }
+    if let Ok(name) = std::env::var(S2K_PROFILE) {
+        match rpgpie_sop::S2kProfile::by_name(&name) {
+            Some(profile) => rpgsop = rpgsop.with_s2k_profile(profile),
+            None => {
+                eprintln!(
+                    "{S2K_PROFILE}: expected 'interactive', 'sensitive' or 'low-memory', got '{name}'"
+                );
+                std::process::exit(UNSUPPORTED_OPTION);
+            }
+        }
+    }
+
+    sop::cli::main(&mut rpgsop, variant);
+}
+
+/// Read a validity period from the environment variable `var`.
+///
+/// Exits with an error if the variable is set to a value that isn't a validity period.
+fn validity_from_env(var: &str) -> Option<Duration> {
+    let value = std::env::var(var).ok()?;
+
+    match parse_validity(&value) {
+        Some(validity) => Some(validity),
+        None => {
+            eprintln!(
+                "{var}: expected a number of days, weeks or years (e.g. '2y'), got '{value}'"
+            );
+            std::process::exit(UNSUPPORTED_OPTION);
+        }
+    }
+}
+
+/// Parse a validity period, given as a number of days, weeks or years (e.g. "90d", "2y")
+fn parse_validity(value: &str) -> Option<Duration> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let unit = match value.chars().last()? {
+        'd' => DAY,
+        'w' => 7 * DAY,
+        'y' => 365 * DAY,
+        _ => return None,
+    };
+
+    let count: u64 = value[..value.len() - 1].parse().ok()?;
+    match count {
+        0 => None,
+        _ => Some(Duration::from_secs(count.checked_mul(unit)?)),
+    }
 }
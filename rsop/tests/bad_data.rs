@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop fails with the SOP exit code for BAD_DATA (rather than panicking) when it is
+//! given malformed input.
+
+mod common;
+
+use common::{rsop, Scratch, BAD_DATA};
+
+/// Input that doesn't resemble OpenPGP data
+const TEXT: &[u8] = b"This is not OpenPGP data\n";
+
+/// Input that starts out like a binary OpenPGP packet (of an experimental type), but is malformed
+const BINARY: &[u8] = &[0xff, 0xff, 0x00, 0x13, 0x37, 0x42, 0x00];
+
+/// Input that has armor framing, but a malformed body
+const ARMORED: &[u8] = b"-----BEGIN PGP MESSAGE-----\n\nnot base64!\n-----END PGP MESSAGE-----\n";
+
+/// Make a scratch directory that contains a key and its certificate
+fn scratch(name: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    scratch.key("key", None, None);
+
+    scratch
+}
+
+/// Run rsop with each kind of garbage input, and check that it fails with BAD_DATA.
+///
+/// The garbage is fed on stdin, and passed in place of `GARBAGE` in `args`.
+/// `KEY`, `CERT` and `OUT` are replaced with paths in the scratch directory.
+fn check(scratch: &Scratch, args: &[&str]) {
+    for garbage in [TEXT, BINARY, ARMORED] {
+        scratch.write("garbage", garbage);
+        scratch.remove("out");
+
+        let args: Vec<String> = args
+            .iter()
+            .map(|a| match *a {
+                "GARBAGE" => scratch.path("garbage"),
+                "KEY" => scratch.path("key"),
+                "CERT" => scratch.path("key.cert"),
+                "OUT" => scratch.path("out"),
+                a => a.to_string(),
+            })
+            .collect();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        assert_eq!(
+            rsop(&args, garbage).status.code(),
+            Some(BAD_DATA),
+            "{args:?} with input {:?}",
+            String::from_utf8_lossy(garbage)
+        );
+    }
+}
+
+#[test]
+fn test_bad_data_stdin() {
+    let scratch = scratch("stdin");
+
+    check(&scratch, &["extract-cert"]);
+    check(&scratch, &["change-key-password"]);
+    check(&scratch, &["revoke-key"]);
+    check(&scratch, &["update-key"]);
+    check(&scratch, &["merge-certs", "CERT"]);
+    check(
+        &scratch,
+        &["certify-userid", "--userid", "<alice@example.org>", "KEY"],
+    );
+    check(
+        &scratch,
+        &["validate-userid", "<alice@example.org>", "CERT"],
+    );
+    check(&scratch, &["decrypt", "KEY"]);
+    check(&scratch, &["inline-detach", "--signatures-out", "OUT"]);
+    check(&scratch, &["inline-verify", "CERT"]);
+}
+
+#[test]
+fn test_bad_data_args() {
+    let scratch = scratch("args");
+
+    check(&scratch, &["sign", "GARBAGE"]);
+    check(&scratch, &["verify", "GARBAGE", "CERT"]);
+    check(&scratch, &["encrypt", "GARBAGE"]);
+    check(&scratch, &["decrypt", "GARBAGE"]);
+    check(&scratch, &["inline-sign", "GARBAGE"]);
+    check(&scratch, &["inline-verify", "GARBAGE"]);
+    check(&scratch, &["merge-certs", "GARBAGE"]);
+    check(
+        &scratch,
+        &[
+            "certify-userid",
+            "--userid",
+            "<alice@example.org>",
+            "GARBAGE",
+        ],
+    );
+    check(
+        &scratch,
+        &["validate-userid", "<alice@example.org>", "GARBAGE"],
+    );
+}
+
+#[test]
+fn test_bad_data_armor() {
+    // dearmor passes binary input through, so only non-binary garbage is an error
+    assert_eq!(rsop(&["dearmor"], TEXT).status.code(), Some(BAD_DATA));
+    assert_eq!(rsop(&["dearmor"], ARMORED).status.code(), Some(BAD_DATA));
+
+    // armor passes non-binary input through, so only binary garbage is an error
+    assert_eq!(rsop(&["armor"], BINARY).status.code(), Some(BAD_DATA));
+}
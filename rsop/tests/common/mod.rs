@@ -0,0 +1,172 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Fixtures that the rsop integration tests share.
+
+// Each test uses only some of the fixtures
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+pub const CANNOT_DECRYPT: i32 = 29;
+pub const BAD_DATA: i32 = 41;
+pub const EXPECTED_TEXT: i32 = 53;
+pub const KEY_IS_PROTECTED: i32 = 67;
+
+/// Key generation profile for a version 4 key, which gets SEIPDv1 encrypted messages
+pub const V4_PROFILE: &str = "draft-koch-eddsa-for-openpgp-00";
+
+/// Key generation profile for a version 6 key, which gets SEIPDv2 encrypted messages
+pub const V6_PROFILE: &str = "rfc9580";
+
+/// Limit for the data segment of memory limited rsop processes, in KiB (well below the size of
+/// the data that the tests stream through rsop)
+pub const DATA_LIMIT: usize = 16 * 1024;
+
+/// Run rsop with `args`, feeding it `stdin`
+pub fn rsop(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rsop"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // rsop produces output while it reads its input, so we need to feed it concurrently.
+    // It may also fail before consuming all of its input.
+    let mut input = child.stdin.take().unwrap();
+    let stdin = stdin.to_vec();
+    let writer = std::thread::spawn(move || {
+        let _ = input.write_all(&stdin);
+    });
+
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+
+    output
+}
+
+/// Run `rsop {args}` in a shell, with a limit on its data segment.
+///
+/// `input` writes the data for rsop's stdin, and `output` gets rsop's stdout in chunks, as it is
+/// produced.
+#[cfg(target_os = "linux")]
+pub fn rsop_limited(
+    args: &str,
+    input: impl FnOnce(&mut dyn Write) + Send + 'static,
+    mut output: impl FnMut(&[u8]),
+) -> ExitStatus {
+    let cmd = format!(
+        "ulimit -d {DATA_LIMIT}; exec '{}' {args}",
+        env!("CARGO_BIN_EXE_rsop"),
+    );
+
+    let mut child = Command::new("sh")
+        .args(["-c", &cmd])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || input(&mut stdin));
+
+    let mut stdout = child.stdout.take().unwrap();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match stdout.read(&mut buf).unwrap() {
+            0 => break,
+            n => output(&buf[..n]),
+        }
+    }
+
+    writer.join().unwrap();
+    child.wait().unwrap()
+}
+
+/// Write `chunk` to `sink` repeatedly, for a total of (about) `size` bytes.
+///
+/// Stops early if the sink is closed.
+pub fn generate(sink: &mut dyn Write, chunk: &[u8], size: usize) {
+    for _ in 0..size / chunk.len() {
+        if sink.write_all(chunk).is_err() {
+            break;
+        }
+    }
+}
+
+/// A scratch directory for keys, certificates and other files
+pub struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rsop-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        Self { dir }
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().to_string()
+    }
+
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        std::fs::write(self.dir.join(name), contents).unwrap();
+    }
+
+    pub fn read(&self, name: &str) -> Vec<u8> {
+        std::fs::read(self.dir.join(name)).unwrap()
+    }
+
+    pub fn remove(&self, name: &str) {
+        let _ = std::fs::remove_file(self.dir.join(name));
+    }
+
+    /// Generate a key for Alice (with `profile`, or the default profile) as `name`, and its
+    /// certificate as `name.cert`.
+    ///
+    /// The key is protected with the password in the file `password`, if given.
+    pub fn key(&self, name: &str, profile: Option<&str>, password: Option<&str>) {
+        let mut args = vec!["generate-key"];
+        if let Some(profile) = profile {
+            args.extend(["--profile", profile]);
+        }
+        if let Some(password) = password {
+            args.extend(["--with-key-password", password]);
+        }
+        args.push("<alice@example.org>");
+
+        let key = self.rsop(&args, b"");
+        assert!(key.status.success(), "{args:?}");
+        let cert = rsop(&["extract-cert"], &key.stdout).stdout;
+
+        self.write(name, key.stdout);
+        self.write(&format!("{name}.cert"), cert);
+    }
+
+    /// Run rsop with `args`, in which the names of files in the scratch directory are replaced
+    /// with their paths
+    pub fn rsop(&self, args: &[&str], stdin: &[u8]) -> Output {
+        let args: Vec<String> = args
+            .iter()
+            .map(|a| match !a.is_empty() && self.dir.join(a).exists() {
+                true => self.path(a),
+                false => a.to_string(),
+            })
+            .collect();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        rsop(&args, stdin)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
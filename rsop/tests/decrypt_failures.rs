@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop reports why it couldn't decrypt a message.
+
+mod common;
+
+use common::{Scratch, BAD_DATA, CANNOT_DECRYPT, KEY_IS_PROTECTED, V4_PROFILE, V6_PROFILE};
+
+/// Make a scratch directory with a password protected key ("key") and an unrelated key
+/// ("other"), and their certificates
+fn scratch(name: &str, profile: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+
+    scratch.write("password", "password");
+    scratch.write("wrong", "wrong");
+
+    scratch.key("key", Some(profile), Some("password"));
+    scratch.key("other", Some(profile), None);
+
+    scratch
+}
+
+#[test]
+fn test_decrypt_failures() {
+    // Key generation profiles, with the matching encryption profiles
+    for (profile, encrypt_profile) in [(V4_PROFILE, "rfc4880"), (V6_PROFILE, "rfc9580")] {
+        let scratch = scratch(&format!("decrypt-failures-{profile}"), profile);
+
+        let to_key = scratch.rsop(&["encrypt", "--no-armor", "key.cert"], b"hello");
+        assert!(to_key.status.success());
+        let to_key = to_key.stdout;
+
+        let to_password = scratch.rsop(
+            &[
+                "encrypt",
+                "--profile",
+                encrypt_profile,
+                "--with-password",
+                "password",
+            ],
+            b"hello",
+        );
+        assert!(to_password.status.success());
+        let to_password = to_password.stdout;
+
+        let code = |args: &[&str], ciphertext: &[u8]| scratch.rsop(args, ciphertext).status.code();
+
+        // Wrong message password
+        assert_eq!(
+            code(&["decrypt", "--with-password", "wrong"], &to_password),
+            Some(CANNOT_DECRYPT),
+            "{profile}"
+        );
+
+        // Wrong (or missing) key password
+        assert_eq!(
+            code(&["decrypt", "--with-key-password", "wrong", "key"], &to_key),
+            Some(KEY_IS_PROTECTED),
+            "{profile}"
+        );
+        assert_eq!(
+            code(&["decrypt", "key"], &to_key),
+            Some(KEY_IS_PROTECTED),
+            "{profile}"
+        );
+
+        // No matching decryption key
+        assert_eq!(
+            code(&["decrypt", "other"], &to_key),
+            Some(CANNOT_DECRYPT),
+            "{profile}"
+        );
+
+        // Corrupt ciphertext
+        let mut corrupt = to_key.clone();
+        let len = corrupt.len();
+        corrupt[len - 20] ^= 0x01;
+        assert_eq!(
+            code(
+                &["decrypt", "--with-key-password", "password", "key"],
+                &corrupt
+            ),
+            Some(BAD_DATA),
+            "{profile}"
+        );
+
+        // With the right password, decryption succeeds
+        let output = scratch.rsop(
+            &["decrypt", "--with-key-password", "password", "key"],
+            &to_key,
+        );
+        assert!(output.status.success(), "{profile}");
+        assert_eq!(output.stdout, b"hello", "{profile}");
+    }
+}
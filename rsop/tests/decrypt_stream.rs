@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop decrypts messages as a stream, and that it doesn't release unauthenticated
+//! plaintext.
+
+mod common;
+
+use std::io::Write;
+use std::process::Output;
+
+use common::{Scratch, BAD_DATA, V4_PROFILE, V6_PROFILE};
+
+/// Size of the generated plaintext
+const PLAINTEXT_SIZE: usize = 32 * 1024 * 1024;
+
+/// Make a scratch directory that contains a key and its certificate
+fn scratch(name: &str, profile: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    scratch.key("key", Some(profile), None);
+
+    scratch
+}
+
+/// Encrypt `plaintext` to our certificate (signed by our key)
+fn encrypt(scratch: &Scratch, plaintext: &[u8]) -> Vec<u8> {
+    let output = scratch.rsop(
+        &["encrypt", "--no-armor", "--sign-with", "key", "key.cert"],
+        plaintext,
+    );
+    assert!(output.status.success());
+
+    output.stdout
+}
+
+/// Decrypt `ciphertext` with our key
+fn decrypt(scratch: &Scratch, ciphertext: &[u8]) -> Output {
+    scratch.rsop(&["decrypt", "key"], ciphertext)
+}
+
+/// Encrypt a large generated plaintext, then decrypt it with rsop, with a limit on its data
+/// segment.
+///
+/// Returns the size of the decrypted output.
+#[cfg(target_os = "linux")]
+fn decrypt_limited(scratch: &Scratch) -> usize {
+    let mut ciphertext = std::fs::File::create(scratch.path("ciphertext")).unwrap();
+    let encrypt = format!(
+        "encrypt --sign-with '{}' '{}'",
+        scratch.path("key"),
+        scratch.path("key.cert"),
+    );
+    let status = common::rsop_limited(
+        &encrypt,
+        |stdin| common::generate(stdin, &[0x2a; 64 * 1024], PLAINTEXT_SIZE),
+        |out| ciphertext.write_all(out).unwrap(),
+    );
+    assert!(status.success());
+
+    let mut ciphertext = std::fs::File::open(scratch.path("ciphertext")).unwrap();
+    let decrypt = format!(
+        "decrypt --verify-with '{}' --verifications-out '{}' '{}'",
+        scratch.path("key.cert"),
+        scratch.path("verifications"),
+        scratch.path("key"),
+    );
+    let mut len = 0;
+    let status = common::rsop_limited(
+        &decrypt,
+        move |stdin| {
+            let _ = std::io::copy(&mut ciphertext, stdin);
+        },
+        |out| {
+            assert!(out.iter().all(|b| *b == 0x2a));
+            len += out.len()
+        },
+    );
+    assert!(status.success());
+
+    assert!(!scratch.read("verifications").is_empty());
+
+    len
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_decrypt_stream_seipd1() {
+    let scratch = scratch("decrypt-stream-seipd1", V4_PROFILE);
+
+    assert_eq!(decrypt_limited(&scratch), PLAINTEXT_SIZE);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_decrypt_stream_seipd2() {
+    let scratch = scratch("decrypt-stream-seipd2", V6_PROFILE);
+
+    assert_eq!(decrypt_limited(&scratch), PLAINTEXT_SIZE);
+}
+
+#[test]
+fn test_decrypt_tampered() {
+    for profile in [V4_PROFILE, V6_PROFILE] {
+        let scratch = scratch(&format!("tampered-{profile}"), profile);
+
+        let plaintext = vec![0x2a; 100_000];
+        let mut ciphertext = encrypt(&scratch, &plaintext);
+
+        // Flip a bit in the last part of the encrypted data
+        let len = ciphertext.len();
+        ciphertext[len - 1000] ^= 0x01;
+
+        let output = decrypt(&scratch, &ciphertext);
+        assert_eq!(output.status.code(), Some(BAD_DATA), "{profile}");
+
+        // Only the authenticated part of the plaintext may be released
+        match profile {
+            V4_PROFILE => assert!(output.stdout.is_empty()),
+            _ => assert!(output.stdout.len() < plaintext.len()),
+        }
+    }
+}
+
+#[test]
+fn test_decrypt_truncated() {
+    for profile in [V4_PROFILE, V6_PROFILE] {
+        let scratch = scratch(&format!("truncated-{profile}"), profile);
+
+        let plaintext = vec![0x2a; 100_000];
+        let ciphertext = encrypt(&scratch, &plaintext);
+
+        let output = decrypt(&scratch, &ciphertext[..ciphertext.len() - 10]);
+        assert!(!output.status.success(), "{profile}");
+        assert!(output.stdout.len() < plaintext.len(), "{profile}");
+    }
+}
+
+#[test]
+fn test_decrypt_trailing_data() {
+    for profile in [V4_PROFILE, V6_PROFILE] {
+        let scratch = scratch(&format!("trailing-{profile}"), profile);
+
+        let message = encrypt(&scratch, b"hello");
+
+        // The input must consist of exactly one message.
+        // Authenticated SEIPDv2 plaintext may already have been written when trailing data shows
+        // up, SEIPDv1 plaintext is held back.
+        for input in [
+            [&message[..], b"garbage"].concat(),
+            [&message[..], &message[..]].concat(),
+        ] {
+            let output = decrypt(&scratch, &input);
+            assert_eq!(output.status.code(), Some(BAD_DATA), "{profile}");
+            if profile == V4_PROFILE {
+                assert!(output.stdout.is_empty(), "{profile}");
+            }
+        }
+    }
+
+    let scratch = scratch("empty", V6_PROFILE);
+    assert_eq!(decrypt(&scratch, b"").status.code(), Some(BAD_DATA));
+}
@@ -0,0 +1,105 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop encrypts (and signs) data as a stream, so that memory use doesn't depend on the
+//! size of the plaintext.
+
+mod common;
+
+use common::{Scratch, V4_PROFILE, V6_PROFILE};
+
+/// Size of the generated plaintext
+const PLAINTEXT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Make a scratch directory that contains a key and its certificate
+fn scratch(name: &str, profile: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    scratch.key("key", Some(profile), None);
+
+    scratch
+}
+
+/// Run rsop with `args`, feeding it `stdin`, and return its output
+fn rsop(scratch: &Scratch, args: &[&str], stdin: &[u8]) -> Vec<u8> {
+    let output = scratch.rsop(args, stdin);
+    assert!(output.status.success(), "{args:?}");
+
+    output.stdout
+}
+
+/// Encrypt and sign a large generated plaintext with rsop, with a limit on its data segment.
+///
+/// Returns the size of the output.
+#[cfg(target_os = "linux")]
+fn encrypt_limited(scratch: &Scratch, args: &str) -> usize {
+    let cmd = format!(
+        "encrypt {args} --sign-with '{}' '{}'",
+        scratch.path("key"),
+        scratch.path("key.cert"),
+    );
+
+    let mut len = 0;
+    let status = common::rsop_limited(
+        &cmd,
+        |stdin| common::generate(stdin, &[0x2a; 64 * 1024], PLAINTEXT_SIZE),
+        |out| len += out.len(),
+    );
+    assert!(status.success());
+
+    len
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_encrypt_stream_seipd1() {
+    let scratch = scratch("stream-seipd1", V4_PROFILE);
+
+    assert!(encrypt_limited(&scratch, "--no-armor") > PLAINTEXT_SIZE);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_encrypt_stream_seipd2() {
+    let scratch = scratch("stream-seipd2", V6_PROFILE);
+
+    // Armored output is a third larger than the binary message
+    assert!(encrypt_limited(&scratch, "") > PLAINTEXT_SIZE * 4 / 3);
+}
+
+#[test]
+fn test_encrypt_roundtrip() {
+    for profile in [V4_PROFILE, V6_PROFILE] {
+        let scratch = scratch(&format!("roundtrip-{profile}"), profile);
+
+        // Sizes around the boundaries of partial body lengths and AEAD chunks
+        for size in [0, 1, 8191, 8192, 8193, 16384, 16385, 100_000] {
+            let plaintext: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+            let ciphertext = rsop(
+                &scratch,
+                &["encrypt", "--sign-with", "key", "key.cert"],
+                &plaintext,
+            );
+
+            let decrypted = rsop(
+                &scratch,
+                &[
+                    "decrypt",
+                    "--verifications-out",
+                    &scratch.path("verifications"),
+                    "--verify-with",
+                    &scratch.path("key.cert"),
+                    &scratch.path("key"),
+                ],
+                &ciphertext,
+            );
+            assert_eq!(decrypted, plaintext, "{profile}, {size} bytes");
+
+            assert!(
+                !scratch.read("verifications").is_empty(),
+                "{profile}, {size} bytes"
+            );
+            scratch.remove("verifications");
+        }
+    }
+}
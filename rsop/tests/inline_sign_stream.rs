@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop creates inline signed messages from streamed data, so that memory use doesn't
+//! depend on the size of the data.
+
+mod common;
+
+use common::{rsop, Scratch, EXPECTED_TEXT, V4_PROFILE, V6_PROFILE};
+
+/// Size of the generated data
+const DATA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Make a scratch directory that contains a v4 and a v6 key, and their certificates
+fn scratch(name: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    scratch.key("v4", Some(V4_PROFILE), None);
+    scratch.key("v6", Some(V6_PROFILE), None);
+
+    scratch
+}
+
+/// Inline sign a large generated message with rsop, with a limit on its data segment.
+///
+/// Returns the size of the output.
+#[cfg(target_os = "linux")]
+fn inline_sign_limited(args: &str) -> usize {
+    let chunk = [b"-- line of text\n".as_slice(); 4096].concat();
+
+    let mut len = 0;
+    let status = common::rsop_limited(
+        &format!("inline-sign {args}"),
+        move |stdin| common::generate(stdin, &chunk, DATA_SIZE),
+        |out| len += out.len(),
+    );
+    assert!(status.success());
+
+    len
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_inline_sign_stream() {
+    let scratch = scratch("inline-sign-stream");
+
+    let key = scratch.path("v6");
+
+    assert!(inline_sign_limited(&format!("--no-armor '{key}'")) > DATA_SIZE);
+
+    // Text mode adds a CR to each line
+    assert!(inline_sign_limited(&format!("--as text '{key}'")) > DATA_SIZE * 4 / 3);
+
+    // Dash-escaping adds two bytes to each line
+    assert!(inline_sign_limited(&format!("--as clearsigned '{key}'")) > DATA_SIZE);
+}
+
+#[test]
+fn test_inline_sign_roundtrip() {
+    let scratch = scratch("inline-sign-roundtrip");
+
+    let data = b"-----BEGIN PGP SIGNATURE-----\nfrom here\r\n- on\n\n-- there\n";
+    let text = b"-----BEGIN PGP SIGNATURE-----\r\nfrom here\r\n- on\r\n\r\n-- there\r\n";
+
+    for (mode, expected) in [("binary", &data[..]), ("text", text), ("clearsigned", text)] {
+        // Sign with both keys, so that the message contains nested signatures
+        let signed = rsop(
+            &[
+                "inline-sign",
+                "--as",
+                mode,
+                &scratch.path("v6"),
+                &scratch.path("v4"),
+            ],
+            data,
+        );
+        assert!(signed.status.success(), "{mode}");
+
+        scratch.remove("verifications");
+        let verified = rsop(
+            &[
+                "inline-verify",
+                "--verifications-out",
+                &scratch.path("verifications"),
+                &scratch.path("v6.cert"),
+                &scratch.path("v4.cert"),
+            ],
+            &signed.stdout,
+        );
+        assert!(verified.status.success(), "{mode}");
+        assert_eq!(verified.stdout, expected, "{mode}");
+
+        let verifications = std::fs::read_to_string(scratch.path("verifications")).unwrap();
+        assert_eq!(verifications.lines().count(), 2, "{mode}");
+    }
+
+    // Signing as text requires UTF-8
+    for mode in ["text", "clearsigned"] {
+        let signed = rsop(
+            &["inline-sign", "--as", mode, &scratch.path("v4")],
+            b"\xff\xfe",
+        );
+        assert_eq!(signed.status.code(), Some(EXPECTED_TEXT), "{mode}");
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: Heiko Schaefer <heiko@schaefer.name>
+// SPDX-License-Identifier: CC0-1.0
+
+//! Check that rsop creates and verifies detached signatures over streamed data, so that memory
+//! use doesn't depend on the size of the data.
+
+mod common;
+
+use common::{rsop, Scratch};
+
+/// Size of the generated data
+const DATA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Make a scratch directory that contains a key and its certificate
+fn scratch(name: &str) -> Scratch {
+    let scratch = Scratch::new(name);
+    scratch.key("key", None, None);
+
+    scratch
+}
+
+/// Run `rsop {args}` with a limit on its data segment, while streaming generated data to it.
+///
+/// Returns rsop's output, if it succeeded.
+#[cfg(target_os = "linux")]
+fn rsop_limited(args: &str) -> Vec<u8> {
+    let mut output = vec![];
+    let status = common::rsop_limited(
+        args,
+        |stdin| common::generate(stdin, &[0x2a; 64 * 1024], DATA_SIZE),
+        |out| output.extend_from_slice(out),
+    );
+    assert!(status.success(), "{args}");
+
+    output
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_sign_verify_stream() {
+    let scratch = scratch("sign-stream");
+
+    let sig = rsop_limited(&format!("sign '{}'", scratch.path("key")));
+    scratch.write("sig", sig);
+
+    let verify = rsop_limited(&format!(
+        "verify '{}' '{}'",
+        scratch.path("sig"),
+        scratch.path("key.cert")
+    ));
+    assert!(!verify.is_empty());
+}
+
+#[test]
+fn test_sign_text() {
+    let scratch = scratch("sign-text");
+
+    let sig = rsop(
+        &["sign", "--as", "text", &scratch.path("key")],
+        b"one\ntwo\r\nthree\n",
+    );
+    assert!(sig.status.success());
+    scratch.write("sig", sig.stdout);
+
+    // Text signatures are made over data with normalized line endings
+    let verify = |data: &[u8]| {
+        rsop(
+            &["verify", &scratch.path("sig"), &scratch.path("key.cert")],
+            data,
+        )
+        .status
+        .success()
+    };
+    assert!(verify(b"one\ntwo\r\nthree\n"));
+    assert!(verify(b"one\r\ntwo\r\nthree\r\n"));
+    assert!(verify(b"one\ntwo\nthree\n"));
+    assert!(!verify(b"one two three\n"));
+
+    // Signing as text requires UTF-8
+    let sig = rsop(&["sign", "--as", "text", &scratch.path("key")], b"\xff\xfe");
+    assert!(!sig.status.success());
+}